@@ -1,12 +1,19 @@
 use anyhow::Result;
 use config::{Config as ConfigBuilder, ConfigError, File};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::encryption::TlsConfig;
+use crate::limits::LimitSettings;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub client: ClientConfig,
     pub server: ServerConfig,
-    pub proxy: ProxyConfig,
+    /// Single unnamed proxy kept for older config files; runs as the proxy named "default"
+    pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub proxies: Vec<sentinel_common::ProxyConfig>,
     pub transport: TransportConfig,
     pub limits: LimitsConfig,
     pub monitoring: MonitoringConfig,
@@ -17,6 +24,10 @@ pub struct Config {
 pub struct ClientConfig {
     pub id: Option<String>,
     pub hostname: Option<String>,
+    /// Where the server-assigned id and token are kept, relative to the config file
+    pub state_file: String,
+    /// Ed25519 key identifying this client to relay peers, relative to the config file
+    pub identity_file: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,22 +41,76 @@ pub struct ServerConfig {
 pub struct ProxyConfig {
     pub listen_addr: String,
     pub target_addr: String,
+    #[serde(default = "default_buffer_size")]
     pub buffer_size: usize,
 }
 
+fn default_buffer_size() -> usize {
+    8192
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransportConfig {
     #[serde(rename = "type")]
     pub transport_type: String,
     pub encryption_key: Option<String>,
+    #[serde(default)]
+    pub tls: TlsTransportConfig,
+    /// Seconds a stopped relay's open connections get to finish before they are aborted
+    pub stop_grace_period: u64,
+}
+
+/// `[transport.tls]`: certificates for `Tls` relays, as PEM files relative to the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsTransportConfig {
+    /// CA bundle trusted for peers; the bundled public web roots when empty
+    pub ca_file: String,
+    /// Certificate chain this client presents, as the exit's server certificate and the
+    /// entry's client certificate
+    pub cert_file: String,
+    pub key_file: String,
+    /// SNI and the name checked on the exit's certificate; defaults to the tunnel host
+    pub server_name: String,
+    pub verify_hostname: bool,
+    /// Exit side: only accept entries presenting a certificate signed by `ca_file`
+    pub require_client_cert: bool,
+}
+
+impl Default for TlsTransportConfig {
+    fn default() -> Self {
+        Self {
+            ca_file: String::new(),
+            cert_file: String::new(),
+            key_file: String::new(),
+            server_name: String::new(),
+            verify_hostname: true,
+            require_client_cert: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LimitsConfig {
+    /// Open connections across all proxies
     pub max_connections: usize,
+    /// Open connections from one source IP across all proxies, 0 for no limit
+    pub max_connections_per_source: u32,
+    /// New connections per second from one source IP across all proxies, 0 for no limit
+    pub accept_rate_per_source: u32,
     pub rate_limit_mbps: u32,
 }
 
+impl LimitsConfig {
+    pub fn connection_limits(&self) -> LimitSettings {
+        LimitSettings {
+            max_connections: self.max_connections.min(u32::MAX as usize) as u32,
+            max_per_source: self.max_connections_per_source,
+            accept_rate_per_source: self.accept_rate_per_source,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MonitoringConfig {
     pub enabled: bool,
@@ -65,12 +130,14 @@ impl Config {
             .add_source(File::with_name(path))
             .set_default("client.id", "")?
             .set_default("client.hostname", "")?
+            .set_default("client.state_file", "client-state.json")?
+            .set_default("client.identity_file", "client-identity.key")?
             .set_default("server.heartbeat_interval", 30)?
-            .set_default("proxy.listen_addr", "0.0.0.0:0")?
-            .set_default("proxy.target_addr", "127.0.0.1:8080")?
-            .set_default("proxy.buffer_size", 8192)?
             .set_default("transport.type", "direct")?
+            .set_default("transport.stop_grace_period", 10)?
             .set_default("limits.max_connections", 1000)?
+            .set_default("limits.max_connections_per_source", 0)?
+            .set_default("limits.accept_rate_per_source", 0)?
             .set_default("limits.rate_limit_mbps", 0)?
             .set_default("monitoring.enabled", true)?
             .set_default("monitoring.report_interval", 30)?
@@ -80,4 +147,85 @@ impl Config {
 
         config.try_deserialize()
     }
-}
\ No newline at end of file
+
+    /// Decode `transport.encryption_key`, 32 bytes written as 64 hex characters
+    pub fn relay_key(&self) -> Result<Option<Vec<u8>>> {
+        let Some(key) = self.transport.encryption_key.as_deref().filter(|k| !k.is_empty()) else {
+            return Ok(None);
+        };
+
+        let key = hex::decode(key.trim())
+            .map_err(|e| anyhow::anyhow!("transport.encryption_key is not valid hex: {}", e))?;
+        if key.len() != 32 {
+            anyhow::bail!("transport.encryption_key must be 32 bytes (64 hex characters)");
+        }
+
+        Ok(Some(key))
+    }
+
+    /// Load the `[transport.tls]` certificates, resolving paths against `config_dir`
+    pub fn tls_config(&self, config_dir: &Path) -> Result<TlsConfig> {
+        let tls = &self.transport.tls;
+        let read = |path: &str| -> Result<Option<Vec<u8>>> {
+            if path.is_empty() {
+                return Ok(None);
+            }
+            let path = config_dir.join(path);
+            std::fs::read(&path)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))
+        };
+
+        let cert = read(&tls.cert_file)?;
+        let key = read(&tls.key_file)?;
+        if cert.is_some() != key.is_some() {
+            anyhow::bail!("transport.tls.cert_file and key_file must be set together");
+        }
+
+        Ok(TlsConfig {
+            ca_cert: read(&tls.ca_file)?,
+            client_cert: cert.clone(),
+            client_key: key.clone(),
+            server_cert: cert,
+            server_key: key,
+            server_name: Some(tls.server_name.clone()).filter(|name| !name.is_empty()),
+            verify_hostname: tls.verify_hostname,
+            require_client_cert: tls.require_client_cert,
+        })
+    }
+
+    /// Proxies to start at boot: `[proxy]` as "default", then every `[[proxies]]` entry
+    pub fn proxy_configs(&self) -> Result<Vec<sentinel_common::ProxyConfig>> {
+        let mut configs = vec![];
+
+        if let Some(proxy) = &self.proxy {
+            configs.push(sentinel_common::ProxyConfig {
+                name: "default".to_string(),
+                listen_addr: proxy.listen_addr.clone(),
+                target_addr: proxy.target_addr.clone(),
+                rate_limit: Some(self.limits.rate_limit_mbps),
+                bandwidth: None,
+                source_bandwidth: None,
+                max_connections: None,
+                max_connections_per_source: None,
+                accept_rate_per_source: None,
+                mode: Default::default(),
+                idle_timeout: None,
+                auth: None,
+                allow_destinations: Vec::new(),
+                deny_destinations: Vec::new(),
+                routes: Vec::new(),
+            });
+        }
+        configs.extend(self.proxies.iter().cloned());
+
+        let mut names = std::collections::HashSet::new();
+        for config in &configs {
+            if !names.insert(config.name.as_str()) {
+                anyhow::bail!("Duplicate proxy name in config: {}", config.name);
+            }
+        }
+
+        Ok(configs)
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::Result;
+use std::net::{IpAddr, SocketAddr};
+
+/// Allow and deny lists for destinations a proxy's clients name themselves. A destination
+/// is refused when any deny rule matches its name or one of its addresses; otherwise each
+/// of its addresses may be dialed when the allow list is empty or one of its rules matches
+/// the name or that address.
+#[derive(Debug, Default)]
+pub struct DestinationFilter {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+#[derive(Debug)]
+enum HostPattern {
+    Network(IpAddr, u8),
+    Name(String),
+    /// `*.example.com`: any name below `example.com`
+    Suffix(String),
+}
+
+impl DestinationFilter {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self> {
+        let parse = |rules: &[String]| rules.iter().map(|rule| Rule::parse(rule)).collect::<Result<Vec<_>>>();
+        Ok(Self {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    /// The addresses a destination resolved to that may be dialed, given the name the
+    /// client asked for, if any; none when the destination is refused
+    pub fn allowed(&self, name: Option<&str>, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let name = name.map(normalize_name);
+        let matches = |rule: &Rule, addr: &SocketAddr| rule.matches(name.as_deref(), addr.ip(), addr.port());
+
+        if self.deny.iter().any(|rule| addrs.iter().any(|addr| matches(rule, addr))) {
+            return Vec::new();
+        }
+        addrs
+            .iter()
+            .filter(|addr| self.allow.is_empty() || self.allow.iter().any(|rule| matches(rule, addr)))
+            .copied()
+            .collect()
+    }
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid destination rule {:?}", rule);
+
+        // `[v6]:port` and `host:port`; a bare IPv6 address has more than one colon
+        let (host, port) = if let Some(rest) = rule.strip_prefix('[') {
+            let (host, after) = rest.split_once(']').ok_or_else(invalid)?;
+            match after {
+                "" => (host, None),
+                _ => (host, Some(after.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else {
+            match rule.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (rule, None),
+            }
+        };
+        let port = port.map(|port| port.parse::<u16>().map_err(|_| invalid())).transpose()?;
+
+        let host = if let Some((addr, prefix)) = host.split_once('/') {
+            let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            if prefix > max_prefix(addr) {
+                return Err(invalid());
+            }
+            HostPattern::Network(addr, prefix)
+        } else if let Ok(addr) = host.parse::<IpAddr>() {
+            HostPattern::Network(addr, max_prefix(addr))
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Suffix(format!(".{}", normalize_name(domain)))
+        } else if !host.is_empty() && !host.contains(['*', '/', ' ']) {
+            HostPattern::Name(normalize_name(host))
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Self { host, port })
+    }
+
+    fn matches(&self, name: Option<&str>, addr: IpAddr, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+
+        match &self.host {
+            HostPattern::Network(network, prefix) => in_network(addr, *network, *prefix),
+            HostPattern::Name(pattern) => name == Some(pattern.as_str()),
+            HostPattern::Suffix(suffix) => name.is_some_and(|name| name.ends_with(suffix.as_str())),
+        }
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    // IPv4-mapped IPv6 addresses are matched as the IPv4 address they carry
+    match (addr.to_canonical(), network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> DestinationFilter {
+        let owned = |rules: &[&str]| rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        DestinationFilter::new(&owned(allow), &owned(deny)).unwrap()
+    }
+
+    #[test]
+    fn test_destination_rules() {
+        let ip = |addr: &str| vec![addr.parse::<IpAddr>().unwrap()];
+        let allows = |filter: &DestinationFilter, name: Option<&str>, ips: &[IpAddr], port: u16| {
+            let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+            !filter.allowed(name, &addrs).is_empty()
+        };
+
+        let open = filter(&[], &["10.0.0.0/8", "[::1]", "*.internal", "mail.example.com:25"]);
+        assert!(allows(&open, None, &ip("1.1.1.1"), 443));
+        assert!(!allows(&open, None, &ip("10.1.2.3"), 443));
+        assert!(!allows(&open, None, &ip("::ffff:10.1.2.3"), 443));
+        assert!(!allows(&open, None, &ip("::1"), 22));
+        // A name resolving into a denied range is refused too
+        assert!(!allows(&open, Some("innocent.example"), &ip("10.0.0.1"), 80));
+        assert!(!allows(&open, Some("DB.Internal."), &ip("1.2.3.4"), 5432));
+        assert!(allows(&open, Some("internal"), &ip("1.2.3.4"), 5432));
+        assert!(!allows(&open, Some("mail.example.com"), &ip("1.2.3.4"), 25));
+        assert!(allows(&open, Some("mail.example.com"), &ip("1.2.3.4"), 587));
+
+        let closed = filter(&["*.example.com:443", "192.168.1.0/24"], &["192.168.1.1"]);
+        assert!(allows(&closed, Some("www.example.com"), &ip("93.184.216.34"), 443));
+        assert!(!allows(&closed, Some("www.example.com"), &ip("93.184.216.34"), 80));
+        assert!(allows(&closed, None, &ip("192.168.1.20"), 22));
+        assert!(!allows(&closed, None, &ip("192.168.1.1"), 22));
+        assert!(!allows(&closed, None, &ip("8.8.8.8"), 53));
+
+        // Only the allowed addresses of a name are dialed, and a denied one refuses it all
+        let mixed = |ips: &[&str]| ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 443)).collect::<Vec<_>>();
+        assert_eq!(
+            closed.allowed(Some("mixed.example.org"), &mixed(&["192.168.1.20", "10.0.0.5"])),
+            mixed(&["192.168.1.20"])
+        );
+        assert!(closed.allowed(Some("mixed.example.org"), &mixed(&["192.168.1.20", "192.168.1.1"])).is_empty());
+        assert_eq!(
+            closed.allowed(Some("www.example.com"), &mixed(&["10.0.0.5", "::1"])),
+            mixed(&["10.0.0.5", "::1"])
+        );
+
+        for rule in ["", "10.0.0.0/33", "host:port", "[::1", "a*b"] {
+            assert!(DestinationFilter::new(&[rule.to_string()], &[]).is_err(), "{}", rule);
+        }
+    }
+}
@@ -1,216 +1,567 @@
 use anyhow::Result;
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::{Aead, OsRng}};
-use chacha20poly1305::{ChaCha20Poly1305, Key as ChaChaKey};
-use rand::RngCore;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::{Aead, Payload}};
+use futures_util::ready;
+use hkdf::Hkdf;
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use sentinel_common::RelayPeer;
+use sha2::{Digest, Sha256};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
-use tokio_rustls::TlsConnector;
-use rustls::ClientConfig;
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub enum EncryptionType {
-    None,
-    Aes256Gcm,
-    ChaCha20Poly1305,
-    Tls,
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub struct EncryptionConfig {
-    pub encryption_type: EncryptionType,
-    pub key: Option<Vec<u8>>,
-    pub tls_config: Option<TlsConfig>,
-}
-
-#[allow(dead_code)]
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+
+/// PEM certificates and keys for TLS transports
 #[derive(Debug, Clone)]
 pub struct TlsConfig {
+    /// Trusted for the peer's certificate; the bundled public web roots when unset
     pub ca_cert: Option<Vec<u8>>,
     pub client_cert: Option<Vec<u8>>,
     pub client_key: Option<Vec<u8>>,
     pub server_cert: Option<Vec<u8>>,
     pub server_key: Option<Vec<u8>>,
+    /// Sent as SNI and checked against the server certificate; defaults to the host dialed
+    pub server_name: Option<String>,
     pub verify_hostname: bool,
+    /// Server side: refuse clients without a certificate signed by `ca_cert`
+    pub require_client_cert: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            server_cert: None,
+            server_key: None,
+            server_name: None,
+            verify_hostname: true,
+            require_client_cert: false,
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub struct EncryptedStream {
-    inner: Box<dyn EncryptedStreamTrait>,
+/// Pinned rather than process-wide, since both rustls backends end up compiled in
+fn tls_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
 }
 
-#[allow(dead_code)]
-trait EncryptedStreamTrait: AsyncRead + AsyncWrite + Send + Unpin {}
+fn load_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in PEM data");
+    }
+    Ok(certs)
+}
 
-impl<T: AsyncRead + AsyncWrite + Send + Unpin> EncryptedStreamTrait for T {}
+fn load_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut &pem[..])
+        .map_err(|e| anyhow::anyhow!("Failed to parse private key: {}", e))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in PEM data"))
+}
 
-impl AsyncRead for EncryptedStream {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+fn root_store(ca_cert: Option<&[u8]>) -> Result<Arc<RootCertStore>> {
+    let mut root_store = RootCertStore::empty();
+
+    if let Some(ca_cert) = ca_cert {
+        for cert in load_certs(ca_cert)? {
+            root_store.add(cert)
+                .map_err(|e| anyhow::anyhow!("Failed to add CA certificate: {}", e))?;
+        }
+    } else {
+        // Use system root certificates
+        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     }
+
+    Ok(Arc::new(root_store))
 }
 
-impl AsyncWrite for EncryptedStream {
-    fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+/// Client side of a TLS transport, presenting `client_cert` when one is configured
+pub fn build_tls_connector(config: &TlsConfig) -> Result<TlsConnector> {
+    let roots = root_store(config.ca_cert.as_deref())?;
+    let builder = ClientConfig::builder_with_provider(tls_provider()).with_safe_default_protocol_versions()?;
+
+    let builder = if config.verify_hostname {
+        builder.with_root_certificates(roots)
+    } else {
+        let verifier = WebPkiServerVerifier::builder_with_provider(roots, tls_provider()).build()?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipHostnameVerification(verifier)))
+    };
+
+    let client_config = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("TLS client certificate and key must be configured together"),
+    };
+
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Server side of a TLS transport, or `None` when no server certificate is configured
+pub fn build_tls_acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>> {
+    let (Some(cert), Some(key)) = (&config.server_cert, &config.server_key) else {
+        return Ok(None);
+    };
+
+    let builder = ServerConfig::builder_with_provider(tls_provider()).with_safe_default_protocol_versions()?;
+    let builder = if config.require_client_cert {
+        let ca_cert = config
+            .ca_cert
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Requiring client certificates needs a CA certificate"))?;
+        let verifier = WebPkiClientVerifier::builder_with_provider(root_store(Some(ca_cert))?, tls_provider()).build()?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let server_config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Checks the server's chain against our roots but accepts any name on it, for
+/// `verify_hostname = false` between hosts addressed by bare IPs
+#[derive(Debug)]
+struct SkipHostnameVerification(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for SkipHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // The chain is checked before the name, so a name mismatch means the chain is good
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
     }
 
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
     }
 
-    fn poll_shutdown(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
     }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Which end of a connection a stream sits on. Each direction is sealed with its own key,
+/// so the two ends must take opposite roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamRole {
+    Initiator,
+    Responder,
 }
 
-#[allow(dead_code)]
+/// Sent first by both ends, followed by an ephemeral X25519 public key
+const HANDSHAKE_MAGIC: &[u8; 4] = b"SXE2";
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const LENGTH_PREFIX_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+/// Largest plaintext carried by a single record
+const MAX_RECORD_LEN: usize = 16 * 1024;
+/// How long the peer gets to finish the handshake before the connection is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// AEAD stream made of length-prefixed records.
+///
+/// Every record is `len: u32 BE || ciphertext`, with the length prefix as associated data.
+/// Nonces are a per-direction record counter, so a replayed, reordered or dropped record
+/// fails to authenticate. Keys come from a fresh X25519 exchange on every connection, so
+/// nonces never repeat under one key and a leaked identity key doesn't expose past sessions.
+/// An empty record marks a clean close; EOF without one is reported as truncation.
 pub struct SymmetricEncryptedStream<T> {
     inner: T,
-    cipher: SymmetricCipher,
+    sealer: RecordCipher,
+    opener: RecordCipher,
+    /// Raw bytes read from `inner` that don't yet form a whole record
     read_buffer: Vec<u8>,
+    /// Decrypted bytes not yet handed to the reader
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    /// Sealed records not yet written to `inner`
     write_buffer: Vec<u8>,
+    write_pos: usize,
+    peer_closed: bool,
+    close_sent: bool,
 }
 
-#[allow(dead_code)]
-enum SymmetricCipher {
-    Aes256Gcm(Aes256Gcm),
-    ChaCha20Poly1305(ChaCha20Poly1305),
+/// Long-term Ed25519 key identifying this client to relay peers. The server only ever sees
+/// the public half, which it hands to the clients this one relays with.
+pub struct IdentityKey {
+    keypair: Ed25519KeyPair,
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> SymmetricEncryptedStream<T> {
-    #[allow(dead_code)]
-    pub fn new_aes256(stream: T, key: &[u8]) -> Result<Self> {
-        if key.len() != 32 {
-            anyhow::bail!("AES-256-GCM requires a 32-byte key");
+impl IdentityKey {
+    /// A fresh key and its PKCS#8 encoding
+    pub fn generate() -> Result<(Self, Vec<u8>)> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("Failed to generate identity key"))?;
+        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|e| anyhow::anyhow!("Failed to load identity key: {}", e))?;
+
+        Ok((Self { keypair }, pkcs8.as_ref().to_vec()))
+    }
+
+    /// Load the PKCS#8 key at `path`, creating one on first start
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(pkcs8) => {
+                let keypair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+                    .map_err(|e| anyhow::anyhow!("Invalid identity key in {}: {}", path.display(), e))?;
+                Ok(Self { keypair })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let (identity, pkcs8) = Self::generate()?;
+                write_private_file(path, &pkcs8)
+                    .map_err(|e| anyhow::anyhow!("Failed to write identity key to {}: {}", path.display(), e))?;
+
+                tracing::info!("Generated identity key in {}", path.display());
+                Ok(identity)
+            }
+            Err(e) => Err(anyhow::anyhow!("Failed to read identity key from {}: {}", path.display(), e)),
         }
+    }
 
-        let cipher = Aes256Gcm::new_from_slice(key)
-            .map_err(|e| anyhow::anyhow!("Failed to create AES cipher: {}", e))?;
+    pub fn public_key(&self) -> &[u8] {
+        self.keypair.public_key().as_ref()
+    }
 
-        Ok(Self {
-            inner: stream,
-            cipher: SymmetricCipher::Aes256Gcm(cipher),
-            read_buffer: Vec::new(),
-            write_buffer: Vec::new(),
-        })
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public_key())
+    }
+
+    /// Hex signature of `message`, proving this client holds the key
+    pub fn sign_hex(&self, message: &[u8]) -> String {
+        hex::encode(self.keypair.sign(message))
     }
+}
+
+/// Write a secret in one step, readable only by the client's user
+fn write_private_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
 
-    #[allow(dead_code)]
-    pub fn new_chacha20(stream: T, key: &[u8]) -> Result<Self> {
-        if key.len() != 32 {
-            anyhow::bail!("ChaCha20Poly1305 requires a 32-byte key");
+/// What one end brings to the relay handshake
+pub struct HandshakeKeys<'a> {
+    pub identity: &'a IdentityKey,
+    /// Identity key the server registered for the peer; the handshake fails for anyone else
+    pub peer_public_key: &'a [u8],
+    /// `transport.encryption_key`, mixed into the session keys when set. Both ends must agree.
+    pub psk: Option<&'a [u8]>,
+}
+
+/// One direction of a stream: a cipher and the counter that supplies its nonces
+struct RecordCipher {
+    cipher: Box<Aes256Gcm>,
+    counter: u64,
+}
+
+impl RecordCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Box::new(Aes256Gcm::new(key.into())),
+            counter: 0,
         }
+    }
 
-        let key = ChaChaKey::from_slice(key);
-        let cipher = ChaCha20Poly1305::new(key);
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        if self.counter == u64::MAX {
+            anyhow::bail!("Record counter exhausted");
+        }
 
-        Ok(Self {
-            inner: stream,
-            cipher: SymmetricCipher::ChaCha20Poly1305(cipher),
-            read_buffer: Vec::new(),
-            write_buffer: Vec::new(),
-        })
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Ok(nonce)
     }
 
-    #[allow(dead_code)]
-    fn encrypt_data(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
-        let mut nonce_bytes = [0u8; 12];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+    /// Append `len || ciphertext` for `plaintext` to `out`
+    fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let nonce = self.next_nonce()?;
+        let len = ((plaintext.len() + TAG_LEN) as u32).to_be_bytes();
+        let payload = Payload { msg: plaintext, aad: &len };
 
-        let ciphertext = match &self.cipher {
-            SymmetricCipher::Aes256Gcm(cipher) => {
-                cipher.encrypt(nonce, plaintext)
-                    .map_err(|e| anyhow::anyhow!("AES encryption failed: {}", e))?
-            }
-            SymmetricCipher::ChaCha20Poly1305(cipher) => {
-                cipher.encrypt(nonce, plaintext)
-                    .map_err(|e| anyhow::anyhow!("ChaCha20 encryption failed: {}", e))?
-            }
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Record encryption failed"))?;
+
+        out.extend_from_slice(&len);
+        out.extend_from_slice(&ciphertext);
+        Ok(())
+    }
+
+    fn open(&mut self, len: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        let payload = Payload { msg: ciphertext, aad: len };
+
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Record authentication failed (wrong key, tampered or replayed data)"))
+    }
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SymmetricEncryptedStream<T> {
+    pub async fn new_aes256(stream: T, keys: &HandshakeKeys<'_>, role: StreamRole) -> Result<Self> {
+        Self::handshake(stream, keys, role).await
+    }
+
+    /// Run the handshake, giving up on a peer that stalls for longer than `HANDSHAKE_TIMEOUT`
+    pub async fn handshake(stream: T, keys: &HandshakeKeys<'_>, role: StreamRole) -> Result<Self> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, Self::exchange_keys(stream, keys, role))
+            .await
+            .map_err(|_| anyhow::anyhow!("Relay handshake timed out"))?
+    }
+
+    /// Swap ephemeral X25519 keys, prove both identities by signing the exchange with each
+    /// end's Ed25519 key, and derive this connection's directional keys from the shared secret
+    async fn exchange_keys(mut stream: T, keys: &HandshakeKeys<'_>, role: StreamRole) -> Result<Self> {
+        if keys.peer_public_key.len() != PUBLIC_KEY_LEN {
+            anyhow::bail!("Peer identity key must be {} bytes", PUBLIC_KEY_LEN);
+        }
+
+        let ephemeral = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("Failed to generate ephemeral key"))?;
+        let ephemeral_public = ephemeral
+            .compute_public_key()
+            .map_err(|_| anyhow::anyhow!("Failed to compute ephemeral key"))?;
+
+        let mut our_hello = [0u8; 4 + PUBLIC_KEY_LEN];
+        our_hello[..4].copy_from_slice(HANDSHAKE_MAGIC);
+        our_hello[4..].copy_from_slice(ephemeral_public.as_ref());
+        stream.write_all(&our_hello).await?;
+        stream.flush().await?;
+
+        let mut peer_hello = [0u8; 4 + PUBLIC_KEY_LEN];
+        stream.read_exact(&mut peer_hello).await?;
+        if &peer_hello[..4] != HANDSHAKE_MAGIC {
+            anyhow::bail!("Peer is not speaking the encrypted relay protocol");
+        }
+
+        let (initiator_ephemeral, responder_ephemeral) = match role {
+            StreamRole::Initiator => (&our_hello[4..], &peer_hello[4..]),
+            StreamRole::Responder => (&peer_hello[4..], &our_hello[4..]),
+        };
+        let transcript: [u8; 32] = Sha256::new()
+            .chain_update(b"sentinel relay handshake v2")
+            .chain_update(initiator_ephemeral)
+            .chain_update(responder_ephemeral)
+            .finalize()
+            .into();
+
+        let mut our_auth = [0u8; PUBLIC_KEY_LEN + SIGNATURE_LEN];
+        our_auth[..PUBLIC_KEY_LEN].copy_from_slice(keys.identity.public_key());
+        our_auth[PUBLIC_KEY_LEN..].copy_from_slice(keys.identity.keypair.sign(&signed_message(role, &transcript)).as_ref());
+        stream.write_all(&our_auth).await?;
+        stream.flush().await?;
+
+        let mut peer_auth = [0u8; PUBLIC_KEY_LEN + SIGNATURE_LEN];
+        stream.read_exact(&mut peer_auth).await?;
+        let (peer_identity, peer_signature) = peer_auth.split_at(PUBLIC_KEY_LEN);
+        if peer_identity != keys.peer_public_key {
+            anyhow::bail!("Peer identity key does not match the one registered with the server");
+        }
+
+        let peer_role = match role {
+            StreamRole::Initiator => StreamRole::Responder,
+            StreamRole::Responder => StreamRole::Initiator,
+        };
+        signature::UnparsedPublicKey::new(&signature::ED25519, peer_identity)
+            .verify(&signed_message(peer_role, &transcript), peer_signature)
+            .map_err(|_| anyhow::anyhow!("Peer failed to prove its identity"))?;
+
+        let mut secret = agreement::agree_ephemeral(
+            ephemeral,
+            &agreement::UnparsedPublicKey::new(&X25519, &peer_hello[4..]),
+            |shared| shared.to_vec(),
+        )
+        .map_err(|_| anyhow::anyhow!("Key agreement failed"))?;
+        if let Some(psk) = keys.psk {
+            secret.extend_from_slice(psk);
+        }
+
+        let (initiator_identity, responder_identity) = match role {
+            StreamRole::Initiator => (keys.identity.public_key(), peer_identity),
+            StreamRole::Responder => (peer_identity, keys.identity.public_key()),
         };
+        let salt = [&transcript[..], initiator_identity, responder_identity].concat();
 
-        // Prepend nonce to ciphertext
-        let mut result = Vec::with_capacity(12 + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
-        Ok(result)
+        let (send_key, recv_key) = derive_keys(&secret, &salt, role)?;
+        Ok(Self::with_keys(stream, &send_key, &recv_key))
     }
 
-    #[allow(dead_code)]
-    fn decrypt_data(&self, encrypted_data: &[u8]) -> Result<Vec<u8>> {
-        if encrypted_data.len() < 12 {
-            anyhow::bail!("Encrypted data too short");
+    fn with_keys(inner: T, send_key: &[u8; 32], recv_key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            sealer: RecordCipher::new(send_key),
+            opener: RecordCipher::new(recv_key),
+            read_buffer: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+            write_buffer: Vec::new(),
+            write_pos: 0,
+            peer_closed: false,
+            close_sent: false,
         }
+    }
 
-        let (nonce_bytes, ciphertext) = encrypted_data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
+    /// Decrypt the next whole record in `read_buffer`, if one has arrived
+    fn open_buffered_record(&mut self) -> std::io::Result<bool> {
+        if self.read_buffer.len() < LENGTH_PREFIX_LEN {
+            return Ok(false);
+        }
 
-        let plaintext = match &self.cipher {
-            SymmetricCipher::Aes256Gcm(cipher) => {
-                cipher.decrypt(nonce, ciphertext)
-                    .map_err(|e| anyhow::anyhow!("AES decryption failed: {}", e))?
-            }
-            SymmetricCipher::ChaCha20Poly1305(cipher) => {
-                cipher.decrypt(nonce, ciphertext)
-                    .map_err(|e| anyhow::anyhow!("ChaCha20 decryption failed: {}", e))?
+        let len = u32::from_be_bytes(self.read_buffer[..LENGTH_PREFIX_LEN].try_into().unwrap()) as usize;
+        if !(TAG_LEN..=MAX_RECORD_LEN + TAG_LEN).contains(&len) {
+            return Err(invalid_data(format!("Invalid encrypted record length {}", len)));
+        }
+        if self.read_buffer.len() < LENGTH_PREFIX_LEN + len {
+            return Ok(false);
+        }
+
+        let (prefix, rest) = self.read_buffer.split_at(LENGTH_PREFIX_LEN);
+        let plaintext = self.opener.open(prefix, &rest[..len]).map_err(invalid_data)?;
+        self.read_buffer.drain(..LENGTH_PREFIX_LEN + len);
+
+        if plaintext.is_empty() {
+            self.peer_closed = true;
+        }
+        self.plaintext = plaintext;
+        self.plaintext_pos = 0;
+        Ok(true)
+    }
+
+    /// Write out sealed records until the buffer is empty
+    fn poll_write_buffered(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        while self.write_pos < self.write_buffer.len() {
+            let n = ready!(std::pin::Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer[self.write_pos..]))?;
+            if n == 0 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
             }
-        };
+            self.write_pos += n;
+        }
 
-        Ok(plaintext)
+        self.write_buffer.clear();
+        self.write_pos = 0;
+        std::task::Poll::Ready(Ok(()))
     }
 }
 
+/// What each end signs: its role, so a signature can't be reflected back, and the exchange
+fn signed_message(role: StreamRole, transcript: &[u8; 32]) -> Vec<u8> {
+    let label: &[u8] = match role {
+        StreamRole::Initiator => b"sentinel relay initiator",
+        StreamRole::Responder => b"sentinel relay responder",
+    };
+    [label, &transcript[..]].concat()
+}
+
+/// HKDF-SHA256 over the shared secret, salted with the transcript and both identities;
+/// returns (send, receive)
+fn derive_keys(secret: &[u8], salt: &[u8], role: StreamRole) -> Result<([u8; 32], [u8; 32])> {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), secret);
+
+    let mut initiator_key = [0u8; 32];
+    let mut responder_key = [0u8; 32];
+    hkdf.expand(b"sentinel relay initiator->responder", &mut initiator_key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    hkdf.expand(b"sentinel relay responder->initiator", &mut responder_key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+
+    Ok(match role {
+        StreamRole::Initiator => (initiator_key, responder_key),
+        StreamRole::Responder => (responder_key, initiator_key),
+    })
+}
+
 impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for SymmetricEncryptedStream<T> {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        // For simplicity, this is a basic implementation
-        // In production, you'd want proper buffering and framing
-        let mut temp_buf = vec![0u8; 8192];
-        let mut read_buf = tokio::io::ReadBuf::new(&mut temp_buf);
-
-        match std::pin::Pin::new(&mut self.inner).poll_read(cx, &mut read_buf) {
-            std::task::Poll::Ready(Ok(())) => {
-                let filled = read_buf.filled();
-                if filled.is_empty() {
-                    return std::task::Poll::Ready(Ok(()));
-                }
-
-                match self.decrypt_data(filled) {
-                    Ok(decrypted) => {
-                        let to_copy = std::cmp::min(decrypted.len(), buf.remaining());
-                        buf.put_slice(&decrypted[..to_copy]);
-                        std::task::Poll::Ready(Ok(()))
-                    }
-                    Err(e) => {
-                        std::task::Poll::Ready(Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Decryption error: {}", e),
-                        )))
-                    }
-                }
+        let this = &mut *self;
+
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let n = std::cmp::min(available.len(), buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.plaintext_pos += n;
+                return std::task::Poll::Ready(Ok(()));
+            }
+
+            if this.peer_closed {
+                return std::task::Poll::Ready(Ok(()));
+            }
+
+            if this.open_buffered_record()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = tokio::io::ReadBuf::new(&mut chunk);
+            ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+
+            if chunk_buf.filled().is_empty() {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Encrypted stream ended without a close record",
+                )));
             }
-            std::task::Poll::Ready(Err(e)) => std::task::Poll::Ready(Err(e)),
-            std::task::Poll::Pending => std::task::Poll::Pending,
+            this.read_buffer.extend_from_slice(chunk_buf.filled());
         }
     }
 }
@@ -221,124 +572,365 @@ impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SymmetricEncryptedStream<
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        match self.encrypt_data(buf) {
-            Ok(encrypted) => {
-                match std::pin::Pin::new(&mut self.inner).poll_write(cx, &encrypted) {
-                    std::task::Poll::Ready(Ok(_)) => std::task::Poll::Ready(Ok(buf.len())),
-                    std::task::Poll::Ready(Err(e)) => std::task::Poll::Ready(Err(e)),
-                    std::task::Poll::Pending => std::task::Poll::Pending,
-                }
-            }
-            Err(e) => {
-                std::task::Poll::Ready(Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Encryption error: {}", e),
-                )))
-            }
+        let this = &mut *self;
+
+        // Only take new data once earlier records are on the wire, which bounds the buffer
+        ready!(this.poll_write_buffered(cx))?;
+
+        if buf.is_empty() {
+            return std::task::Poll::Ready(Ok(0));
         }
+        if this.close_sent {
+            return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = std::cmp::min(buf.len(), MAX_RECORD_LEN);
+        this.sealer.seal(&buf[..n], &mut this.write_buffer).map_err(invalid_data)?;
+
+        // The record is accepted; push what we can now and leave the rest to the next call
+        if let std::task::Poll::Ready(Err(e)) = this.poll_write_buffered(cx) {
+            return std::task::Poll::Ready(Err(e));
+        }
+        std::task::Poll::Ready(Ok(n))
     }
 
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        let this = &mut *self;
+        ready!(this.poll_write_buffered(cx))?;
+        std::pin::Pin::new(&mut this.inner).poll_flush(cx)
     }
 
     fn poll_shutdown(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        let this = &mut *self;
+        ready!(this.poll_write_buffered(cx))?;
+
+        if !this.close_sent {
+            this.sealer.seal(&[], &mut this.write_buffer).map_err(invalid_data)?;
+            this.close_sent = true;
+            ready!(this.poll_write_buffered(cx))?;
+        }
+
+        std::pin::Pin::new(&mut this.inner).poll_shutdown(cx)
     }
 }
 
-pub struct EncryptionManager;
+/// Wraps relay connections in the transport their config asks for
+#[derive(Clone)]
+pub struct EncryptionManager {
+    identity: Arc<IdentityKey>,
+    /// Optional pre-shared key from `transport.encryption_key`
+    relay_key: Option<Vec<u8>>,
+    tls_connector: TlsConnector,
+    /// Only present when `[transport.tls]` has a certificate to serve
+    tls_acceptor: Option<TlsAcceptor>,
+    tls_server_name: Option<String>,
+}
 
 impl EncryptionManager {
-    pub fn new() -> Self {
-        Self
+    pub fn new(identity: Arc<IdentityKey>, relay_key: Option<Vec<u8>>, tls: &TlsConfig) -> Result<Self> {
+        Ok(Self {
+            identity,
+            relay_key,
+            tls_connector: build_tls_connector(tls)?,
+            tls_acceptor: build_tls_acceptor(tls)?,
+            tls_server_name: tls.server_name.clone(),
+        })
     }
 
-    #[allow(dead_code)]
-    pub async fn wrap_stream(&self, stream: TcpStream, config: EncryptionConfig) -> Result<EncryptedStream> {
-        match config.encryption_type {
-            EncryptionType::None => {
-                Ok(EncryptedStream {
-                    inner: Box::new(stream),
-                })
-            }
-            EncryptionType::Aes256Gcm => {
-                let key = config.key.ok_or_else(|| anyhow::anyhow!("AES key required"))?;
-                let encrypted_stream = SymmetricEncryptedStream::new_aes256(stream, &key)?;
-                Ok(EncryptedStream {
-                    inner: Box::new(encrypted_stream),
-                })
-            }
-            EncryptionType::ChaCha20Poly1305 => {
-                let key = config.key.ok_or_else(|| anyhow::anyhow!("ChaCha20 key required"))?;
-                let encrypted_stream = SymmetricEncryptedStream::new_chacha20(stream, &key)?;
-                Ok(EncryptedStream {
-                    inner: Box::new(encrypted_stream),
-                })
-            }
-            EncryptionType::Tls => {
-                self.wrap_with_tls(stream, config.tls_config).await
-            }
+    pub fn has_tls_acceptor(&self) -> bool {
+        self.tls_acceptor.is_some()
+    }
+
+    /// Open TLS to the exit end of a relay at `host`, which is also the SNI unless
+    /// `transport.tls.server_name` overrides it
+    pub async fn connect_tls<S>(&self, stream: S, host: &str) -> Result<client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let name = self.tls_server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|e| anyhow::anyhow!("Invalid TLS server name {}: {}", name, e))?;
+
+        self.tls_connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| anyhow::anyhow!("TLS connection to {} failed: {}", name, e))
+    }
+
+    /// Terminate TLS from the entry end of a relay
+    pub async fn accept_tls<S>(&self, stream: S) -> Result<server::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self
+            .tls_acceptor
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("TLS relay exit needs transport.tls.cert_file and key_file"))?;
+
+        acceptor
+            .accept(stream)
+            .await
+            .map_err(|e| anyhow::anyhow!("TLS handshake failed: {}", e))
+    }
+
+    fn handshake_keys<'a>(&'a self, peer_public_key: &'a [u8]) -> HandshakeKeys<'a> {
+        HandshakeKeys {
+            identity: &self.identity,
+            peer_public_key,
+            psk: self.relay_key.as_deref(),
         }
     }
 
-    #[allow(dead_code)]
-    async fn wrap_with_tls(&self, stream: TcpStream, tls_config: Option<TlsConfig>) -> Result<EncryptedStream> {
-        let tls_config = tls_config.ok_or_else(|| anyhow::anyhow!("TLS config required"))?;
+    /// Run the encrypted-relay handshake with `peer` over `stream`; there is no plaintext fallback
+    pub async fn encrypt_relay_stream<S>(&self, stream: S, peer: &RelayPeer, role: StreamRole) -> Result<SymmetricEncryptedStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let peer_key = hex::decode(&peer.public_key)
+            .map_err(|e| anyhow::anyhow!("Invalid identity key for client {}: {}", peer.client_id, e))?;
+
+        SymmetricEncryptedStream::new_aes256(stream, &self.handshake_keys(&peer_key), role)
+            .await
+            .map_err(|e| anyhow::anyhow!("Relay handshake with client {} failed: {}", peer.client_id, e))
+    }
+}
 
-        // Create a basic TLS client configuration
-        let mut root_store = rustls::RootCertStore::empty();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
 
-        if let Some(ca_cert) = tls_config.ca_cert {
-            let ca_cert = rustls_pemfile::certs(&mut ca_cert.as_slice())
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| anyhow::anyhow!("Failed to parse CA certificate: {}", e))?;
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
 
-            for cert in ca_cert {
-                root_store.add(cert)
-                    .map_err(|e| anyhow::anyhow!("Failed to add CA certificate: {}", e))?;
-            }
-        } else {
-            // Use system root certificates
-            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    fn identity() -> IdentityKey {
+        IdentityKey::generate().unwrap().0
+    }
+
+    fn keys<'a>(identity: &'a IdentityKey, peer: &'a IdentityKey, psk: Option<&'a [u8]>) -> HandshakeKeys<'a> {
+        HandshakeKeys {
+            identity,
+            peer_public_key: peer.public_key(),
+            psk,
         }
+    }
 
-        let client_config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+    /// Run both ends of the handshake, `a` as initiator
+    async fn handshake<S>(
+        a: S,
+        b: S,
+        a_keys: HandshakeKeys<'_>,
+        b_keys: HandshakeKeys<'_>,
+    ) -> (Result<SymmetricEncryptedStream<S>>, Result<SymmetricEncryptedStream<S>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::join!(
+            SymmetricEncryptedStream::new_aes256(a, &a_keys, StreamRole::Initiator),
+            SymmetricEncryptedStream::new_aes256(b, &b_keys, StreamRole::Responder),
+        )
+    }
 
-        let connector = TlsConnector::from(Arc::new(client_config));
-        let domain = rustls::pki_types::ServerName::try_from("localhost")
-            .map_err(|e| anyhow::anyhow!("Invalid server name: {}", e))?;
+    async fn pair<S>(a: S, b: S) -> (SymmetricEncryptedStream<S>, SymmetricEncryptedStream<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (alice, bob) = (identity(), identity());
+        let (a, b) = handshake(a, b, keys(&alice, &bob, None), keys(&bob, &alice, None)).await;
+        (a.unwrap(), b.unwrap())
+    }
 
-        let tls_stream = connector.connect(domain, stream).await
-            .map_err(|e| anyhow::anyhow!("TLS connection failed: {}", e))?;
+    /// Send `data` one way in odd-sized writes, shut down, and return everything the other end read
+    async fn transfer<A, B>(mut writer: A, mut reader: B, data: Vec<u8>) -> Vec<u8>
+    where
+        A: AsyncWrite + Unpin + Send + 'static,
+        B: AsyncRead + Unpin + Send + 'static,
+    {
+        let send = tokio::spawn(async move {
+            for chunk in data.chunks(7919) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+        });
 
-        Ok(EncryptedStream {
-            inner: Box::new(tls_stream),
-        })
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        send.await.unwrap();
+        received
     }
 
-    #[allow(dead_code)]
-    pub fn generate_key() -> Vec<u8> {
-        let mut key = vec![0u8; 32];
-        OsRng.fill_bytes(&mut key);
-        key
+    #[tokio::test]
+    async fn test_multi_megabyte_over_small_duplex() {
+        // A tiny pipe forces partial reads and writes on every record
+        let (a, b) = tokio::io::duplex(1000);
+        let (a, b) = pair(a, b).await;
+
+        let (a_read, a_write) = tokio::io::split(a);
+        let (b_read, b_write) = tokio::io::split(b);
+
+        let up = pattern(3 * 1024 * 1024 + 17, 1);
+        let down = pattern(2 * 1024 * 1024 + 5, 2);
+        let (got_up, got_down) = tokio::join!(
+            transfer(a_write, b_read, up.clone()),
+            transfer(b_write, a_read, down.clone()),
+        );
+
+        assert_eq!(got_up, up);
+        assert_eq!(got_down, down);
     }
-}
 
-impl Default for EncryptionConfig {
-    fn default() -> Self {
-        Self {
-            encryption_type: EncryptionType::None,
-            key: None,
-            tls_config: None,
+    #[tokio::test]
+    async fn test_multi_megabyte_over_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (client, server) = pair(client.unwrap(), server.unwrap().0).await;
+
+        let data = pattern(8 * 1024 * 1024, 3);
+        assert_eq!(transfer(client, server, data.clone()).await, data);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_peer_identity_is_rejected() {
+        let (alice, bob, mallory) = (identity(), identity(), identity());
+        let (a, b) = tokio::io::duplex(64 * 1024);
+
+        // Mallory answers a connection meant for Bob
+        let (a, b) = handshake(a, b, keys(&alice, &bob, None), keys(&mallory, &alice, None)).await;
+
+        let err = a.err().expect("handshake with the wrong peer succeeded").to_string();
+        assert!(err.contains("does not match"), "{}", err);
+        assert!(b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_psk_is_rejected() {
+        let (alice, bob) = (identity(), identity());
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (a, b) = handshake(a, b, keys(&alice, &bob, Some(&[1u8; 32])), keys(&bob, &alice, Some(&[2u8; 32]))).await;
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        a.write_all(b"secret").await.unwrap();
+        a.flush().await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = b.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_stream_is_an_error() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (mut a, mut b) = pair(a, b).await;
+
+        a.write_all(b"partial").await.unwrap();
+        a.flush().await.unwrap();
+        // Drop without shutdown, so no close record is sent
+        drop(a);
+
+        let mut received = Vec::new();
+        let err = b.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(received, b"partial");
+    }
+
+    #[test]
+    fn test_replayed_and_reordered_records_fail() {
+        let key = [9u8; 32];
+        let mut sealer = RecordCipher::new(&key);
+
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        sealer.seal(b"one", &mut first).unwrap();
+        sealer.seal(b"two", &mut second).unwrap();
+
+        let open = |opener: &mut RecordCipher, record: &[u8]| {
+            let (len, ciphertext) = record.split_at(LENGTH_PREFIX_LEN);
+            opener.open(len, ciphertext)
+        };
+
+        let mut opener = RecordCipher::new(&key);
+        assert!(open(&mut opener, &second).is_err(), "reordered record accepted");
+
+        let mut opener = RecordCipher::new(&key);
+        assert_eq!(open(&mut opener, &first).unwrap(), b"one");
+        assert!(open(&mut opener, &first).is_err(), "replayed record accepted");
+    }
+
+    const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+    const RELAY_CERT: &[u8] = include_bytes!("../testdata/relay.pem");
+    const RELAY_KEY: &[u8] = include_bytes!("../testdata/relay-key.pem");
+    const OTHER_CERT: &[u8] = include_bytes!("../testdata/other.pem");
+    const OTHER_KEY: &[u8] = include_bytes!("../testdata/other-key.pem");
+
+    /// Both testdata chains carry the same names; only relay.pem is signed by ca.pem
+    fn tls_config(cert: &[u8], key: &[u8]) -> TlsConfig {
+        TlsConfig {
+            ca_cert: Some(CA.to_vec()),
+            client_cert: Some(cert.to_vec()),
+            client_key: Some(key.to_vec()),
+            server_cert: Some(RELAY_CERT.to_vec()),
+            server_key: Some(RELAY_KEY.to_vec()),
+            require_client_cert: true,
+            ..TlsConfig::default()
         }
     }
-}
\ No newline at end of file
+
+    /// Run a TLS handshake over a duplex pipe and pass one message from client to server
+    async fn tls_exchange(client: &TlsConfig, server: &TlsConfig, name: &str) -> Result<Vec<u8>> {
+        let connector = build_tls_connector(client)?;
+        let acceptor = build_tls_acceptor(server)?.expect("server certificate configured");
+        let (a, b) = tokio::io::duplex(16 * 1024);
+        let server_name = ServerName::try_from(name.to_string())?;
+
+        let (client, server) = tokio::join!(connector.connect(server_name, a), acceptor.accept(b));
+        let (mut client, mut server) = (client?, server?);
+
+        client.write_all(b"through tls").await?;
+        client.shutdown().await?;
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await?;
+        Ok(received)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let config = tls_config(RELAY_CERT, RELAY_KEY);
+        let received = tls_exchange(&config, &config, "relay.sentinel.test").await.unwrap();
+        assert_eq!(received, b"through tls");
+
+        // The server must refuse a client certificate from another CA
+        let untrusted = tls_config(OTHER_CERT, OTHER_KEY);
+        assert!(tls_exchange(&untrusted, &config, "relay.sentinel.test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_hostname_verification() {
+        let config = tls_config(RELAY_CERT, RELAY_KEY);
+        assert!(tls_exchange(&config, &config, "elsewhere.test").await.is_err());
+
+        let lenient = TlsConfig {
+            verify_hostname: false,
+            ..config.clone()
+        };
+        let received = tls_exchange(&lenient, &config, "elsewhere.test").await.unwrap();
+        assert_eq!(received, b"through tls");
+
+        // Skipping the name check still requires a trusted chain
+        let other = TlsConfig {
+            server_cert: Some(OTHER_CERT.to_vec()),
+            server_key: Some(OTHER_KEY.to_vec()),
+            require_client_cert: false,
+            ..config.clone()
+        };
+        assert!(tls_exchange(&lenient, &other, "elsewhere.test").await.is_err());
+    }
+}
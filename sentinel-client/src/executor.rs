@@ -0,0 +1,101 @@
+use anyhow::Result;
+use sentinel_common::{ConfigUpdate, ProxyCommand, RelayConfig, Task, TaskResult, TaskType};
+use std::sync::Arc;
+
+use crate::iptables::IptablesManager;
+use crate::proxy::ProxyRegistry;
+use crate::relay::RelayManager;
+use crate::settings::SettingsManager;
+
+/// Runs tasks received from the server and turns their outcome into a `TaskResult`
+pub struct TaskExecutor {
+    relay_manager: Arc<RelayManager>,
+    iptables_manager: Arc<IptablesManager>,
+    proxy_registry: Arc<ProxyRegistry>,
+    settings: Arc<SettingsManager>,
+}
+
+impl TaskExecutor {
+    pub fn new(
+        relay_manager: Arc<RelayManager>,
+        iptables_manager: Arc<IptablesManager>,
+        proxy_registry: Arc<ProxyRegistry>,
+        settings: Arc<SettingsManager>,
+    ) -> Self {
+        Self {
+            relay_manager,
+            iptables_manager,
+            proxy_registry,
+            settings,
+        }
+    }
+
+    pub async fn run(&self, task: &Task) -> TaskResult {
+        tracing::info!("Processing task: {} (type: {:?})", task.id, task.task_type);
+
+        match self.execute(task).await {
+            Ok(data) => TaskResult {
+                task_id: task.id.clone(),
+                success: true,
+                message: "ok".to_string(),
+                data,
+            },
+            Err(e) => {
+                tracing::error!("Task {} failed: {}", task.id, e);
+                TaskResult {
+                    task_id: task.id.clone(),
+                    success: false,
+                    message: e.to_string(),
+                    data: None,
+                }
+            }
+        }
+    }
+
+    async fn execute(&self, task: &Task) -> Result<Option<serde_json::Value>> {
+        match task.task_type {
+            TaskType::StartRelay => {
+                let config: RelayConfig = serde_json::from_value(task.payload.clone())?;
+                let bound = self.relay_manager.start_relay(config).await?;
+                Ok(Some(serde_json::json!({ "listen_addr": bound.to_string() })))
+            }
+            TaskType::StopRelay => {
+                let config: RelayConfig = serde_json::from_value(task.payload.clone())?;
+                let report = self.relay_manager.stop_relay(&config).await?;
+                Ok(Some(serde_json::to_value(report)?))
+            }
+            TaskType::SetRelayBandwidth => {
+                let config: RelayConfig = serde_json::from_value(task.payload.clone())?;
+                self.relay_manager.set_bandwidth(&config).await?;
+                Ok(None)
+            }
+            TaskType::UpdateIptables => {
+                let applied = self.iptables_manager.process_task(task).await?;
+                Ok(Some(serde_json::json!({ "rules_applied": applied })))
+            }
+            TaskType::ConfigureProxy => match serde_json::from_value(task.payload.clone())? {
+                ProxyCommand::Start(config) => {
+                    let bound = self.proxy_registry.start(*config).await?;
+                    Ok(Some(serde_json::json!({ "listen_addr": bound.to_string() })))
+                }
+                ProxyCommand::SetBandwidth {
+                    name,
+                    bandwidth,
+                    source_bandwidth,
+                } => {
+                    self.proxy_registry.set_bandwidth(&name, bandwidth, source_bandwidth).await?;
+                    Ok(None)
+                }
+                ProxyCommand::Stop { name } => {
+                    self.proxy_registry.stop(&name).await?;
+                    Ok(None)
+                }
+            },
+            TaskType::UpdateConfig => {
+                let update: ConfigUpdate = serde_json::from_value(task.payload.clone())?;
+                let outcome = self.settings.apply(&update).await?;
+                Ok(Some(serde_json::to_value(outcome)?))
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use sentinel_common::{ExitProbe, RelayConfig};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// How long resolved addresses of an exit are reused before its name is looked up again
+const RESOLVE_TTL: Duration = Duration::from_secs(60);
+/// Connection attempts to an exit outside of probes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A relay's exit points in priority order: `exit_point`, then its fallbacks. Names are
+/// resolved again once `RESOLVE_TTL` passes, and exits that fail a connection or a probe
+/// are tried only after the healthy ones.
+pub struct ExitPool {
+    exits: Vec<Exit>,
+    probe: Option<ExitProbe>,
+}
+
+struct Exit {
+    addr: String,
+    state: Mutex<ExitState>,
+}
+
+#[derive(Default)]
+struct ExitState {
+    resolved: Vec<SocketAddr>,
+    resolved_at: Option<Instant>,
+    down: bool,
+}
+
+impl ExitPool {
+    pub fn new(config: &RelayConfig) -> Result<Self> {
+        let exits = std::iter::once(&config.exit_point)
+            .chain(&config.fallback_exits)
+            .map(|addr| {
+                // Only the syntax is checked here; a name that doesn't resolve yet is just down
+                match addr.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Exit {
+                        addr: addr.clone(),
+                        state: Mutex::new(ExitState::default()),
+                    }),
+                    _ => Err(anyhow::anyhow!("Invalid exit point {:?}, expected host:port", addr)),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { exits, probe: config.exit_probe.clone() })
+    }
+
+    /// Connect to the first exit that accepts, healthy ones first
+    pub async fn connect(&self) -> Result<TcpStream> {
+        let (healthy, down): (Vec<_>, Vec<_>) = self.exits.iter().partition(|exit| !exit.state.lock().unwrap().down);
+
+        let mut errors = Vec::new();
+        for exit in healthy.into_iter().chain(down) {
+            match exit.connect(CONNECT_TIMEOUT).await {
+                Ok(stream) => {
+                    tracing::debug!("Direct connection established to {}", exit.addr);
+                    return Ok(stream);
+                }
+                Err(e) => errors.push(format!("{}: {}", exit.addr, e)),
+            }
+        }
+
+        anyhow::bail!("No exit point reachable ({})", errors.join("; "))
+    }
+
+    /// Probe every exit on the configured interval; never returns, and without probes
+    /// configured it only waits
+    pub async fn probe(&self) {
+        let Some(probe) = &self.probe else {
+            return std::future::pending().await;
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(probe.interval_secs.max(1)));
+        let timeout = Duration::from_millis(probe.timeout_ms);
+        loop {
+            interval.tick().await;
+            for exit in &self.exits {
+                if let Err(e) = exit.connect(timeout).await {
+                    tracing::debug!("Probe of exit {} failed: {}", exit.addr, e);
+                }
+            }
+        }
+    }
+}
+
+impl Exit {
+    /// Try each of the exit's addresses, recording whether it is up
+    async fn connect(&self, timeout: Duration) -> Result<TcpStream> {
+        let result = self.try_connect(timeout).await;
+
+        let was_down = std::mem::replace(&mut self.state.lock().unwrap().down, result.is_err());
+        match (&result, was_down) {
+            (Ok(_), true) => tracing::info!("Exit {} is back up", self.addr),
+            (Err(e), false) => tracing::warn!("Exit {} is down: {}", self.addr, e),
+            _ => {}
+        }
+
+        result
+    }
+
+    async fn try_connect(&self, timeout: Duration) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.resolve().await? {
+            match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(anyhow::anyhow!("{}: {}", addr, e)),
+                Err(_) => last_error = Some(anyhow::anyhow!("{}: connection timed out", addr)),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no addresses")))
+    }
+
+    /// The exit's addresses, looked up again once the last lookup is `RESOLVE_TTL` old. A
+    /// failed lookup falls back to the previous addresses, if there were any.
+    async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        {
+            let state = self.state.lock().unwrap();
+            if state.resolved_at.is_some_and(|at| at.elapsed() < RESOLVE_TTL) {
+                return Ok(state.resolved.clone());
+            }
+        }
+
+        match tokio::net::lookup_host(self.addr.as_str()).await {
+            Ok(addrs) => {
+                let addrs: Vec<SocketAddr> = addrs.collect();
+                let mut state = self.state.lock().unwrap();
+                if !state.resolved.is_empty() && state.resolved != addrs {
+                    tracing::info!("Exit {} now resolves to {:?}", self.addr, addrs);
+                }
+                state.resolved = addrs.clone();
+                state.resolved_at = Some(Instant::now());
+                Ok(addrs)
+            }
+            Err(e) => {
+                let state = self.state.lock().unwrap();
+                if state.resolved.is_empty() {
+                    anyhow::bail!("lookup failed: {}", e);
+                }
+                tracing::warn!("Lookup of exit {} failed, keeping {:?}: {}", self.addr, state.resolved, e);
+                Ok(state.resolved.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_failover_to_next_exit() {
+        // A port nothing listens on any more
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config: RelayConfig = serde_json::from_value(serde_json::json!({
+            "entry_point": "127.0.0.1:0",
+            "exit_point": closed.to_string(),
+            "fallback_exits": [format!("localhost:{}", port)],
+            "transport_type": "Direct",
+        }))
+        .unwrap();
+        let pool = ExitPool::new(&config).unwrap();
+
+        let stream = pool.connect().await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+        assert!(pool.exits[0].state.lock().unwrap().down);
+        assert!(!pool.exits[1].state.lock().unwrap().down);
+
+        assert!(ExitPool::new(&RelayConfig { exit_point: "nonsense".into(), ..config }).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::Result;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+use sentinel_common::{HttpRoute, ProxyAuth};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::time::Sleep;
+
+use crate::limiter::{Direction, Shaper};
+use crate::socks::constant_time_eq;
+use crate::stats::StatsCollector;
+
+pub type HttpBody = BoxBody<Bytes, hyper::Error>;
+
+/// Headers that only apply to one connection and are never passed on
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Reverse proxy routes of an HTTP proxy
+#[derive(Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+pub struct Route {
+    host: HostMatch,
+    path_prefix: String,
+    backends: Vec<String>,
+    next_backend: AtomicUsize,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum HostMatch {
+    Any,
+    /// `.example.com`, from `*.example.com`
+    Suffix(String),
+    Exact(String),
+}
+
+impl RouteTable {
+    pub fn new(routes: &[HttpRoute]) -> Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                if route.backends.is_empty() {
+                    anyhow::bail!("HTTP route {:?} has no backends", route.path_prefix);
+                }
+                if let Some(backend) = route.backends.iter().find(|backend| {
+                    !backend
+                        .rsplit_once(':')
+                        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+                }) {
+                    anyhow::bail!("Invalid HTTP backend {:?}, expected host:port", backend);
+                }
+                if !route.path_prefix.starts_with('/') {
+                    anyhow::bail!("HTTP route path prefix {:?} must start with /", route.path_prefix);
+                }
+
+                let host = match route.host.as_deref().map(str::to_ascii_lowercase) {
+                    None => HostMatch::Any,
+                    Some(host) => match host.strip_prefix("*.") {
+                        Some(domain) => HostMatch::Suffix(format!(".{}", domain)),
+                        None => HostMatch::Exact(host),
+                    },
+                };
+
+                Ok(Route {
+                    host,
+                    path_prefix: route.path_prefix.clone(),
+                    backends: route.backends.clone(),
+                    next_backend: AtomicUsize::new(0),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { routes })
+    }
+
+    /// The route for a request: an exact host beats a wildcard, which beats any host,
+    /// then the longest path prefix wins
+    pub fn find(&self, host: &str, path: &str) -> Option<&Route> {
+        let host = host.to_ascii_lowercase();
+        self.routes
+            .iter()
+            .filter(|route| route.matches(&host, path))
+            .max_by(|a, b| (&a.host, a.path_prefix.len()).cmp(&(&b.host, b.path_prefix.len())))
+    }
+}
+
+impl Route {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let host_matches = match &self.host {
+            HostMatch::Any => true,
+            HostMatch::Suffix(suffix) => host.ends_with(suffix.as_str()),
+            HostMatch::Exact(exact) => host == exact,
+        };
+
+        let prefix = self.path_prefix.as_str();
+        host_matches
+            && path.starts_with(prefix)
+            && (prefix.ends_with('/') || path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    }
+
+    /// Backends starting with the next one in turn
+    pub fn backends(&self) -> impl Iterator<Item = &str> {
+        let start = self.next_backend.fetch_add(1, Ordering::Relaxed);
+        let len = self.backends.len();
+        (0..len).map(move |i| self.backends[(start + i) % len].as_str())
+    }
+}
+
+/// Host a request is for, without the port: the Host header, or the authority of an
+/// absolute URI
+pub fn request_host<B>(request: &hyper::Request<B>) -> Option<String> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().authority().map(|authority| authority.as_str()))?;
+
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Whether `Proxy-Authorization` carries the expected Basic credentials
+pub fn proxy_authorized(headers: &HeaderMap, auth: &ProxyAuth) -> bool {
+    let expected = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+    headers
+        .get(header::PROXY_AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .is_some_and(|credentials| constant_time_eq(credentials.trim().as_bytes(), expected.as_bytes()))
+}
+
+/// Drop hop-by-hop headers, including any the Connection header names
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
+
+/// Count a body's bytes towards `host` as they pass, holding frames back as long as the
+/// shaper says
+pub fn counted(body: Incoming, stats: Arc<StatsCollector>, host: String, direction: Direction, shaper: Shaper) -> HttpBody {
+    ShapedBody {
+        body,
+        stats,
+        host,
+        direction,
+        shaper,
+        delayed: None,
+    }
+    .boxed()
+}
+
+struct ShapedBody {
+    body: Incoming,
+    stats: Arc<StatsCollector>,
+    host: String,
+    direction: Direction,
+    shaper: Shaper,
+    /// A frame waiting out its delay
+    delayed: Option<(Frame<Bytes>, Pin<Box<Sleep>>)>,
+}
+
+impl Body for ShapedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+        if let Some((_, sleep)) = &mut this.delayed {
+            ready!(sleep.as_mut().poll(cx));
+            let (frame, _) = this.delayed.take().expect("delayed frame");
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        let frame = match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            other => return Poll::Ready(other),
+        };
+        let Some(data) = frame.data_ref() else {
+            return Poll::Ready(Some(Ok(frame)));
+        };
+
+        let len = data.len();
+        match this.direction {
+            Direction::Upload => {
+                this.stats.add_bytes_sent(len);
+                this.stats.add_host_bytes_sent(&this.host, len);
+            }
+            Direction::Download => {
+                this.stats.add_bytes_received(len);
+                this.stats.add_host_bytes_received(&this.host, len);
+            }
+        }
+
+        let delay = this.shaper.take(this.direction, len);
+        if delay.is_zero() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        let mut sleep = Box::pin(tokio::time::sleep(delay));
+        if sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        this.delayed = Some((frame, sleep));
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.delayed.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+pub fn empty_response(status: StatusCode) -> Response<HttpBody> {
+    let mut response = Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<HttpBody> {
+    let mut response = Response::new(Full::new(Bytes::from(format!("{}\n", message))).map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
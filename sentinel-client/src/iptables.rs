@@ -16,22 +16,36 @@ impl IptablesManager {
         }
     }
 
-    /// Process iptables task from server
-    pub async fn process_task(&self, task: &Task) -> Result<()> {
+    /// Process iptables task from server, returning the number of rules applied
+    pub async fn process_task(&self, task: &Task) -> Result<usize> {
         match task.task_type {
             TaskType::UpdateIptables => {
                 if let Ok(rules) = serde_json::from_value::<Vec<IptablesRule>>(task.payload.clone()) {
                     tracing::info!("Processing {} iptables rules from task {}", rules.len(), task.id);
 
+                    let total = rules.len();
+                    let mut failures = Vec::new();
                     for rule in rules {
                         if let Err(e) = self.apply_rule(&rule).await {
                             tracing::error!("Failed to apply iptables rule: {}", e);
                             // Continue with other rules even if one fails
+                            failures.push(e.to_string());
                         }
                     }
+
+                    if !failures.is_empty() {
+                        anyhow::bail!(
+                            "{} of {} iptables rules failed: {}",
+                            failures.len(),
+                            total,
+                            failures.join("; ")
+                        );
+                    }
+                    Ok(total)
                 } else if let Ok(rule) = serde_json::from_value::<IptablesRule>(task.payload.clone()) {
                     tracing::info!("Processing single iptables rule from task {}", task.id);
                     self.apply_rule(&rule).await?;
+                    Ok(1)
                 } else {
                     anyhow::bail!("Invalid iptables task payload format");
                 }
@@ -40,8 +54,6 @@ impl IptablesManager {
                 anyhow::bail!("Invalid task type for iptables manager: {:?}", task.task_type);
             }
         }
-
-        Ok(())
     }
 
     pub async fn apply_rule(&self, rule: &IptablesRule) -> Result<()> {
@@ -1,34 +1,232 @@
-use governor::{DefaultDirectRateLimiter, Quota, RateLimiter as GovernorRateLimiter};
-use std::num::NonZeroU32;
-use std::sync::Arc;
+use parking_lot::Mutex;
+use sentinel_common::Bandwidth;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
+/// Sources remembered before the limiters no connection holds any more are dropped
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the connecting client towards the target
+    Upload,
+    Download,
+}
+
+/// Token bucket counting bytes. A take never waits for the bucket to fill up to its size:
+/// it borrows against the bucket and the caller sleeps until the debt is paid off, so
+/// chunks of any size pass at exactly the rate.
+struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Bytes per second, 0 for no limit
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(mbps: u32, burst_kb: u32) -> Self {
+        let (rate, burst) = bucket_size(mbps, burst_kb);
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                burst,
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    fn set(&self, mbps: u32, burst_kb: u32) {
+        let (rate, burst) = bucket_size(mbps, burst_kb);
+        let mut state = self.state.lock();
+        state.refill(Instant::now());
+        // A bucket that had no limit starts out full
+        state.tokens = if state.rate == 0.0 { burst } else { state.tokens.min(burst) };
+        state.rate = rate;
+        state.burst = burst;
+    }
+
+    /// Take `bytes`, returning how long the caller has to wait before they are due
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock();
+        if state.rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        state.refill(Instant::now());
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / state.rate)
+        }
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+/// Bytes per second and bucket size in bytes for a cap in Mbps (10^6 bits/s) and a burst in KiB
+fn bucket_size(mbps: u32, burst_kb: u32) -> (f64, f64) {
+    let rate = mbps as f64 * 125_000.0;
+    let burst = match burst_kb {
+        0 => rate,
+        kb => kb as f64 * 1024.0,
+    };
+    (rate, burst)
+}
+
+/// Upload and download buckets for the caps of a `Bandwidth`
 pub struct RateLimiter {
-    limiter: Arc<DefaultDirectRateLimiter>,
+    upload: TokenBucket,
+    download: TokenBucket,
 }
 
 impl RateLimiter {
-    #[allow(dead_code)]
-    pub fn new(bytes_per_second: u32) -> Self {
-        let quota = Quota::per_second(NonZeroU32::new(bytes_per_second.max(1)).unwrap());
-        let limiter = Arc::new(GovernorRateLimiter::direct(quota));
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        Self {
+            upload: TokenBucket::new(bandwidth.upload_mbps, bandwidth.upload_burst_kb),
+            download: TokenBucket::new(bandwidth.download_mbps, bandwidth.download_burst_kb),
+        }
+    }
 
-        Self { limiter }
+    /// Change the caps, for connections already open too
+    pub fn set(&self, bandwidth: Bandwidth) {
+        self.upload.set(bandwidth.upload_mbps, bandwidth.upload_burst_kb);
+        self.download.set(bandwidth.download_mbps, bandwidth.download_burst_kb);
     }
 
-    pub async fn wait_for_capacity(&self, bytes: usize) {
-        let permits = (bytes / 1024).max(1) as u32;
-        for _ in 0..permits {
-            self.limiter.until_ready().await;
+    fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        match direction {
+            Direction::Upload => self.upload.take(bytes),
+            Direction::Download => self.download.take(bytes),
         }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn try_consume(&self, bytes: usize) -> bool {
-        let permits = (bytes / 1024).max(1) as u32;
-        if let Some(n) = NonZeroU32::new(permits) {
-            self.limiter.check_n(n).is_ok()
-        } else {
-            true
+/// Caps of a proxy or relay: one limiter all its connections share, and one per source IP
+pub struct Shaping {
+    shared: Arc<RateLimiter>,
+    source_bandwidth: Mutex<Bandwidth>,
+    sources: Mutex<HashMap<IpAddr, Weak<RateLimiter>>>,
+}
+
+impl Shaping {
+    pub fn new(bandwidth: Bandwidth, source_bandwidth: Bandwidth) -> Self {
+        Self {
+            shared: Arc::new(RateLimiter::new(bandwidth)),
+            source_bandwidth: Mutex::new(source_bandwidth),
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set(&self, bandwidth: Bandwidth, source_bandwidth: Bandwidth) {
+        self.shared.set(bandwidth);
+        *self.source_bandwidth.lock() = source_bandwidth;
+        for limiter in self.sources.lock().values().filter_map(Weak::upgrade) {
+            limiter.set(source_bandwidth);
+        }
+    }
+
+    /// The limiters a connection from `source` goes through
+    pub fn shaper(&self, source: IpAddr) -> Shaper {
+        let source = source.to_canonical();
+        let mut sources = self.sources.lock();
+        if sources.len() >= PRUNE_THRESHOLD {
+            sources.retain(|_, limiter| limiter.strong_count() > 0);
+        }
+
+        let limiter = match sources.get(&source).and_then(Weak::upgrade) {
+            Some(limiter) => limiter,
+            None => {
+                let limiter = Arc::new(RateLimiter::new(*self.source_bandwidth.lock()));
+                sources.insert(source, Arc::downgrade(&limiter));
+                limiter
+            }
+        };
+
+        Shaper {
+            limiters: vec![self.shared.clone(), limiter],
+        }
+    }
+}
+
+/// The limiters one connection's traffic passes through
+#[derive(Clone, Default)]
+pub struct Shaper {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Shaper {
+    /// Take `bytes` from every limiter, returning how long to hold them back
+    pub fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        self.limiters
+            .iter()
+            .map(|limiter| limiter.take(direction, bytes))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Wait until `bytes` may pass in `direction`
+    pub async fn wait(&self, direction: Direction, bytes: usize) {
+        let delay = self.take(direction, bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(delay: Duration) -> f64 {
+        (delay.as_secs_f64() * 100.0).round() / 100.0
+    }
+
+    #[test]
+    fn test_token_buckets() {
+        // 8 Mbps is 1 MB/s; the upload burst is 64 KiB, the download one a second's worth
+        let shaping = Shaping::new(
+            Bandwidth {
+                upload_mbps: 8,
+                download_mbps: 8,
+                upload_burst_kb: 64,
+                download_burst_kb: 0,
+            },
+            Bandwidth::default(),
+        );
+        let shaper = shaping.shaper("192.0.2.1".parse().unwrap());
+
+        assert_eq!(shaper.take(Direction::Upload, 64 * 1024), Duration::ZERO);
+        // Larger chunks than the bucket holds are let through late, not refused
+        assert_eq!(secs(shaper.take(Direction::Upload, 2_000_000)), 2.0);
+        assert_eq!(secs(shaper.take(Direction::Upload, 500_000)), 2.5);
+        assert_eq!(shaper.take(Direction::Download, 1_000_000), Duration::ZERO);
+        assert_eq!(secs(shaper.take(Direction::Download, 250_000)), 0.25);
+
+        // Per-source caps apply on top, to each source separately
+        shaping.set(Bandwidth::default(), Bandwidth::symmetric(8));
+        assert_eq!(shaper.take(Direction::Download, 1_000_000), Duration::ZERO);
+        assert_eq!(secs(shaper.take(Direction::Download, 1_000_000)), 1.0);
+        let other = shaping.shaper("::ffff:192.0.2.2".parse().unwrap());
+        assert_eq!(other.take(Direction::Download, 1_000_000), Duration::ZERO);
+
+        // Rates past 4 Gbps no longer overflow
+        let fast = RateLimiter::new(Bandwidth::symmetric(10_000));
+        assert_eq!(fast.take(Direction::Upload, 1_250_000_000), Duration::ZERO);
+        assert_eq!(secs(fast.take(Direction::Upload, 1_250_000_000)), 1.0);
+    }
+}
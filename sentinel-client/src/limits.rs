@@ -0,0 +1,175 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Sources remembered before those without open connections or a draining bucket are
+/// forgotten
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Connection caps; 0 leaves a limit off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LimitSettings {
+    pub max_connections: u32,
+    pub max_per_source: u32,
+    /// New connections per second from one source IP, in bursts of as many
+    pub accept_rate_per_source: u32,
+}
+
+/// Admission control for incoming connections: how many may be open in total and per
+/// source IP, and how fast each source may open them. Settings can change while
+/// connections are open; connections over a lowered cap keep running.
+pub struct ConnectionLimits {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    settings: LimitSettings,
+    active: u32,
+    sources: HashMap<IpAddr, Source>,
+}
+
+struct Source {
+    active: u32,
+    /// Token bucket for the accept rate
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    MaxConnections,
+    MaxPerSource,
+    AcceptRate,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::MaxConnections => "connection limit",
+            Self::MaxPerSource => "per-source connection limit",
+            Self::AcceptRate => "accept rate limit",
+        })
+    }
+}
+
+/// An admitted connection, counted until dropped
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    source: IpAddr,
+}
+
+impl ConnectionLimits {
+    pub fn new(settings: LimitSettings) -> Self {
+        Self {
+            state: Mutex::new(State { settings, ..Default::default() }),
+        }
+    }
+
+    pub fn set(&self, settings: LimitSettings) {
+        self.state.lock().settings = settings;
+    }
+
+    pub fn admit(self: &Arc<Self>, source: IpAddr) -> Result<ConnectionPermit, Refusal> {
+        let source = source.to_canonical();
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        let settings = state.settings;
+
+        if settings.max_connections > 0 && state.active >= settings.max_connections {
+            return Err(Refusal::MaxConnections);
+        }
+
+        let now = Instant::now();
+        let rate = settings.accept_rate_per_source as f64;
+        if state.sources.len() >= PRUNE_THRESHOLD {
+            state.sources.retain(|_, source| source.active > 0 || source.refill(now, rate) < rate);
+        }
+
+        let entry = state.sources.entry(source).or_insert_with(|| Source {
+            active: 0,
+            tokens: rate,
+            refilled: now,
+        });
+        if settings.max_per_source > 0 && entry.active >= settings.max_per_source {
+            return Err(Refusal::MaxPerSource);
+        }
+        if rate > 0.0 {
+            if entry.refill(now, rate) < 1.0 {
+                return Err(Refusal::AcceptRate);
+            }
+            entry.tokens -= 1.0;
+        }
+
+        entry.active += 1;
+        state.active += 1;
+        Ok(ConnectionPermit {
+            limits: self.clone(),
+            source,
+        })
+    }
+}
+
+impl Source {
+    fn refill(&mut self, now: Instant, rate: f64) -> f64 {
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+        self.tokens
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut guard = self.limits.state.lock();
+        let state = &mut *guard;
+        state.active = state.active.saturating_sub(1);
+
+        if let Some(source) = state.sources.get_mut(&self.source) {
+            source.active = source.active.saturating_sub(1);
+            // Without an accept rate there is nothing left to remember
+            if source.active == 0 && state.settings.accept_rate_per_source == 0 {
+                state.sources.remove(&self.source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = Arc::new(ConnectionLimits::new(LimitSettings {
+            max_connections: 3,
+            max_per_source: 2,
+            accept_rate_per_source: 0,
+        }));
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "::ffff:192.0.2.2".parse().unwrap();
+
+        let first = limits.admit(a).unwrap();
+        let _second = limits.admit(a).unwrap();
+        assert_eq!(limits.admit(a).err(), Some(Refusal::MaxPerSource));
+        let _third = limits.admit(b).unwrap();
+        assert_eq!(limits.admit("192.0.2.2".parse().unwrap()).err(), Some(Refusal::MaxConnections));
+
+        drop(first);
+        assert!(limits.admit(a).is_ok());
+
+        limits.set(LimitSettings {
+            accept_rate_per_source: 20,
+            ..Default::default()
+        });
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+        let burst: Vec<_> = (0..20).map(|_| limits.admit(c).unwrap()).collect();
+        assert_eq!(limits.admit(c).err(), Some(Refusal::AcceptRate));
+        drop(burst);
+        assert_eq!(limits.admit(c).err(), Some(Refusal::AcceptRate));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limits.admit(c).is_ok());
+    }
+}
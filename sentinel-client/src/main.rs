@@ -1,27 +1,38 @@
 mod config;
+mod executor;
 mod register;
 mod monitor;
 mod proxy;
 mod iptables;
 mod stats;
 mod limiter;
+mod limits;
 mod relay;
+mod exits;
+mod destinations;
+mod socks;
+mod http_proxy;
 mod encryption;
 mod websocket;
+mod mux;
+mod settings;
 
 use anyhow::Result;
 use clap::Parser;
 use std::sync::Arc;
-use tracing_subscriber;
 
 use crate::config::Config;
+use crate::encryption::{EncryptionManager, IdentityKey};
+use crate::executor::TaskExecutor;
 use crate::monitor::{MetricsReporter, get_system_info};
-use crate::proxy::ProxyServer;
+use crate::proxy::ProxyRegistry;
 use crate::register::RegistrationManager;
 use crate::relay::RelayManager;
+use crate::settings::{log_filter, SettingsManager};
 use crate::iptables::IptablesManager;
-use sentinel_common::{RelayConfig, TaskType};
 use sentinel_common::ClientInfo;
+use tokio::sync::mpsc;
+use tracing_subscriber::prelude::*;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -32,18 +43,23 @@ struct Args {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "sentinel=info".into()),
-        )
-        .init();
-
     let args = Args::parse();
     let config = Config::from_file(&args.config)?;
 
+    // The filter sits behind a reload layer so `logging.level` can be changed at runtime
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| log_filter(&config.logging.level));
+    let (filter, log_reload) = tracing_subscriber::reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
     tracing::info!("Starting Sentinel Client v{}", env!("CARGO_PKG_VERSION"));
 
+    // Both rustls backends get compiled in through our dependencies, so pick one for wss:// and TLS
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
     let system_info = get_system_info();
     tracing::info!(
         "System: {} {}, CPU: {} cores, Memory: {} GB",
@@ -53,16 +69,20 @@ async fn main() -> Result<()> {
         system_info.total_memory / (1024 * 1024 * 1024)
     );
 
+    // An empty id lets the server assign one, which is then remembered in the state file
     let client_info = ClientInfo {
-        id: config.client.id.clone().unwrap_or_else(|| {
-            uuid::Uuid::new_v4().to_string()
-        }),
-        hostname: config.client.hostname.clone().unwrap_or_else(|| {
-            hostname::get()
-                .ok()
-                .and_then(|h| h.to_str().map(String::from))
-                .unwrap_or_else(|| "unknown".to_string())
-        }),
+        id: config.client.id.clone().unwrap_or_default(),
+        hostname: config
+            .client
+            .hostname
+            .clone()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| {
+                hostname::get()
+                    .ok()
+                    .and_then(|h| h.to_str().map(String::from))
+                    .unwrap_or_else(|| "unknown".to_string())
+            }),
         ip: get_local_ip()?,
         version: env!("CARGO_PKG_VERSION").to_string(),
         capabilities: vec![
@@ -74,10 +94,31 @@ async fn main() -> Result<()> {
         system_info,
     };
 
+    let proxy_registry = Arc::new(ProxyRegistry::new(config.limits.connection_limits()));
+    for proxy in config.proxy_configs()? {
+        let name = proxy.name.clone();
+        proxy_registry
+            .start(proxy)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to start proxy {}: {}", name, e))?;
+    }
+
+    let (task_tx, mut task_rx) = mpsc::unbounded_channel();
+
+    // State and identity files live next to the config file unless given absolute paths
+    let config_dir = std::path::Path::new(&args.config)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."));
+    let identity = Arc::new(IdentityKey::load_or_generate(&config_dir.join(&config.client.identity_file))?);
+
     let registration = Arc::new(RegistrationManager::new(
         client_info,
+        identity.clone(),
         config.server.url.clone(),
         std::time::Duration::from_secs(config.server.heartbeat_interval),
+        config_dir.join(&config.client.state_file),
+        task_tx,
+        proxy_registry.clone(),
     ));
 
     let reg_handle = {
@@ -85,71 +126,56 @@ async fn main() -> Result<()> {
         tokio::spawn(async move { registration.start().await })
     };
 
-    let monitor_handle = if config.monitoring.enabled {
-        let reporter = MetricsReporter::new(
+    let reporter = config.monitoring.enabled.then(|| {
+        Arc::new(MetricsReporter::new(
             config.server.url.clone(),
             std::time::Duration::from_secs(config.monitoring.report_interval),
-        );
-
-        Some(tokio::spawn(async move { reporter.start().await }))
-    } else {
-        None
-    };
+        ))
+    });
 
-    let proxy = ProxyServer::new(
-        config.proxy.listen_addr.parse()?,
-        config.proxy.target_addr.parse()?,
-    );
+    let monitor_handle = reporter.clone().map(|reporter| {
+        tokio::spawn(async move { reporter.start().await })
+    });
 
-    let relay_manager = Arc::new(RelayManager::new(config.server.url.clone())?);
+    let relay_manager = Arc::new(RelayManager::new(
+        EncryptionManager::new(identity, config.relay_key()?, &config.tls_config(config_dir)?)?,
+        std::time::Duration::from_secs(config.transport.stop_grace_period),
+    ));
     let iptables_manager = Arc::new(IptablesManager::new());
+    let settings = Arc::new(SettingsManager::new(
+        &args.config,
+        config.clone(),
+        registration.clone(),
+        reporter,
+        proxy_registry.clone(),
+        log_reload,
+    ));
 
-    let proxy_handle = tokio::spawn(async move { proxy.start().await });
+    // Receive tasks as the server queues them
+    let stream_handle = {
+        let registration = registration.clone();
+        tokio::spawn(async move { registration.stream_tasks().await })
+    };
 
-    // Start task manager to handle all server tasks
+    // Run tasks one at a time, in the order the server queued them, and report each outcome
     let task_handle = {
-        let relay_manager = relay_manager.clone();
-        let iptables_manager = iptables_manager.clone();
-        let registration_clone = registration.clone();
+        let executor = TaskExecutor::new(
+            relay_manager.clone(),
+            iptables_manager.clone(),
+            proxy_registry.clone(),
+            settings,
+        );
+        let registration = registration.clone();
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
-
-            loop {
-                interval.tick().await;
-
-                // Get pending tasks from server
-                if let Ok(tasks) = registration_clone.get_pending_tasks().await {
-                    for task in tasks {
-                        match task.task_type {
-                            TaskType::StartRelay => {
-                                if let Ok(config) = serde_json::from_value::<RelayConfig>(task.payload) {
-                                    if let Err(e) = relay_manager.start_relay(config).await {
-                                        tracing::error!("Failed to start relay: {}", e);
-                                    }
-                                }
-                            }
-                            TaskType::StopRelay => {
-                                if let Ok(config) = serde_json::from_value::<RelayConfig>(task.payload) {
-                                    if let Err(e) = relay_manager.stop_relay(&config.entry_point, &config.exit_point).await {
-                                        tracing::error!("Failed to stop relay: {}", e);
-                                    }
-                                }
-                            }
-                            TaskType::UpdateIptables => {
-                                if let Err(e) = iptables_manager.process_task(&task).await {
-                                    tracing::error!("Failed to process iptables task {}: {}", task.id, e);
-                                }
-                            }
-                            TaskType::ConfigureProxy => {
-                                tracing::info!("Proxy configuration task received, task ID: {}", task.id);
-                                // TODO: Implement proxy reconfiguration
-                            }
-                            TaskType::UpdateConfig => {
-                                tracing::info!("Configuration update task received, task ID: {}", task.id);
-                                // TODO: Implement config update
-                            }
-                        }
-                    }
+            while let Some(task) = task_rx.recv().await {
+                if let Err(e) = registration.report_running(&task.id).await {
+                    tracing::warn!("Failed to report task {} as running: {}", task.id, e);
+                }
+
+                let result = executor.run(&task).await;
+
+                if let Err(e) = registration.report_result(result).await {
+                    tracing::error!("Failed to report result of task {}: {}", task.id, e);
                 }
             }
         })
@@ -159,8 +185,8 @@ async fn main() -> Result<()> {
         r = reg_handle => {
             tracing::error!("Registration manager stopped: {:?}", r);
         }
-        r = proxy_handle => {
-            tracing::error!("Proxy server stopped: {:?}", r);
+        r = stream_handle => {
+            tracing::error!("Task channel stopped: {:?}", r);
         }
         r = task_handle => {
             tracing::error!("Task manager stopped: {:?}", r);
@@ -190,9 +216,4 @@ fn get_local_ip() -> Result<String> {
         IpAddr::V4(ip) => Ok(ip.to_string()),
         IpAddr::V6(ip) => Ok(ip.to_string()),
     }
-}
-
-#[allow(dead_code)]
-extern crate hostname;
-#[allow(dead_code)]
-extern crate uuid;
\ No newline at end of file
+}
\ No newline at end of file
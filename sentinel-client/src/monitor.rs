@@ -1,6 +1,7 @@
 use anyhow::Result;
 use sentinel_common::{SystemInfo, SystemMetrics};
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use sysinfo::{System, Networks, Disks};
@@ -167,7 +168,7 @@ pub struct MetricsReporter {
     monitor: Arc<SystemMonitor>,
     #[allow(dead_code)]
     server_url: String,
-    interval: Duration,
+    interval_secs: AtomicU64,
 }
 
 impl MetricsReporter {
@@ -175,15 +176,18 @@ impl MetricsReporter {
         Self {
             monitor: Arc::new(SystemMonitor::new()),
             server_url,
-            interval,
+            interval_secs: AtomicU64::new(interval.as_secs().max(1)),
         }
     }
 
-    pub async fn start(&self) -> Result<()> {
-        let mut ticker = tokio::time::interval(self.interval);
+    /// Change the reporting interval; takes effect after the current wait
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval_secs.store(interval.as_secs().max(1), Ordering::Relaxed);
+    }
 
+    pub async fn start(&self) -> Result<()> {
         loop {
-            ticker.tick().await;
+            tokio::time::sleep(Duration::from_secs(self.interval_secs.load(Ordering::Relaxed))).await;
 
             match SystemMonitor::collect_metrics().await {
                 Ok(metrics) => {
@@ -0,0 +1,579 @@
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use crate::encryption::StreamRole;
+
+/// Frame header: type (1 byte), stream id (4 bytes), length or value (4 bytes)
+const HEADER_LEN: usize = 9;
+/// Largest data frame; bigger writes are split across frames
+const MAX_FRAME_LEN: usize = 32 * 1024;
+/// Bytes a stream may have in flight before the reading end grants more
+const INITIAL_WINDOW: u32 = 256 * 1024;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// The tunnel is given up on when nothing, not even a ping, arrives for this long
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Any byte stream a tunnel transport hands back
+pub trait TunnelIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunnelIo for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameType {
+    Open,
+    Data,
+    /// Grants the sender `value` more bytes on the stream
+    WindowUpdate,
+    /// No more data from the sender on this stream
+    Fin,
+    Reset,
+    Ping,
+    Pong,
+}
+
+impl FrameType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Open,
+            1 => Self::Data,
+            2 => Self::WindowUpdate,
+            3 => Self::Fin,
+            4 => Self::Reset,
+            5 => Self::Ping,
+            6 => Self::Pong,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: FrameType,
+    stream_id: u32,
+    /// Payload length for data frames, otherwise the window delta or ping nonce
+    value: u32,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn control(kind: FrameType, stream_id: u32, value: u32) -> Self {
+        Self { kind, stream_id, value, payload: Bytes::new() }
+    }
+
+    fn data(stream_id: u32, payload: Bytes) -> Self {
+        Self { kind: FrameType::Data, stream_id, value: payload.len() as u32, payload }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let kind = FrameType::from_u8(header[0])
+        .ok_or_else(|| anyhow::anyhow!("Unknown multiplexer frame type {}", header[0]))?;
+    let stream_id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+    let value = u32::from_be_bytes(header[5..9].try_into().unwrap());
+
+    let payload = if kind == FrameType::Data {
+        if value as usize > MAX_FRAME_LEN {
+            anyhow::bail!("Multiplexer data frame of {} bytes exceeds {}", value, MAX_FRAME_LEN);
+        }
+        let mut payload = vec![0u8; value as usize];
+        reader.read_exact(&mut payload).await?;
+        Bytes::from(payload)
+    } else {
+        Bytes::new()
+    };
+
+    Ok(Some(Frame { kind, stream_id, value, payload }))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = frame.kind as u8;
+    header[1..5].copy_from_slice(&frame.stream_id.to_be_bytes());
+    header[5..9].copy_from_slice(&frame.value.to_be_bytes());
+
+    writer.write_all(&header).await?;
+    writer.write_all(&frame.payload).await
+}
+
+/// Send side of a stream, shared with the session reader that applies window updates
+#[derive(Default)]
+struct SendState {
+    credit: u32,
+    waker: Option<Waker>,
+    error: Option<io::ErrorKind>,
+}
+
+struct StreamShared {
+    send: Mutex<SendState>,
+    /// Bytes the peer may still send before we grant more
+    recv_window: AtomicU32,
+}
+
+struct StreamEntry {
+    shared: Arc<StreamShared>,
+    /// Dropped when the peer sends FIN, which the stream reads as EOF
+    incoming: Option<mpsc::UnboundedSender<io::Result<Bytes>>>,
+}
+
+impl StreamEntry {
+    fn fail(self, kind: io::ErrorKind) {
+        if let Some(incoming) = self.incoming {
+            let _ = incoming.send(Err(kind.into()));
+        }
+
+        let mut send = self.shared.send.lock().unwrap();
+        send.error = Some(kind);
+        if let Some(waker) = send.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    frames: mpsc::UnboundedSender<Frame>,
+    closed: AtomicBool,
+    /// Cancelled along with `closed`, for whoever waits on the tunnel going down
+    done: CancellationToken,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) -> bool {
+        self.frames.send(frame).is_ok()
+    }
+
+    /// Track a new stream, or `None` if the id is already in use
+    fn register(self: &Arc<Self>, id: u32) -> Option<MuxStream> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(StreamShared {
+            send: Mutex::new(SendState { credit: INITIAL_WINDOW, ..Default::default() }),
+            recv_window: AtomicU32::new(INITIAL_WINDOW),
+        });
+
+        let mut streams = self.streams.lock().unwrap();
+        if self.closed.load(Ordering::Acquire) || streams.contains_key(&id) {
+            return None;
+        }
+        streams.insert(id, StreamEntry { shared: shared.clone(), incoming: Some(incoming_tx) });
+
+        Some(MuxStream {
+            id,
+            session: self.clone(),
+            state: shared,
+            incoming: incoming_rx,
+            buffer: Bytes::new(),
+            unacked: 0,
+            read_done: false,
+            write_done: false,
+        })
+    }
+
+    fn deliver(&self, id: u32, payload: Bytes) {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(entry) = streams.get(&id) else {
+            // Already dropped on our side, which sent the peer a reset
+            return;
+        };
+
+        let len = payload.len() as u32;
+        let within_window = entry
+            .shared
+            .recv_window
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |window| window.checked_sub(len))
+            .is_ok();
+
+        match &entry.incoming {
+            Some(incoming) if within_window => {
+                let _ = incoming.send(Ok(payload));
+            }
+            _ => {
+                tracing::debug!("Resetting multiplexed stream {}: data past its window or FIN", id);
+                if let Some(entry) = streams.remove(&id) {
+                    entry.fail(io::ErrorKind::InvalidData);
+                }
+                self.send(Frame::control(FrameType::Reset, id, 0));
+            }
+        }
+    }
+
+    fn grant(&self, id: u32, delta: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().get(&id) {
+            let mut send = entry.shared.send.lock().unwrap();
+            send.credit = send.credit.saturating_add(delta);
+            if let Some(waker) = send.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn finish(&self, id: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().get_mut(&id) {
+            entry.incoming = None;
+        }
+    }
+
+    fn reset(&self, id: u32) {
+        let entry = self.streams.lock().unwrap().remove(&id);
+        if let Some(entry) = entry {
+            entry.fail(io::ErrorKind::ConnectionReset);
+        }
+    }
+
+    /// Fail every open stream; the session can't be used afterwards
+    fn shutdown(&self) {
+        let streams: Vec<_> = {
+            let mut streams = self.streams.lock().unwrap();
+            self.closed.store(true, Ordering::Release);
+            streams.drain().map(|(_, entry)| entry).collect()
+        };
+        self.done.cancel();
+
+        for entry in streams {
+            entry.fail(io::ErrorKind::ConnectionAborted);
+        }
+    }
+}
+
+/// Many logical streams over one tunnel connection, each with its own flow-control window.
+/// The initiator opens odd stream ids and the responder even ones.
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+    accepted: mpsc::UnboundedReceiver<MuxStream>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MuxSession {
+    pub fn new<S>(io: S, role: StreamRole) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(io);
+        let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+        let (accepted_tx, accepted_rx) = mpsc::unbounded_channel();
+
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            frames: frames_tx,
+            closed: AtomicBool::new(false),
+            done: CancellationToken::new(),
+        });
+
+        let read_task = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                if let Err(e) = read_frames(reader, &shared, accepted_tx).await {
+                    tracing::debug!("Multiplexed tunnel read failed: {}", e);
+                }
+                shared.shutdown();
+            }
+        });
+
+        let write_task = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                if let Err(e) = write_frames(writer, frames_rx).await {
+                    tracing::debug!("Multiplexed tunnel write failed: {}", e);
+                }
+                shared.shutdown();
+            }
+        });
+
+        let first_id = match role {
+            StreamRole::Initiator => 1,
+            StreamRole::Responder => 2,
+        };
+
+        Self {
+            shared,
+            next_id: AtomicU32::new(first_id),
+            accepted: accepted_rx,
+            tasks: vec![read_task, write_task],
+        }
+    }
+
+    pub fn open_stream(&self) -> Result<MuxStream> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self
+            .shared
+            .register(id)
+            .ok_or_else(|| anyhow::anyhow!("Multiplexed tunnel is closed"))?;
+
+        if !self.shared.send(Frame::control(FrameType::Open, id, 0)) {
+            anyhow::bail!("Multiplexed tunnel is closed");
+        }
+
+        Ok(stream)
+    }
+
+    /// Next stream opened by the peer, or `None` once the tunnel is gone
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.accepted.recv().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the tunnel is gone, whether it dropped or missed its keepalives
+    pub fn closed(&self) -> WaitForCancellationFutureOwned {
+        self.shared.done.clone().cancelled_owned()
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.shared.shutdown();
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    shared: &Arc<Shared>,
+    accepted: mpsc::UnboundedSender<MuxStream>,
+) -> Result<()> {
+    loop {
+        let frame = match tokio::time::timeout(KEEPALIVE_TIMEOUT, read_frame(&mut reader)).await {
+            Ok(frame) => frame?,
+            Err(_) => anyhow::bail!("No keepalive from the peer in {:?}", KEEPALIVE_TIMEOUT),
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+
+        match frame.kind {
+            FrameType::Open => {
+                let stream = shared
+                    .register(frame.stream_id)
+                    .ok_or_else(|| anyhow::anyhow!("Peer reopened multiplexed stream {}", frame.stream_id))?;
+                // Nobody accepting drops the stream, which resets it
+                let _ = accepted.send(stream);
+            }
+            FrameType::Data => shared.deliver(frame.stream_id, frame.payload),
+            FrameType::WindowUpdate => shared.grant(frame.stream_id, frame.value),
+            FrameType::Fin => shared.finish(frame.stream_id),
+            FrameType::Reset => shared.reset(frame.stream_id),
+            FrameType::Ping => {
+                shared.send(Frame::control(FrameType::Pong, 0, frame.value));
+            }
+            FrameType::Pong => {}
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(writer: W, mut frames: mpsc::UnboundedReceiver<Frame>) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+    keepalive.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            _ = keepalive.tick() => Frame::control(FrameType::Ping, 0, 0),
+        };
+
+        write_frame(&mut writer, &frame).await?;
+        // Batch whatever else is queued into the same flush
+        while let Ok(frame) = frames.try_recv() {
+            write_frame(&mut writer, &frame).await?;
+        }
+        writer.flush().await?;
+    }
+
+    writer.shutdown().await
+}
+
+/// One logical connection inside a `MuxSession`
+pub struct MuxStream {
+    id: u32,
+    session: Arc<Shared>,
+    state: Arc<StreamShared>,
+    incoming: mpsc::UnboundedReceiver<io::Result<Bytes>>,
+    buffer: Bytes,
+    /// Bytes read since the peer was last granted more window
+    unacked: u32,
+    read_done: bool,
+    write_done: bool,
+}
+
+impl MuxStream {
+    fn consumed(&mut self, n: usize) {
+        self.unacked += n as u32;
+        if self.unacked >= INITIAL_WINDOW / 2 {
+            self.state.recv_window.fetch_add(self.unacked, Ordering::AcqRel);
+            self.session.send(Frame::control(FrameType::WindowUpdate, self.id, self.unacked));
+            self.unacked = 0;
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if !self.buffer.is_empty() {
+                let n = buf.remaining().min(self.buffer.len());
+                let chunk = self.buffer.split_to(n);
+                buf.put_slice(&chunk);
+                self.consumed(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.read_done {
+                return Poll::Ready(Ok(()));
+            }
+
+            match std::task::ready!(self.incoming.poll_recv(cx)) {
+                Some(Ok(data)) => self.buffer = data,
+                Some(Err(e)) => {
+                    self.read_done = true;
+                    return Poll::Ready(Err(e));
+                }
+                None => self.read_done = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.write_done {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = {
+            let mut send = self.state.send.lock().unwrap();
+            if let Some(kind) = send.error {
+                return Poll::Ready(Err(kind.into()));
+            }
+            if send.credit == 0 {
+                send.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let n = buf.len().min(send.credit as usize).min(MAX_FRAME_LEN);
+            send.credit -= n as u32;
+            n
+        };
+
+        if !self.session.send(Frame::data(self.id, Bytes::copy_from_slice(&buf[..n]))) {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // The session writer flushes whenever its queue drains
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_done {
+            self.write_done = true;
+            self.session.send(Frame::control(FrameType::Fin, self.id, 0));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let tracked = self.session.streams.lock().unwrap().remove(&self.id).is_some();
+        // A stream dropped before both sides finished tells the peer to give up on it
+        if tracked && !(self.read_done && self.write_done) {
+            self.session.send(Frame::control(FrameType::Reset, self.id, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_streams_with_flow_control() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let client = MuxSession::new(a, StreamRole::Initiator);
+        let mut server = MuxSession::new(b, StreamRole::Responder);
+
+        // Echo every stream back to its opener
+        tokio::spawn(async move {
+            while let Some(stream) = server.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        // Each stream carries several windows' worth, so the echo stalls without updates
+        let mut tasks = Vec::new();
+        for seed in 0..8u8 {
+            let stream = client.open_stream().unwrap();
+            tasks.push(tokio::spawn(async move {
+                let data = pattern(INITIAL_WINDOW as usize * 3 + 17, seed);
+                let (mut reader, mut writer) = tokio::io::split(stream);
+
+                let expected = data.clone();
+                let send = tokio::spawn(async move {
+                    writer.write_all(&data).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                });
+
+                let mut received = Vec::new();
+                reader.read_to_end(&mut received).await.unwrap();
+                send.await.unwrap();
+                assert_eq!(received, expected);
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_fail_when_tunnel_drops() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let client = MuxSession::new(a, StreamRole::Initiator);
+        let mut stream = client.open_stream().unwrap();
+        drop(b);
+
+        let mut buf = [0u8; 16];
+        assert!(stream.read(&mut buf).await.is_err());
+        tokio::time::timeout(Duration::from_secs(1), client.closed()).await.unwrap();
+        assert!(client.is_closed());
+        assert!(client.open_stream().is_err());
+    }
+}
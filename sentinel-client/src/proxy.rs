@@ -1,51 +1,234 @@
 use anyhow::Result;
-use std::net::SocketAddr;
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderValue};
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use sentinel_common::{Bandwidth, HostTraffic, ProxyAuth, ProxyConfig, ProxyMode, ProxyStatus};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
 
-use crate::limiter::RateLimiter;
+use crate::destinations::DestinationFilter;
+use crate::http_proxy::{self, HttpBody, RouteTable};
+use crate::limiter::{Direction, Shaper, Shaping};
+use crate::limits::{ConnectionLimits, ConnectionPermit, LimitSettings, Refusal};
+use crate::socks::{self, Destination};
 use crate::stats::StatsCollector;
 
+/// How long a UDP session lives without traffic when the proxy doesn't set `idle_timeout`
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Datagrams waiting for a UDP session's socket; more are dropped, as the network would
+const UDP_SESSION_QUEUE: usize = 256;
+const MAX_DATAGRAM: usize = 65535;
+/// Connection attempts to destinations SOCKS5 and HTTP `CONNECT` clients ask for, and to
+/// HTTP backends
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an HTTP proxy client gets to send a request's headers
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a SOCKS5 client gets to finish its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct ProxyServer {
     listen_addr: SocketAddr,
-    target_addr: SocketAddr,
+    /// None for SOCKS5 and HTTP, whose clients name their own destinations
+    target_addr: Option<SocketAddr>,
+    mode: ProxyMode,
+    udp_idle_timeout: Duration,
+    auth: Option<ProxyAuth>,
+    destinations: DestinationFilter,
+    routes: RouteTable,
     stats: Arc<StatsCollector>,
-    limiter: Option<Arc<RateLimiter>>,
+    shaping: Shaping,
+    limits: Arc<ConnectionLimits>,
+    /// Client-wide limits shared with the other proxies
+    global_limits: Option<Arc<ConnectionLimits>>,
+}
+
+/// Admission of one connection under a proxy's own and the client-wide limits
+type Permits = (ConnectionPermit, Option<ConnectionPermit>);
+
+/// A bound proxy, waiting for `ProxyServer::serve`
+pub enum ProxyListener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl ProxyListener {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Udp(socket) => socket.local_addr(),
+        }
+    }
 }
 
 impl ProxyServer {
     pub fn new(listen_addr: SocketAddr, target_addr: SocketAddr) -> Self {
+        Self::with_target(listen_addr, Some(target_addr), ProxyMode::Tcp)
+    }
+
+    pub fn socks5(listen_addr: SocketAddr) -> Self {
+        Self::with_target(listen_addr, None, ProxyMode::Socks5)
+    }
+
+    pub fn http(listen_addr: SocketAddr) -> Self {
+        Self::with_target(listen_addr, None, ProxyMode::Http)
+    }
+
+    fn with_target(listen_addr: SocketAddr, target_addr: Option<SocketAddr>, mode: ProxyMode) -> Self {
         Self {
             listen_addr,
             target_addr,
+            mode,
+            udp_idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            auth: None,
+            destinations: DestinationFilter::default(),
+            routes: RouteTable::default(),
             stats: Arc::new(StatsCollector::new()),
-            limiter: None,
+            shaping: Shaping::new(Bandwidth::default(), Bandwidth::default()),
+            limits: Arc::new(ConnectionLimits::new(LimitSettings::default())),
+            global_limits: None,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn with_rate_limit(mut self, mbps: u32) -> Self {
-        if mbps > 0 {
-            self.limiter = Some(Arc::new(RateLimiter::new(mbps * 1024 * 1024 / 8)));
+    pub fn from_config(config: &ProxyConfig) -> Result<Self> {
+        let listen_addr = config.listen_addr.parse()?;
+        let mut server = match config.mode {
+            ProxyMode::Socks5 => Self::socks5(listen_addr)
+                .with_auth(config.auth.clone())
+                .with_destinations(DestinationFilter::new(&config.allow_destinations, &config.deny_destinations)?),
+            ProxyMode::Http => Self::http(listen_addr)
+                .with_auth(config.auth.clone())
+                .with_destinations(DestinationFilter::new(&config.allow_destinations, &config.deny_destinations)?)
+                .with_routes(RouteTable::new(&config.routes)?),
+            mode => Self::new(listen_addr, config.target_addr.parse()?).with_mode(mode),
+        };
+        server = server.with_bandwidth(proxy_bandwidth(config), config.source_bandwidth.unwrap_or_default());
+        server = server.with_limits(LimitSettings {
+            max_connections: config.max_connections.unwrap_or(0),
+            max_per_source: config.max_connections_per_source.unwrap_or(0),
+            accept_rate_per_source: config.accept_rate_per_source.unwrap_or(0),
+        });
+        if let Some(secs) = config.idle_timeout {
+            server = server.with_idle_timeout(Duration::from_secs(secs));
         }
+        Ok(server)
+    }
+
+    pub fn with_mode(mut self, mode: ProxyMode) -> Self {
+        self.mode = mode;
         self
     }
 
-    pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.listen_addr).await?;
-        tracing::info!("Proxy listening on {}", listener.local_addr()?);
+    pub fn with_auth(mut self, auth: Option<ProxyAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_destinations(mut self, destinations: DestinationFilter) -> Self {
+        self.destinations = destinations;
+        self
+    }
+
+    pub fn with_routes(mut self, routes: RouteTable) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        if !timeout.is_zero() {
+            self.udp_idle_timeout = timeout;
+        }
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: Bandwidth, source_bandwidth: Bandwidth) -> Self {
+        self.shaping = Shaping::new(bandwidth, source_bandwidth);
+        self
+    }
+
+    pub fn with_limits(mut self, settings: LimitSettings) -> Self {
+        self.limits = Arc::new(ConnectionLimits::new(settings));
+        self
+    }
 
+    pub fn with_global_limits(mut self, limits: Arc<ConnectionLimits>) -> Self {
+        self.global_limits = Some(limits);
+        self
+    }
+
+    /// Admit a connection or UDP session from `peer_addr`, counting it as refused if
+    /// any limit is reached
+    fn admit(&self, peer_addr: SocketAddr) -> Result<Permits, Refusal> {
+        let admitted = self
+            .global_limits
+            .as_ref()
+            .map(|limits| limits.admit(peer_addr.ip()))
+            .transpose()
+            .and_then(|global| Ok((self.limits.admit(peer_addr.ip())?, global)));
+        if admitted.is_err() {
+            self.stats.refuse_connection();
+        }
+        admitted
+    }
+
+    pub async fn bind(&self) -> Result<ProxyListener> {
+        let listener = match self.mode {
+            ProxyMode::Tcp | ProxyMode::Socks5 | ProxyMode::Http => ProxyListener::Tcp(TcpListener::bind(self.listen_addr).await?),
+            ProxyMode::Udp => ProxyListener::Udp(UdpSocket::bind(self.listen_addr).await?),
+        };
+        match self.target_addr {
+            Some(target) => {
+                tracing::info!("Proxy listening on {} -> {} ({:?})", listener.local_addr()?, target, self.mode)
+            }
+            None => tracing::info!("Proxy listening on {} ({:?})", listener.local_addr()?, self.mode),
+        }
+        Ok(listener)
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: ProxyListener) -> Result<()> {
+        match listener {
+            ProxyListener::Tcp(listener) => self.serve_tcp(listener).await,
+            ProxyListener::Udp(socket) => self.serve_udp(socket).await,
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
         loop {
             let (inbound, peer_addr) = listener.accept().await?;
-            let target = self.target_addr;
-            let stats = self.stats.clone();
-            let limiter = self.limiter.clone();
+
+            // Refusals are only logged at debug level, a flood would drown the log otherwise
+            let permits = match self.admit(peer_addr) {
+                Ok(permits) => permits,
+                Err(refusal) => {
+                    tracing::debug!("Proxy {} at {}, rejecting {}", self.listen_addr, refusal, peer_addr);
+                    continue;
+                }
+            };
+
+            let server = self.clone();
 
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(inbound, target, stats, limiter).await {
+                let shaper = server.shaping.shaper(peer_addr.ip());
+                let result = match (server.mode, server.target_addr) {
+                    (ProxyMode::Socks5, _) => server.handle_socks5(inbound, shaper).await,
+                    (ProxyMode::Http, _) => server.clone().handle_http(inbound, peer_addr, shaper).await,
+                    (_, Some(target)) => Self::handle_connection(inbound, target, server.stats.clone(), shaper).await,
+                    (_, None) => Err(anyhow::anyhow!("Proxy has no target address")),
+                };
+                if let Err(e) = result {
                     tracing::error!("Connection error from {}: {}", peer_addr, e);
                 }
+                drop(permits);
             });
         }
     }
@@ -54,22 +237,43 @@ impl ProxyServer {
         inbound: TcpStream,
         target: SocketAddr,
         stats: Arc<StatsCollector>,
-        limiter: Option<Arc<RateLimiter>>,
+        shaper: Shaper,
     ) -> Result<()> {
         let outbound = TcpStream::connect(target).await?;
+        Self::forward(inbound, outbound, stats, shaper).await
+    }
 
+    /// Forward a client connection, tracking it in the stats while it lasts
+    async fn forward(
+        inbound: TcpStream,
+        outbound: TcpStream,
+        stats: Arc<StatsCollector>,
+        shaper: Shaper,
+    ) -> Result<()> {
         let peer_addr = inbound.peer_addr()?.to_string();
-        stats.new_connection(peer_addr);
+        stats.new_connection(peer_addr.clone());
+        let result = Self::pipe(inbound, outbound, None, stats.clone(), shaper).await;
+        stats.close_connection(&peer_addr);
+        result
+    }
 
-        let (mut ri, mut wi) = inbound.into_split();
+    /// Copy both ways until both sides have closed, counting and shaping the traffic,
+    /// counting it towards `host` too if given. A half-closed connection stays open, and
+    /// counted against the limits, until the other side is done as well.
+    async fn pipe<S>(
+        inbound: S,
+        outbound: TcpStream,
+        host: Option<String>,
+        stats: Arc<StatsCollector>,
+        shaper: Shaper,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut ri, mut wi) = tokio::io::split(inbound);
         let (mut ro, mut wo) = outbound.into_split();
 
-        let stats1 = stats.clone();
-        let stats2 = stats.clone();
-        let limiter1 = limiter.clone();
-        let limiter2 = limiter.clone();
-
-        let client_to_server = tokio::spawn(async move {
+        let client_to_server = async {
             let mut buf = vec![0u8; 8192];
             loop {
                 let n = match ri.read(&mut buf).await {
@@ -81,21 +285,23 @@ impl ProxyServer {
                     }
                 };
 
-                if let Some(limiter) = &limiter1 {
-                    limiter.wait_for_capacity(n).await;
-                }
+                shaper.wait(Direction::Upload, n).await;
 
                 if let Err(e) = wo.write_all(&buf[..n]).await {
                     tracing::error!("Write error: {}", e);
                     break;
                 }
 
-                stats1.add_bytes_sent(n);
+                stats.add_bytes_sent(n);
+                if let Some(host) = &host {
+                    stats.add_host_bytes_sent(host, n);
+                }
             }
-            Ok::<_, anyhow::Error>(())
-        });
+            // Dropping a split stream's write half doesn't close it
+            let _ = wo.shutdown().await;
+        };
 
-        let server_to_client = tokio::spawn(async move {
+        let server_to_client = async {
             let mut buf = vec![0u8; 8192];
             loop {
                 let n = match ro.read(&mut buf).await {
@@ -107,25 +313,894 @@ impl ProxyServer {
                     }
                 };
 
-                if let Some(limiter) = &limiter2 {
-                    limiter.wait_for_capacity(n).await;
-                }
+                shaper.wait(Direction::Download, n).await;
 
                 if let Err(e) = wi.write_all(&buf[..n]).await {
                     tracing::error!("Write error: {}", e);
                     break;
                 }
 
-                stats2.add_bytes_received(n);
+                stats.add_bytes_received(n);
+                if let Some(host) = &host {
+                    stats.add_host_bytes_received(host, n);
+                }
+            }
+            // Dropping a split stream's write half doesn't close it
+            let _ = wi.shutdown().await;
+        };
+
+        // Both directions run in the connection's task, which holds its permits until they end
+        tokio::join!(client_to_server, server_to_client);
+        Ok(())
+    }
+
+    /// Forward datagrams from each source address through a socket of its own, so replies
+    /// from the target find their way back. Sessions end after `udp_idle_timeout` without
+    /// traffic either way, and all of them end with the proxy.
+    async fn serve_udp(&self, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut tasks = JoinSet::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            let (n, peer_addr) = tokio::select! {
+                received = socket.recv_from(&mut buf) => received?,
+                Some(ended) = tasks.join_next(), if !tasks.is_empty() => {
+                    // Unless the source has already started a new session
+                    if let Ok(peer_addr) = ended {
+                        if sessions.get(&peer_addr).is_some_and(|session| session.is_closed()) {
+                            sessions.remove(&peer_addr);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            match sessions.get(&peer_addr).map(|session| session.try_send(buf[..n].to_vec())) {
+                Some(Ok(())) => continue,
+                Some(Err(TrySendError::Full(_))) => {
+                    tracing::debug!("UDP session for {} is backed up, dropping a datagram", peer_addr);
+                    continue;
+                }
+                // No session, or it just went idle
+                Some(Err(TrySendError::Closed(_))) | None => {}
+            }
+
+            let permits = match self.admit(peer_addr) {
+                Ok(permits) => permits,
+                Err(refusal) => {
+                    tracing::debug!("Proxy {} at {}, dropping a datagram from {}", self.listen_addr, refusal, peer_addr);
+                    continue;
+                }
+            };
+
+            let upstream = match self.connect_udp().await {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    tracing::error!("UDP session for {} failed: {}", peer_addr, e);
+                    continue;
+                }
+            };
+
+            let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE);
+            let _ = tx.try_send(buf[..n].to_vec());
+            sessions.insert(peer_addr, tx);
+
+            let session = UdpSession {
+                peer_addr,
+                listener: socket.clone(),
+                upstream,
+                idle_timeout: self.udp_idle_timeout,
+                stats: self.stats.clone(),
+                shaper: self.shaping.shaper(peer_addr.ip()),
+                _permits: permits,
+            };
+            tasks.spawn(async move {
+                if let Err(e) = session.run(rx).await {
+                    tracing::error!("UDP session error from {}: {}", peer_addr, e);
+                }
+                peer_addr
+            });
+        }
+    }
+
+    /// A socket of the target's address family, connected so only its replies come back
+    async fn connect_udp(&self) -> Result<UdpSocket> {
+        let target = self
+            .target_addr
+            .ok_or_else(|| anyhow::anyhow!("UDP proxy has no target address"))?;
+        let upstream = bind_udp_for(target).await?;
+        upstream.connect(target).await?;
+        Ok(upstream)
+    }
+
+    /// Serve one SOCKS5 client: a `CONNECT` is forwarded like any proxied connection, a
+    /// `UDP ASSOCIATE` relays datagrams for as long as the client keeps this connection open
+    async fn handle_socks5(&self, mut inbound: TcpStream, shaper: Shaper) -> Result<()> {
+        let peer_addr = inbound.peer_addr()?;
+
+        let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, socks::handshake(&mut inbound, self.auth.as_ref()))
+            .await
+            .map_err(|_| anyhow::anyhow!("SOCKS5 client {} did not finish its handshake in time", peer_addr))??;
+
+        match request {
+            socks::Request::Connect(destination) => {
+                let outbound = match self.connect_destination(&destination, peer_addr).await {
+                    Ok(outbound) => outbound,
+                    Err(code) => {
+                        socks::reply(&mut inbound, code, unspecified()).await?;
+                        return Ok(());
+                    }
+                };
+                socks::reply(&mut inbound, socks::SUCCEEDED, outbound.local_addr()?).await?;
+                tracing::debug!("SOCKS5 client {} connected to {}", peer_addr, destination);
+
+                Self::forward(inbound, outbound, self.stats.clone(), shaper).await
+            }
+            socks::Request::UdpAssociate(source) => self.udp_associate(inbound, peer_addr, source, shaper).await,
+            socks::Request::Unsupported => Ok(()),
+        }
+    }
+
+    /// Resolve a destination a SOCKS5 or HTTP `CONNECT` client named to the addresses the
+    /// allow and deny lists let it reach, failing with the reply code to send back
+    async fn resolve_destination(&self, destination: &Destination, peer_addr: SocketAddr) -> Result<Vec<SocketAddr>, u8> {
+        let addrs = match destination.resolve().await {
+            Ok(addrs) if !addrs.is_empty() => addrs,
+            Ok(_) => return Err(socks::HOST_UNREACHABLE),
+            Err(e) => {
+                tracing::debug!("Proxy client {} asked for {}, which doesn't resolve: {}", peer_addr, destination, e);
+                return Err(socks::HOST_UNREACHABLE);
+            }
+        };
+
+        // Only the addresses the rules allow are dialed
+        let allowed = self.destinations.allowed(destination.name(), &addrs);
+        if allowed.is_empty() {
+            tracing::warn!("Proxy client {} denied access to {}", peer_addr, destination);
+            return Err(socks::NOT_ALLOWED);
+        }
+
+        Ok(allowed)
+    }
+
+    async fn connect_destination(&self, destination: &Destination, peer_addr: SocketAddr) -> Result<TcpStream, u8> {
+        let mut code = socks::HOST_UNREACHABLE;
+        for addr in self.resolve_destination(destination, peer_addr).await? {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    tracing::debug!("Connection to {} ({}) failed: {}", destination, addr, e);
+                    code = socks::error_code(&e);
+                }
+                Err(_) => code = socks::HOST_UNREACHABLE,
+            }
+        }
+        Err(code)
+    }
+
+    async fn udp_associate(
+        &self,
+        mut control: TcpStream,
+        peer_addr: SocketAddr,
+        source: Destination,
+        shaper: Shaper,
+    ) -> Result<()> {
+        // Datagrams are taken on the address the client reached the proxy at
+        let relay = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+        socks::reply(&mut control, socks::SUCCEEDED, relay.local_addr()?).await?;
+        tracing::debug!("SOCKS5 client {} relaying UDP through {}", peer_addr, relay.local_addr()?);
+
+        // The client may announce where its datagrams come from; otherwise the first one tells
+        let client_addr = match source {
+            Destination::Addr(addr) if addr.port() != 0 && !addr.ip().is_unspecified() => Some(addr),
+            _ => None,
+        };
+
+        let peer = peer_addr.to_string();
+        self.stats.new_connection(peer.clone());
+        let result = self.relay_udp(&mut control, relay, peer_addr, client_addr, shaper).await;
+        self.stats.close_connection(&peer);
+        result
+    }
+
+    async fn relay_udp(
+        &self,
+        control: &mut TcpStream,
+        relay: UdpSocket,
+        peer_addr: SocketAddr,
+        mut client_addr: Option<SocketAddr>,
+        shaper: Shaper,
+    ) -> Result<()> {
+        // Sockets towards destinations, one per address family as needed
+        let mut outbound_v4: Option<UdpSocket> = None;
+        let mut outbound_v6: Option<UdpSocket> = None;
+        // Replies are only passed back from destinations the client has sent to
+        let mut contacted = HashSet::new();
+
+        let mut control_buf = [0u8; 64];
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut reply_buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            tokio::select! {
+                // The association ends with the client's TCP connection
+                read = control.read(&mut control_buf) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        return Ok(());
+                    }
+                }
+                received = relay.recv_from(&mut buf) => {
+                    let (n, from) = received?;
+                    if from.ip().to_canonical() != peer_addr.ip().to_canonical() || *client_addr.get_or_insert(from) != from {
+                        continue;
+                    }
+                    let Some((destination, payload)) = socks::parse_udp_datagram(&buf[..n]) else {
+                        continue;
+                    };
+                    let Ok(addrs) = self.resolve_destination(&destination, peer_addr).await else {
+                        continue;
+                    };
+
+                    let target = addrs[0];
+                    let outbound = match target {
+                        SocketAddr::V4(_) => &mut outbound_v4,
+                        SocketAddr::V6(_) => &mut outbound_v6,
+                    };
+                    if outbound.is_none() {
+                        *outbound = Some(bind_udp_for(target).await?);
+                    }
+                    shaper.wait(Direction::Upload, payload.len()).await;
+                    if let Some(outbound) = outbound {
+                        outbound.send_to(payload, target).await?;
+                    }
+                    self.stats.add_bytes_sent(payload.len());
+                    contacted.insert(target);
+                }
+                received = recv_from_either(&outbound_v4, &outbound_v6, &mut reply_buf) => {
+                    let (n, from) = received?;
+                    let Some(client_addr) = client_addr.filter(|_| contacted.contains(&from)) else {
+                        continue;
+                    };
+                    shaper.wait(Direction::Download, n).await;
+                    relay.send_to(&socks::encode_udp_datagram(from, &reply_buf[..n]), client_addr).await?;
+                    self.stats.add_bytes_received(n);
+                }
+            }
+        }
+    }
+
+    /// Serve one HTTP/1 client connection: `CONNECT` requests become tunnels, anything
+    /// else goes to the backends of the matching route
+    async fn handle_http(self: Arc<Self>, inbound: TcpStream, peer_addr: SocketAddr, shaper: Shaper) -> Result<()> {
+        let peer = peer_addr.to_string();
+        self.stats.new_connection(peer.clone());
+        let result = self.serve_http(inbound, peer_addr, shaper).await;
+        self.stats.close_connection(&peer);
+        result
+    }
+
+    async fn serve_http(self: &Arc<Self>, inbound: TcpStream, peer_addr: SocketAddr, shaper: Shaper) -> Result<()> {
+        let (tunnels, mut tunnel) = mpsc::unbounded_channel();
+        let server = self.clone();
+        let request_shaper = shaper.clone();
+        let service = service_fn(move |request: Request<Incoming>| {
+            let server = server.clone();
+            let tunnels = tunnels.clone();
+            let shaper = request_shaper.clone();
+            async move {
+                let response = if request.method() == Method::CONNECT {
+                    server.http_connect(request, peer_addr, &tunnels).await
+                } else {
+                    server.reverse_proxy(request, peer_addr, shaper).await
+                };
+                Ok::<_, Infallible>(response)
             }
-            Ok::<_, anyhow::Error>(())
         });
 
-        tokio::select! {
-            r1 = client_to_server => r1??,
-            r2 = server_to_client => r2??,
+        hyper::server::conn::http1::Builder::new()
+            .timer(TokioTimer::new())
+            .header_read_timeout(HEADER_READ_TIMEOUT)
+            .serve_connection(TokioIo::new(inbound), service)
+            .with_upgrades()
+            .await?;
+
+        // An accepted CONNECT hands the connection over to its tunnel
+        if let Ok(tunnel) = tunnel.try_recv() {
+            let upgraded = tunnel.upgrade.await?;
+            Self::pipe(TokioIo::new(upgraded), tunnel.outbound, Some(tunnel.host), self.stats.clone(), shaper).await?;
         }
+        Ok(())
+    }
 
+    async fn http_connect(
+        &self,
+        mut request: Request<Incoming>,
+        peer_addr: SocketAddr,
+        tunnels: &mpsc::UnboundedSender<HttpTunnel>,
+    ) -> Response<HttpBody> {
+        if let Some(auth) = &self.auth {
+            if !http_proxy::proxy_authorized(request.headers(), auth) {
+                let mut response = http_proxy::empty_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+                response
+                    .headers_mut()
+                    .insert(header::PROXY_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"sentinel\""));
+                return response;
+            }
+        }
+
+        let Some(authority) = request.uri().authority() else {
+            return http_proxy::error_response(StatusCode::BAD_REQUEST, "CONNECT needs host:port");
+        };
+        let port = authority.port_u16().unwrap_or(443);
+        let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+        let destination = match host.parse::<IpAddr>() {
+            Ok(ip) => Destination::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Destination::Domain(host.to_ascii_lowercase(), port),
+        };
+
+        let outbound = match self.connect_destination(&destination, peer_addr).await {
+            Ok(outbound) => outbound,
+            Err(socks::NOT_ALLOWED) => {
+                return http_proxy::error_response(StatusCode::FORBIDDEN, &format!("Access to {} is not allowed", destination))
+            }
+            Err(_) => {
+                return http_proxy::error_response(StatusCode::BAD_GATEWAY, &format!("Could not connect to {}", destination))
+            }
+        };
+        tracing::debug!("HTTP client {} connected to {}", peer_addr, destination);
+
+        let host = match &destination {
+            Destination::Addr(addr) => addr.ip().to_string(),
+            Destination::Domain(name, _) => name.clone(),
+        };
+        self.stats.record_request(&host);
+        let _ = tunnels.send(HttpTunnel {
+            upgrade: hyper::upgrade::on(&mut request),
+            outbound,
+            host,
+        });
+        http_proxy::empty_response(StatusCode::OK)
+    }
+
+    /// Pass a request to the first backend of its route that accepts a connection
+    async fn reverse_proxy(&self, mut request: Request<Incoming>, peer_addr: SocketAddr, shaper: Shaper) -> Response<HttpBody> {
+        let Some(host) = http_proxy::request_host(&request) else {
+            return http_proxy::error_response(StatusCode::BAD_REQUEST, "Missing Host header");
+        };
+        let Some(route) = self.routes.find(&host, request.uri().path()) else {
+            return http_proxy::error_response(StatusCode::NOT_FOUND, &format!("No route for {}{}", host, request.uri().path()));
+        };
+        self.stats.record_request(&host);
+
+        let mut backend = None;
+        for addr in route.backends() {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => {
+                    backend = Some((addr, stream));
+                    break;
+                }
+                Ok(Err(e)) => tracing::warn!("HTTP backend {} for {} failed: {}", addr, host, e),
+                Err(_) => tracing::warn!("HTTP backend {} for {} timed out", addr, host),
+            }
+        }
+        let Some((addr, stream)) = backend else {
+            return http_proxy::error_response(StatusCode::BAD_GATEWAY, &format!("No backend for {} is reachable", host));
+        };
+
+        let (mut sender, connection) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                tracing::warn!("HTTP backend {} for {} failed: {}", addr, host, e);
+                return http_proxy::error_response(StatusCode::BAD_GATEWAY, &format!("Backend for {} failed", host));
+            }
+        };
+        let backend_addr = addr.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("HTTP backend connection to {} ended: {}", backend_addr, e);
+            }
+        });
+
+        // Backends get origin-form requests with the original Host, and learn the client
+        // from X-Forwarded-*
+        let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+        *request.uri_mut() = path.parse().unwrap_or_default();
+        let headers = request.headers_mut();
+        http_proxy::strip_hop_by_hop(headers);
+        if !headers.contains_key(header::HOST) {
+            if let Ok(value) = HeaderValue::from_str(&host) {
+                headers.insert(header::HOST, value);
+            }
+        }
+        let forwarded_for = match headers.get("x-forwarded-for").and_then(|value| value.to_str().ok()) {
+            Some(previous) => format!("{}, {}", previous, peer_addr.ip()),
+            None => peer_addr.ip().to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert("x-forwarded-for", value);
+        }
+        if let Some(value) = headers.get(header::HOST).cloned() {
+            headers.insert("x-forwarded-host", value);
+        }
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+
+        let stats = self.stats.clone();
+        let request = request.map(|body| http_proxy::counted(body, stats, host.clone(), Direction::Upload, shaper.clone()));
+        match sender.send_request(request).await {
+            Ok(response) => {
+                let stats = self.stats.clone();
+                let mut response = response.map(|body| http_proxy::counted(body, stats, host, Direction::Download, shaper));
+                http_proxy::strip_hop_by_hop(response.headers_mut());
+                response
+            }
+            Err(e) => {
+                tracing::warn!("HTTP backend {} for {} failed: {}", addr, host, e);
+                http_proxy::error_response(StatusCode::BAD_GATEWAY, &format!("Backend for {} failed", host))
+            }
+        }
+    }
+}
+
+/// A `CONNECT` that was accepted, waiting for its response to go out
+struct HttpTunnel {
+    upgrade: OnUpgrade,
+    outbound: TcpStream,
+    host: String,
+}
+
+/// A proxy's caps: `bandwidth`, with `rate_limit` for the directions it leaves open
+fn proxy_bandwidth(config: &ProxyConfig) -> Bandwidth {
+    let mut bandwidth = config.bandwidth.unwrap_or_default();
+    if let Some(mbps) = config.rate_limit {
+        if bandwidth.upload_mbps == 0 {
+            bandwidth.upload_mbps = mbps;
+        }
+        if bandwidth.download_mbps == 0 {
+            bandwidth.download_mbps = mbps;
+        }
+    }
+    bandwidth
+}
+
+/// A UDP socket on an ephemeral port, of the same address family as `target`
+async fn bind_udp_for(target: SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    Ok(UdpSocket::bind(local).await?)
+}
+
+/// Receive on whichever socket has a datagram first; waits forever when neither exists yet
+async fn recv_from_either(
+    v4: &Option<UdpSocket>,
+    v6: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match (v4, v6) {
+        (Some(v4), Some(v6)) => loop {
+            // Both would read into the same buffer, so wait for one to be readable first
+            let socket = tokio::select! {
+                ready = v4.readable() => { ready?; v4 }
+                ready = v6.readable() => { ready?; v6 }
+            };
+            match socket.try_recv_from(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        },
+        (Some(socket), None) | (None, Some(socket)) => socket.recv_from(buf).await,
+        (None, None) => std::future::pending().await,
+    }
+}
+
+fn unspecified() -> SocketAddr {
+    (Ipv4Addr::UNSPECIFIED, 0).into()
+}
+
+/// One source address of a UDP proxy, counted as a connection in its stats
+struct UdpSession {
+    peer_addr: SocketAddr,
+    listener: Arc<UdpSocket>,
+    upstream: UdpSocket,
+    idle_timeout: Duration,
+    stats: Arc<StatsCollector>,
+    shaper: Shaper,
+    _permits: Permits,
+}
+
+impl UdpSession {
+    async fn run(self, mut datagrams: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let peer = self.peer_addr.to_string();
+        self.stats.new_connection(peer.clone());
+        let result = self.forward(&mut datagrams).await;
+        self.stats.close_connection(&peer);
+        result
+    }
+
+    async fn forward(&self, datagrams: &mut mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => {
+                    let Some(datagram) = datagram else { return Ok(()) };
+                    self.shaper.wait(Direction::Upload, datagram.len()).await;
+                    self.upstream.send(&datagram).await?;
+                    self.stats.add_bytes_sent(datagram.len());
+                }
+                received = self.upstream.recv(&mut buf) => {
+                    let n = received?;
+                    self.shaper.wait(Direction::Download, n).await;
+                    self.listener.send_to(&buf[..n], self.peer_addr).await?;
+                    self.stats.add_bytes_received(n);
+                }
+                _ = tokio::time::sleep(self.idle_timeout) => {
+                    tracing::debug!("UDP session for {} idle, closing", self.peer_addr);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+struct RunningProxy {
+    config: ProxyConfig,
+    local_addr: SocketAddr,
+    server: Arc<ProxyServer>,
+    handle: JoinHandle<()>,
+}
+
+/// Named proxy forwarders, each with its own bandwidth caps, stats and listener task, and all
+/// under the client-wide connection limits. Proxies come from the local config or from
+/// `ConfigureProxy` tasks.
+pub struct ProxyRegistry {
+    proxies: Mutex<HashMap<String, RunningProxy>>,
+    limits: Arc<ConnectionLimits>,
+}
+
+impl ProxyRegistry {
+    pub fn new(limits: LimitSettings) -> Self {
+        Self {
+            proxies: Mutex::new(HashMap::new()),
+            limits: Arc::new(ConnectionLimits::new(limits)),
+        }
+    }
+
+    /// Change the client-wide connection limits; open connections are kept
+    pub fn set_limits(&self, limits: LimitSettings) {
+        self.limits.set(limits);
+    }
+
+    /// Start a proxy, replacing any proxy already running under the same name.
+    /// Connections accepted by a replaced or stopped proxy run until they close.
+    pub async fn start(&self, config: ProxyConfig) -> Result<SocketAddr> {
+        let server = Arc::new(ProxyServer::from_config(&config)?.with_global_limits(self.limits.clone()));
+        let mut proxies = self.proxies.lock().await;
+
+        // Release the old listener first so the replacement can bind the same port
+        if let Some(old) = proxies.remove(&config.name) {
+            old.handle.abort();
+            let _ = old.handle.await;
+            tracing::info!("Replacing proxy {} (was {} -> {})", config.name, old.local_addr, old.config.target_addr);
+        }
+
+        let listener = server.bind().await?;
+        let local_addr = listener.local_addr()?;
+
+        let handle = {
+            let server = server.clone();
+            let name = config.name.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(listener).await {
+                    tracing::error!("Proxy {} stopped: {}", name, e);
+                }
+            })
+        };
+
+        proxies.insert(
+            config.name.clone(),
+            RunningProxy {
+                config,
+                local_addr,
+                server,
+                handle,
+            },
+        );
+        Ok(local_addr)
+    }
+
+    /// Change a running proxy's bandwidth caps, for its open connections too. The caps
+    /// replace `rate_limit` in its stored config.
+    pub async fn set_bandwidth(&self, name: &str, bandwidth: Option<Bandwidth>, source_bandwidth: Option<Bandwidth>) -> Result<()> {
+        let mut proxies = self.proxies.lock().await;
+        let proxy = proxies
+            .get_mut(name)
+            .ok_or_else(|| anyhow::anyhow!("No proxy named {}", name))?;
+
+        proxy.config.rate_limit = None;
+        proxy.config.bandwidth = bandwidth;
+        proxy.config.source_bandwidth = source_bandwidth;
+        proxy
+            .server
+            .shaping
+            .set(proxy_bandwidth(&proxy.config), source_bandwidth.unwrap_or_default());
+
+        tracing::info!("Set bandwidth of proxy {}: {:?}, per source {:?}", name, bandwidth, source_bandwidth);
+        Ok(())
+    }
+
+    /// Change a running proxy's `rate_limit`, which only caps the directions its
+    /// `bandwidth` leaves open; its other caps stay as they are
+    pub async fn set_rate_limit(&self, name: &str, mbps: u32) -> Result<()> {
+        let mut proxies = self.proxies.lock().await;
+        let proxy = proxies
+            .get_mut(name)
+            .ok_or_else(|| anyhow::anyhow!("No proxy named {}", name))?;
+
+        proxy.config.rate_limit = Some(mbps);
+        proxy.server.shaping.set(
+            proxy_bandwidth(&proxy.config),
+            proxy.config.source_bandwidth.unwrap_or_default(),
+        );
+
+        tracing::info!("Set rate limit of proxy {} to {} Mbps", name, mbps);
+        Ok(())
+    }
+
+    pub async fn stop(&self, name: &str) -> Result<()> {
+        let proxy = self
+            .proxies
+            .lock()
+            .await
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("No proxy named {}", name))?;
+
+        proxy.handle.abort();
+        let _ = proxy.handle.await;
+
+        tracing::info!("Stopped proxy {} on {}", name, proxy.local_addr);
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub async fn config(&self, name: &str) -> Option<ProxyConfig> {
+        self.proxies.lock().await.get(name).map(|proxy| proxy.config.clone())
+    }
+
+    pub async fn statuses(&self) -> Vec<ProxyStatus> {
+        let proxies = self.proxies.lock().await;
+        let mut statuses: Vec<ProxyStatus> = proxies
+            .values()
+            .map(|proxy| {
+                let stats = proxy.server.stats.get_stats();
+                ProxyStatus {
+                    name: proxy.config.name.clone(),
+                    listen_addr: proxy.local_addr.to_string(),
+                    target_addr: proxy.config.target_addr.clone(),
+                    running: !proxy.handle.is_finished(),
+                    bytes_sent: stats.bytes_sent,
+                    bytes_received: stats.bytes_received,
+                    total_connections: stats.total_connections,
+                    active_connections: stats.active_connections,
+                    refused_connections: stats.refused_connections,
+                    hosts: proxy
+                        .server
+                        .stats
+                        .host_stats()
+                        .into_iter()
+                        .map(|(host, stats)| HostTraffic {
+                            host,
+                            requests: stats.requests,
+                            bytes_sent: stats.bytes_sent,
+                            bytes_received: stats.bytes_received,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_sessions() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let server = Arc::new(
+            ProxyServer::new("127.0.0.1:0".parse().unwrap(), echo_addr)
+                .with_mode(ProxyMode::Udp)
+                .with_idle_timeout(Duration::from_millis(300)),
+        );
+        let listener = server.bind().await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(listener).await }
+        });
+
+        let mut buf = [0u8; 1500];
+        for payload in [&b"first"[..], b"second"] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(payload, proxy_addr).await.unwrap();
+            let (n, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], payload);
+            assert_eq!(from, proxy_addr);
+        }
+
+        let stats = server.stats.get_stats();
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!(stats.bytes_sent, 11);
+        assert_eq!(stats.bytes_received, 11);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(server.stats.get_stats().active_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_closed_connection() {
+        // A target that answers only once the client is done sending
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stream.write_all(&request).await.unwrap();
+        });
+
+        let server = Arc::new(ProxyServer::new("127.0.0.1:0".parse().unwrap(), target_addr).with_limits(LimitSettings {
+            max_connections: 1,
+            ..Default::default()
+        }));
+        let listener = server.bind().await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(server.clone().serve(listener));
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Still open and holding the only permit
+        assert_eq!(server.stats.get_stats().active_connections, 1);
+        let mut refused = TcpStream::connect(proxy_addr).await.unwrap();
+        assert_eq!(refused.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"ping");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.stats.get_stats().active_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let server = Arc::new(
+            ProxyServer::socks5("127.0.0.1:0".parse().unwrap())
+                .with_auth(Some(ProxyAuth { username: "user".into(), password: "secret".into() }))
+                .with_destinations(DestinationFilter::new(&[], &[format!("127.0.0.1:{}", target_addr.port() + 1)]).unwrap()),
+        );
+        let listener = server.bind().await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(server.clone().serve(listener));
+
+        // Greeting and password authentication, then CONNECT to an IPv4 address and port
+        let connect = |password: &'static str, port: u16| async move {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let mut reply = [0u8; 10];
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            client.read_exact(&mut reply[..2]).await.unwrap();
+            assert_eq!(reply[..2], [5, 2]);
+
+            let mut auth = vec![1, 4];
+            auth.extend_from_slice(b"user");
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            client.write_all(&auth).await.unwrap();
+            client.read_exact(&mut reply[..2]).await.unwrap();
+            if reply[1] != 0 {
+                return (client, None);
+            }
+
+            let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+            request.extend_from_slice(&port.to_be_bytes());
+            client.write_all(&request).await.unwrap();
+            client.read_exact(&mut reply).await.unwrap();
+            (client, Some(reply[1]))
+        };
+
+        assert_eq!(connect("wrong", target_addr.port()).await.1, None);
+        assert_eq!(connect("secret", target_addr.port() + 1).await.1, Some(socks::NOT_ALLOWED));
+
+        let (mut client, code) = connect("secret", target_addr.port()).await;
+        assert_eq!(code, Some(socks::SUCCEEDED));
+        let mut greeting = Vec::new();
+        client.read_to_end(&mut greeting).await.unwrap();
+        assert_eq!(greeting, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy() {
+        // A backend answering once with the request it saw
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let seen = String::from_utf8_lossy(&buf[..n]).to_string();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", seen.len(), seen);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let routes = RouteTable::new(&[sentinel_common::HttpRoute {
+            host: Some("*.internal".into()),
+            path_prefix: "/api".into(),
+            backends: vec![backend_addr.to_string()],
+        }])
+        .unwrap();
+        let server = Arc::new(ProxyServer::http("127.0.0.1:0".parse().unwrap()).with_routes(routes));
+        let listener = server.bind().await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(server.clone().serve(listener));
+
+        let request = |text: String| async move {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(text.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            String::from_utf8(response).unwrap()
+        };
+
+        let response = request("GET /apix HTTP/1.1\r\nHost: app.internal\r\nConnection: close\r\n\r\n".into()).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        let response =
+            request("GET /api/users?id=1 HTTP/1.1\r\nHost: App.Internal:8080\r\nConnection: close\r\n\r\n".into()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("GET /api/users?id=1 HTTP/1.1"), "{}", response);
+        assert!(response.contains("x-forwarded-for: 127.0.0.1"), "{}", response);
+
+        let response = request(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr)).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let hosts = server.stats.host_stats();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].0, "127.0.0.1");
+        assert_eq!(hosts[0].1.bytes_received, 5);
+        assert_eq!(hosts[1].0, "app.internal");
+        assert_eq!(hosts[1].1.requests, 1);
+        assert!(hosts[1].1.bytes_received > 0);
+    }
+}
@@ -1,141 +1,510 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use jsonrpsee::core::client::{ClientT, Error as RpcError, SubscriptionClientT};
+use jsonrpsee::core::params::ObjectParams;
 use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
-use jsonrpsee::core::client::ClientT;
+use jsonrpsee::ws_client::{PingConfig, WsClientBuilder};
 use sentinel_common::{
-    ClientInfo, HeartbeatRequest, HeartbeatResponse, RegisterRequest, RegisterResponse, Task,
+    register_challenge_message, ClientInfo, HeartbeatRequest, HeartbeatResponse, RefreshTokenRequest,
+    RegisterChallengeRequest, RegisterChallengeResponse, RegisterRequest, RegisterResponse, Task, TaskResult, TaskResultRequest, TaskRunningRequest,
+    TaskSubscribeRequest, UNAUTHORIZED_ERROR_CODE,
 };
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tokio::time::interval;
 
+use crate::encryption::IdentityKey;
 use crate::monitor::SystemMonitor;
+use crate::proxy::ProxyRegistry;
+
+/// How often to poll for tasks, and retry the push channel, while the channel is down
+const TASK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Identity confirmed by the server and the token issued with it.
+/// Kept in the state file so restarts come back as the same client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    client_id: String,
+    token: String,
+    expires_at: DateTime<Utc>,
+}
 
 pub struct RegistrationManager {
     client_info: ClientInfo,
-    #[allow(dead_code)]
+    /// Registered so the server can hand its public half to relay peers; signs the
+    /// challenge when the token alone no longer proves this client holds its id
+    identity: Arc<IdentityKey>,
     server_url: String,
-    heartbeat_interval: Duration,
-    token: Arc<RwLock<Option<String>>>,
+    heartbeat_secs: AtomicU64,
+    session: Arc<RwLock<Option<Session>>>,
+    state_file: PathBuf,
+    registered: AtomicBool,
     client: HttpClient,
+    task_tx: mpsc::UnboundedSender<Task>,
+    proxy_registry: Arc<ProxyRegistry>,
 }
 
 impl RegistrationManager {
-    pub fn new(client_info: ClientInfo, server_url: String, heartbeat_interval: Duration) -> Self {
+    pub fn new(
+        client_info: ClientInfo,
+        identity: Arc<IdentityKey>,
+        server_url: String,
+        heartbeat_interval: Duration,
+        state_file: PathBuf,
+        task_tx: mpsc::UnboundedSender<Task>,
+        proxy_registry: Arc<ProxyRegistry>,
+    ) -> Self {
         let client = HttpClientBuilder::default()
             .build(&server_url)
             .expect("Failed to create HTTP client");
 
+        // A client id set in config.toml takes precedence over the one remembered in the state file
+        let session = load_session(&state_file)
+            .filter(|s| client_info.id.is_empty() || s.client_id == client_info.id);
+        if let Some(session) = &session {
+            tracing::info!("Resuming as client {}", session.client_id);
+        }
+
         Self {
             client_info,
+            identity,
             server_url,
-            heartbeat_interval,
-            token: Arc::new(RwLock::new(None)),
+            heartbeat_secs: AtomicU64::new(heartbeat_interval.as_secs().max(1)),
+            session: Arc::new(RwLock::new(session)),
+            state_file,
+            registered: AtomicBool::new(false),
             client,
+            task_tx,
+            proxy_registry,
         }
     }
 
-    pub async fn start(&self) -> Result<()> {
-        self.register().await?;
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs.load(Ordering::Relaxed))
+    }
+
+    /// Change the heartbeat interval; takes effect after the current wait
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        self.heartbeat_secs.store(interval.as_secs().max(1), Ordering::Relaxed);
+    }
 
-        let mut ticker = interval(self.heartbeat_interval);
+    pub async fn start(&self) -> Result<()> {
         loop {
-            ticker.tick().await;
-            if let Err(e) = self.send_heartbeat().await {
+            self.heartbeat().await;
+            tokio::time::sleep(self.heartbeat_interval()).await;
+        }
+    }
+
+    async fn heartbeat(&self) {
+        if let Err(e) = self.ensure_session().await {
+            tracing::error!("Failed to establish session with server: {}", e);
+            return;
+        }
+
+        if let Err(e) = self.send_heartbeat().await {
+            if is_unauthorized(&e) {
+                tracing::warn!("Server rejected client token, re-registering: {}", e);
+                if let Err(e) = self.register().await {
+                    tracing::error!("Re-registration failed: {}", e);
+                }
+            } else {
                 tracing::error!("Heartbeat failed: {}", e);
-                self.register().await?;
             }
         }
     }
 
+    /// Register on startup or when no token is held, and refresh the token before it expires
+    async fn ensure_session(&self) -> Result<()> {
+        let session = self.session.read().await.clone();
+        let Some(session) = session.filter(|_| self.registered.load(Ordering::Relaxed)) else {
+            return self.register().await;
+        };
+
+        let refresh_margin = chrono::Duration::from_std(self.heartbeat_interval() * 2)
+            .unwrap_or_else(|_| chrono::Duration::minutes(1));
+        if session.expires_at - Utc::now() > refresh_margin {
+            return Ok(());
+        }
+
+        match self.refresh_token(&session.client_id, &session.token).await {
+            Err(e) if is_unauthorized(&e) => {
+                tracing::warn!("Token refresh rejected, re-registering: {}", e);
+                self.register().await
+            }
+            other => other,
+        }
+    }
+
     async fn register(&self) -> Result<()> {
         tracing::info!("Registering client with server...");
 
+        // Offer the remembered id and token so the server can confirm this is the same client
+        let previous = self.session.read().await.clone();
+        let mut client_info = self.client_info.clone();
+        if let Some(previous) = &previous {
+            client_info.id = previous.client_id.clone();
+        }
+
+        // The token is forgotten once the server restarts or drops this client as offline,
+        // so also sign a challenge with the identity key the id was registered with
+        let (nonce, signature) = match previous.as_ref() {
+            Some(previous) => match self.register_challenge(&previous.client_id).await {
+                Ok(nonce) => {
+                    let message = register_challenge_message(&previous.client_id, &nonce);
+                    (Some(nonce), Some(self.identity.sign_hex(&message)))
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to get a registration challenge: {}", e);
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+
         let request = RegisterRequest {
-            client_info: self.client_info.clone(),
+            client_info,
+            token: previous.as_ref().map(|s| s.token.clone()),
+            public_key: Some(self.identity.public_key_hex()),
+            nonce,
+            signature,
         };
 
         let response: RegisterResponse = self
             .client
-            .request("client.register", (request,))
+            .request("client.register", object_params(&request)?)
             .await?;
 
-        *self.token.write().await = Some(response.token.clone());
+        if !request.client_info.id.is_empty() && request.client_info.id != response.client_id {
+            tracing::warn!(
+                "Server assigned new client id {} in place of {}",
+                response.client_id,
+                request.client_info.id
+            );
+        }
+
+        self.store_session(Session {
+            client_id: response.client_id.clone(),
+            token: response.token,
+            expires_at: response.expires_at,
+        })
+        .await;
+        self.registered.store(true, Ordering::Relaxed);
 
         tracing::info!("Client registered successfully: {}", response.client_id);
         Ok(())
     }
 
-    async fn send_heartbeat(&self) -> Result<()> {
-        let token = self.token.read().await.clone();
-        if token.is_none() {
-            return Err(anyhow::anyhow!("No token available"));
+    async fn register_challenge(&self, client_id: &str) -> Result<String> {
+        let request = RegisterChallengeRequest {
+            client_id: client_id.to_string(),
+        };
+
+        let response: RegisterChallengeResponse = self
+            .client
+            .request("client.register_challenge", object_params(&request)?)
+            .await?;
+
+        Ok(response.nonce)
+    }
+
+    async fn refresh_token(&self, client_id: &str, token: &str) -> Result<()> {
+        let request = RefreshTokenRequest {
+            client_id: client_id.to_string(),
+            token: token.to_string(),
+        };
+
+        let response: RegisterResponse = self
+            .client
+            .request("client.refresh_token", object_params(&request)?)
+            .await?;
+
+        self.store_session(Session {
+            client_id: response.client_id,
+            token: response.token,
+            expires_at: response.expires_at,
+        })
+        .await;
+
+        tracing::debug!("Client token refreshed, valid until {}", response.expires_at);
+        Ok(())
+    }
+
+    async fn store_session(&self, session: Session) {
+        if let Err(e) = save_session(&self.state_file, &session) {
+            tracing::error!("Failed to save client state to {}: {}", self.state_file.display(), e);
         }
+        *self.session.write().await = Some(session);
+    }
+
+    /// Client id and token for authenticated calls, once registered
+    async fn credentials(&self) -> Option<(String, String)> {
+        self.session
+            .read()
+            .await
+            .as_ref()
+            .filter(|_| self.registered.load(Ordering::Relaxed))
+            .map(|s| (s.client_id.clone(), s.token.clone()))
+    }
+
+    async fn send_heartbeat(&self) -> Result<()> {
+        let (client_id, token) = self
+            .credentials()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No token available"))?;
 
         let metrics = SystemMonitor::collect_metrics().await.ok();
+        let proxies = self.proxy_registry.statuses().await;
 
         let request = HeartbeatRequest {
-            client_id: self.client_info.id.clone(),
-            token: token.unwrap(),
+            client_id,
+            token,
             metrics,
+            proxies: Some(proxies),
         };
 
         let response: HeartbeatResponse = self
             .client
-            .request("client.heartbeat", (request,))
+            .request("client.heartbeat", object_params(&request)?)
             .await?;
 
         if !response.tasks.is_empty() {
             tracing::info!("Received {} tasks from server", response.tasks.len());
-            for task in response.tasks {
-                self.handle_task(task).await?;
+            self.queue_tasks(response.tasks);
+        }
+
+        Ok(())
+    }
+
+    /// Hand tasks over to the task loop; the server has already marked them dispatched
+    fn queue_tasks(&self, tasks: Vec<Task>) {
+        for task in tasks {
+            if self.task_tx.send(task).is_err() {
+                tracing::error!("Task loop is not running, dropping task");
             }
         }
+    }
+
+    pub async fn report_running(&self, task_id: &str) -> Result<()> {
+        let (client_id, token) = self
+            .credentials()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No token available"))?;
+
+        let request = TaskRunningRequest {
+            client_id,
+            token,
+            task_id: task_id.to_string(),
+        };
+
+        let _: serde_json::Value = self
+            .client
+            .request("task.report_running", object_params(&request)?)
+            .await?;
 
         Ok(())
     }
 
-    async fn handle_task(&self, task: sentinel_common::Task) -> Result<()> {
-        tracing::info!("Processing task: {} (type: {:?})", task.id, task.task_type);
+    pub async fn report_result(&self, result: TaskResult) -> Result<()> {
+        let (client_id, token) = self
+            .credentials()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No token available"))?;
 
-        match task.task_type {
-            sentinel_common::TaskType::UpdateIptables => {
-                tracing::info!("Updating iptables rules...");
-            }
-            sentinel_common::TaskType::ConfigureProxy => {
-                tracing::info!("Configuring proxy...");
-            }
-            sentinel_common::TaskType::StartRelay => {
-                tracing::info!("Starting relay...");
+        let request = TaskResultRequest {
+            client_id,
+            token,
+            result,
+        };
+
+        let _: serde_json::Value = self
+            .client
+            .request("task.report_result", object_params(&request)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Receive tasks over the server's push channel, polling instead whenever the channel is down
+    pub async fn stream_tasks(&self) {
+        let mut fallback = interval(TASK_POLL_INTERVAL);
+        loop {
+            fallback.tick().await;
+
+            if self.credentials().await.is_none() {
+                continue;
             }
-            sentinel_common::TaskType::StopRelay => {
-                tracing::info!("Stopping relay...");
+
+            match self.subscribe_tasks().await {
+                Ok(()) => tracing::warn!("Task channel closed, falling back to polling"),
+                Err(e) => tracing::debug!("Task channel unavailable, polling instead: {}", e),
             }
-            sentinel_common::TaskType::UpdateConfig => {
-                tracing::info!("Updating configuration...");
+
+            if let Err(e) = self.poll_tasks().await {
+                tracing::debug!("Task poll failed: {}", e);
             }
         }
+    }
+
+    /// Hold a `client.subscribe_tasks` subscription open until the connection drops
+    async fn subscribe_tasks(&self) -> Result<()> {
+        let (client_id, token) = self
+            .credentials()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No token available"))?;
+
+        let client = WsClientBuilder::default()
+            .enable_ws_ping(PingConfig::new().ping_interval(self.heartbeat_interval()))
+            .build(ws_url(&self.server_url))
+            .await?;
+
+        let request = TaskSubscribeRequest {
+            client_id,
+            token,
+        };
+
+        let mut subscription = client
+            .subscribe::<Task, _>("client.subscribe_tasks", object_params(&request)?, "client.unsubscribe_tasks")
+            .await?;
+
+        tracing::info!("Task channel established");
+
+        while let Some(task) = subscription.next().await {
+            let task = task?;
+            tracing::info!("Received task {} from server", task.id);
+            self.queue_tasks(vec![task]);
+        }
 
         Ok(())
     }
 
-    pub async fn get_pending_tasks(&self) -> Result<Vec<Task>> {
-        let token = self.token.read().await.clone();
-        if token.is_none() {
-            return Ok(vec![]);
-        }
+    /// Poll the server for tasks and queue them for the task loop
+    async fn poll_tasks(&self) -> Result<()> {
+        let Some((client_id, token)) = self.credentials().await else {
+            return Ok(());
+        };
 
         let request = HeartbeatRequest {
-            client_id: self.client_info.id.clone(),
-            token: token.unwrap(),
+            client_id,
+            token,
             metrics: None,
+            proxies: None,
         };
 
         let response: HeartbeatResponse = self
             .client
-            .request("client.heartbeat", (request,))
+            .request("client.heartbeat", object_params(&request)?)
             .await?;
 
-        Ok(response.tasks)
+        self.queue_tasks(response.tasks);
+        Ok(())
+    }
+}
+
+fn load_session(path: &Path) -> Option<Session> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!("Failed to read client state from {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    serde_json::from_slice(&data)
+        .map_err(|e| tracing::warn!("Ignoring unreadable client state in {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Write the state file in one step, readable only by the client's user since it holds the token
+fn save_session(path: &Path, session: &Session) -> Result<()> {
+    use std::io::Write;
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(session)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Map the server's HTTP endpoint to its WebSocket endpoint
+fn ws_url(server_url: &str) -> String {
+    if let Some(rest) = server_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = server_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        server_url.to_string()
+    }
+}
+
+/// Encode a request struct as named JSON-RPC params, which is what the server parses
+pub fn object_params<T: Serialize>(value: &T) -> Result<ObjectParams> {
+    let serde_json::Value::Object(fields) = serde_json::to_value(value)? else {
+        anyhow::bail!("RPC params must serialize to a JSON object");
+    };
+
+    let mut params = ObjectParams::new();
+    for (name, field) in fields {
+        params.insert(&name, field)?;
     }
-}
\ No newline at end of file
+    Ok(params)
+}
+
+/// Whether the server refused the call because of a missing, invalid or expired token
+pub fn is_unauthorized(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<RpcError>(),
+        Some(RpcError::Call(e)) if e.code() == UNAUTHORIZED_ERROR_CODE
+    )
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_state_file() {
+        let dir = std::env::temp_dir().join(format!("sentinel-state-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client-state.json");
+
+        assert!(load_session(&path).is_none());
+
+        let session = Session {
+            client_id: "80921b5c-3606-4138-a554-215a05dcf2c3".to_string(),
+            token: "token".to_string(),
+            expires_at: Utc::now(),
+        };
+        save_session(&path, &session).unwrap();
+
+        // A restart reads back the same id and token to offer the server
+        let loaded = load_session(&path).unwrap();
+        assert_eq!(loaded.client_id, session.client_id);
+        assert_eq!(loaded.token, session.token);
+        assert_eq!(loaded.expires_at, session.expires_at);
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // A damaged file is ignored, so the client registers afresh
+        std::fs::write(&path, b"{ not json").unwrap();
+        assert!(load_session(&path).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
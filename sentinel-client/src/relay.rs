@@ -1,187 +1,573 @@
 use anyhow::Result;
-use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
-use sentinel_common::{RelayConfig, TransportType};
-use crate::encryption::EncryptionManager;
+use sentinel_common::{RelayConfig, RelayPeer, RelayRole, TransportType};
+use crate::exits::ExitPool;
+use crate::limiter::{Direction, Shaper, Shaping};
+use crate::encryption::{EncryptionManager, StreamRole, SymmetricEncryptedStream};
+use crate::mux::{MuxSession, MuxStream, TunnelIo};
+use crate::websocket::{self, WebSocketByteStream, WebSocketTransport};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
-use tracing;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
 pub struct RelayManager {
-    #[allow(dead_code)]
-    server_url: String,
-    #[allow(dead_code)]
-    client: HttpClient,
-    active_relays: Arc<RwLock<HashMap<String, RelayConnection>>>,
+    active_relays: Arc<RwLock<HashMap<String, ActiveRelay>>>,
     encryption_manager: EncryptionManager,
+    /// How long a stopped relay's connections may take to finish before they are cut
+    stop_grace: Duration,
 }
 
-impl RelayManager {
-    pub fn new(server_url: String) -> Result<Self> {
-        let client = HttpClientBuilder::default()
-            .build(&server_url)
-            .expect("Failed to create HTTP client");
+struct ActiveRelay {
+    connection: Arc<RelayConnection>,
+    /// The accept loop, finished once the listener is closed
+    task: JoinHandle<()>,
+}
 
-        Ok(Self {
-            server_url,
-            client,
+/// What `stop_relay` closed, returned as the stop task's result
+#[derive(Debug, Serialize)]
+pub struct StopReport {
+    pub relay_id: String,
+    /// Address of the listener that was closed
+    pub listener: String,
+    /// Connections that finished within the grace period
+    pub drained: usize,
+    /// Connections still open when the grace period ran out
+    pub aborted: usize,
+}
+
+impl RelayManager {
+    pub fn new(encryption_manager: EncryptionManager, stop_grace: Duration) -> Self {
+        Self {
             active_relays: Arc::new(RwLock::new(HashMap::new())),
-            encryption_manager: EncryptionManager::new(),
-        })
+            encryption_manager,
+            stop_grace,
+        }
     }
 
-    pub async fn start_relay(&self, config: RelayConfig) -> Result<()> {
-        let relay_id = format!("{}:{}", config.entry_point, config.exit_point);
+    /// Start one end of a relay, returning the address its listener is bound to
+    pub async fn start_relay(&self, config: RelayConfig) -> Result<SocketAddr> {
+        let relay_id = relay_id(&config);
+
+        if matches!(config.transport_type, TransportType::Encrypted) && config.peer.is_none() {
+            anyhow::bail!("Encrypted relay requested without the peer client's identity key");
+        }
+        if config.tunnel_addr.is_none() && (config.role != RelayRole::Entry || !matches!(config.transport_type, TransportType::Direct)) {
+            anyhow::bail!("{:?} relay requested without a tunnel address", config.transport_type);
+        }
+        if config.role == RelayRole::Hop {
+            let next_hop = config
+                .next_hop
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Chain hop requested without the next hop"))?;
+            if matches!(config.transport_type, TransportType::Encrypted) && next_hop.peer.is_none() {
+                anyhow::bail!("Encrypted chain hop requested without the next client's identity key");
+            }
+        }
+        if config.multiplex && config.tunnel_addr.is_none() {
+            anyhow::bail!("Multiplexed relay requested without a tunnel address");
+        }
+        if matches!(config.transport_type, TransportType::Tls)
+            && config.role != RelayRole::Entry
+            && !self.encryption_manager.has_tls_acceptor()
+        {
+            anyhow::bail!("TLS relay exit needs transport.tls.cert_file and key_file");
+        }
+
+        let running = self
+            .active_relays
+            .read()
+            .await
+            .get(&relay_id)
+            .map(|relay| (same_relay(&relay.connection.config, &config), relay.connection.listen_addr));
+        match running {
+            // A repeated or redelivered start leaves the relay running as it is
+            Some((true, listen_addr)) => {
+                tracing::info!("Relay {} is already running on {}", relay_id, listen_addr);
+                return Ok(listen_addr);
+            }
+            // A changed one replaces it, once the old listener is closed and its address free
+            Some((false, _)) => {
+                if let Some(previous) = self.active_relays.write().await.remove(&relay_id) {
+                    let report = previous.connection.stop(previous.task, self.stop_grace).await;
+                    tracing::info!(
+                        "Stopped relay {} to restart it ({} connections drained, {} aborted)",
+                        relay_id,
+                        report.drained,
+                        report.aborted
+                    );
+                }
+            }
+            None => {}
+        }
 
         tracing::info!(
-            "Starting relay: {} -> {} (transport: {:?})",
+            "Starting relay {:?} end: {} -> {} (transport: {:?}, tunnel: {:?})",
+            config.role,
             config.entry_point,
             config.exit_point,
-            config.transport_type
+            config.transport_type,
+            config.tunnel_addr
         );
 
-        let connection = RelayConnection::new(config, &self.encryption_manager).await?;
+        let connection = Arc::new(RelayConnection::new(config, &self.encryption_manager).await?);
+        let listen_addr = connection.listen_addr;
 
-        // Store the relay connection
-        self.active_relays.write().await.insert(relay_id.clone(), connection);
+        // Hold the lock until the entry is stored, so a failing accept loop can't look for it first
+        let mut relays = self.active_relays.write().await;
 
         // Start the relay handler in background
-        let relays = self.active_relays.clone();
-        let id = relay_id.clone();
-
-        tokio::spawn(async move {
-            if let Some(relay) = relays.read().await.get(&id) {
-                if let Err(e) = relay.run().await {
-                    tracing::error!("Relay {} failed: {}", id, e);
-                    // Remove failed relay
-                    relays.write().await.remove(&id);
+        let task = tokio::spawn({
+            let relays = self.active_relays.clone();
+            let connection = connection.clone();
+            let relay_id = relay_id.clone();
+            async move {
+                if let Err(e) = connection.run().await {
+                    tracing::error!("Relay {} failed: {}", relay_id, e);
+                    // Remove the failed relay unless it has already been replaced
+                    let mut relays = relays.write().await;
+                    if relays.get(&relay_id).is_some_and(|r| Arc::ptr_eq(&r.connection, &connection)) {
+                        relays.remove(&relay_id);
+                    }
                 }
             }
         });
 
-        Ok(())
+        if let Some(previous) = relays.insert(relay_id, ActiveRelay { connection, task }) {
+            // Another start of the same relay got in while this one was binding elsewhere
+            tokio::spawn(previous.connection.stop(previous.task, self.stop_grace));
+        }
+
+        Ok(listen_addr)
     }
 
-    pub async fn stop_relay(&self, entry_point: &str, exit_point: &str) -> Result<()> {
-        let relay_id = format!("{}:{}", entry_point, exit_point);
+    /// Close the relay's listener, then give its connections the grace period to finish
+    /// before cutting them
+    pub async fn stop_relay(&self, config: &RelayConfig) -> Result<StopReport> {
+        let relay_id = relay_id(config);
 
-        if let Some(_) = self.active_relays.write().await.remove(&relay_id) {
-            tracing::info!("Stopped relay: {}", relay_id);
-        }
+        let relay = self
+            .active_relays
+            .write()
+            .await
+            .remove(&relay_id)
+            .ok_or_else(|| anyhow::anyhow!("Relay {} is not running", relay_id))?;
+
+        let report = relay.connection.stop(relay.task, self.stop_grace).await;
+        tracing::info!(
+            "Stopped relay: {} ({} connections drained, {} aborted)",
+            report.relay_id,
+            report.drained,
+            report.aborted
+        );
+        Ok(report)
+    }
 
+    /// Change a running relay's bandwidth caps, for its open connections too
+    pub async fn set_bandwidth(&self, config: &RelayConfig) -> Result<()> {
+        let relay_id = relay_id(config);
+        let relays = self.active_relays.read().await;
+        let relay = relays
+            .get(&relay_id)
+            .ok_or_else(|| anyhow::anyhow!("Relay {} is not running", relay_id))?;
+
+        relay.connection.shaping.set(
+            config.bandwidth.unwrap_or_default(),
+            config.source_bandwidth.unwrap_or_default(),
+        );
+        tracing::info!(
+            "Set bandwidth of relay {}: {:?}, per source {:?}",
+            relay_id,
+            config.bandwidth,
+            config.source_bandwidth
+        );
         Ok(())
     }
+}
 
-    #[allow(dead_code)]
-    pub async fn list_active_relays(&self) -> Vec<String> {
-        self.active_relays.read().await.keys().cloned().collect()
+/// Key for a relay in `active_relays`; a client can run both ends of the same relay, but
+/// appears in a chain only once
+fn relay_id(config: &RelayConfig) -> String {
+    if let Some(chain_id) = &config.chain_id {
+        return format!("chain {}", chain_id);
+    }
+
+    match config.role {
+        RelayRole::Entry => format!("{}:{}", config.entry_point, config.exit_point),
+        RelayRole::Hop | RelayRole::Exit => format!("{}:{} (exit)", config.entry_point, config.exit_point),
+    }
+}
+
+/// Whether two starts describe the same relay, down to every setting
+fn same_relay(a: &RelayConfig, b: &RelayConfig) -> bool {
+    matches!((serde_json::to_value(a), serde_json::to_value(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Bind a listener on `addr` as written in a relay config: an IPv4 address, a bracketed IPv6
+/// address or a hostname, with port 0 leaving the choice to the OS
+async fn bind_listener(addr: &str, what: &str) -> Result<TcpListener> {
+    let candidates = tokio::net::lookup_host(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Invalid relay {} {:?}: {}", what, addr, e))?;
+
+    let mut last_error = None;
+    for candidate in candidates {
+        match TcpListener::bind(candidate).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => anyhow::bail!("Failed to bind relay {} {}: {}", what, addr, e),
+        None => anyhow::bail!("Relay {} {} resolves to no addresses", what, addr),
     }
 }
 
 pub struct RelayConnection {
     config: RelayConfig,
-    listener: Option<TcpListener>,
-    #[allow(dead_code)]
+    /// Taken by `run`, so the port is released as soon as the accept loop ends
+    listener: std::sync::Mutex<Option<TcpListener>>,
+    listen_addr: SocketAddr,
     encryption_manager: EncryptionManager,
+    /// Shared tunnel of a multiplexed entry end
+    tunnel: Arc<MuxTunnel>,
+    /// Where this end forwards traffic when it is the one dialing `exit_point`
+    exits: Arc<ExitPool>,
+    /// Bandwidth caps, per source by the address connecting to this end
+    shaping: Shaping,
+    /// Ends the accept loop
+    shutdown: CancellationToken,
+    connections: Connections,
+}
+
+/// In-flight connections of a relay, which a stop first drains and then aborts
+#[derive(Clone, Default)]
+struct Connections {
+    tracker: TaskTracker,
+    abort: CancellationToken,
+}
+
+impl Connections {
+    fn spawn<F>(&self, connection: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let abort = self.abort.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                result = connection => {
+                    if let Err(e) = result {
+                        tracing::error!("Relay connection error: {}", e);
+                    }
+                }
+                _ = abort.cancelled() => tracing::debug!("Relay connection aborted"),
+            }
+        });
+    }
 }
 
 impl RelayConnection {
-    pub async fn new(config: RelayConfig, _encryption_manager: &EncryptionManager) -> Result<Self> {
-        // Parse entry point to start listening
-        let listener = if config.entry_point.starts_with("0.0.0.0:") || config.entry_point.starts_with("127.0.0.1:") {
-            let addr: SocketAddr = config.entry_point.parse()?;
-            Some(TcpListener::bind(addr).await?)
-        } else {
-            None
+    pub async fn new(config: RelayConfig, encryption_manager: &EncryptionManager) -> Result<Self> {
+        let exits = Arc::new(ExitPool::new(&config)?);
+
+        let listener = match config.role {
+            RelayRole::Entry => bind_listener(&config.entry_point, "entry point").await?,
+            // Hops and the exit listen for the tunnel from the client before them
+            RelayRole::Hop | RelayRole::Exit => {
+                let addr = config
+                    .tunnel_addr
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("Relay {:?} has no tunnel address", config.role))?;
+                bind_listener(addr, "tunnel address").await?
+            }
         };
 
+        let listen_addr = listener.local_addr()?;
+        let shaping = Shaping::new(config.bandwidth.unwrap_or_default(), config.source_bandwidth.unwrap_or_default());
+
         Ok(Self {
             config,
-            listener,
-            encryption_manager: EncryptionManager::new(),
+            listener: std::sync::Mutex::new(Some(listener)),
+            listen_addr,
+            encryption_manager: encryption_manager.clone(),
+            tunnel: Arc::new(MuxTunnel::default()),
+            exits,
+            shaping,
+            shutdown: CancellationToken::new(),
+            connections: Connections::default(),
         })
     }
 
+    /// Accept connections until the relay is stopped
     pub async fn run(&self) -> Result<()> {
-        if let Some(listener) = &self.listener {
-            tracing::info!("Relay listening on {}", self.config.entry_point);
+        let listener = self
+            .listener
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Relay is already running"))?;
+        tracing::info!("Relay {:?} end listening on {}", self.config.role, self.listen_addr);
+
+        // Only the end that dials the exits probes them
+        let dials_exits = self.config.role == RelayRole::Exit
+            || (self.config.role == RelayRole::Entry && self.config.tunnel_addr.is_none());
+        let multiplexed_entry = self.config.role == RelayRole::Entry && self.config.multiplex;
 
-            loop {
-                let (inbound, peer_addr) = listener.accept().await?;
-                tracing::debug!("New relay connection from {}", peer_addr);
+        tokio::select! {
+            result = self.accept_loop(listener) => result,
+            _ = self.exits.probe(), if dials_exits => Ok(()),
+            _ = self.tunnel.keep_up(&self.config, &self.encryption_manager), if multiplexed_entry => Ok(()),
+        }
+    }
 
-                let config = self.config.clone();
-                let encryption_manager = EncryptionManager::new();
-                tokio::spawn(async move {
-                    if let Err(e) = Self::handle_relay_connection(inbound, config, encryption_manager).await {
-                        tracing::error!("Relay connection error: {}", e);
+    async fn accept_loop(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (inbound, peer_addr) = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted?,
+            };
+            tracing::debug!("New relay connection from {}", peer_addr);
+
+            let config = self.config.clone();
+            let encryption_manager = self.encryption_manager.clone();
+            let tunnel = self.tunnel.clone();
+            let exits = self.exits.clone();
+            let connections = self.connections.clone();
+            let shaper = self.shaping.shaper(peer_addr.ip());
+            self.connections.spawn(async move {
+                match config.role {
+                    RelayRole::Entry if config.multiplex => {
+                        let outbound = tunnel.open_stream(&config, &encryption_manager).await?;
+                        Self::relay_traffic(inbound, outbound, shaper).await
                     }
-                });
-            }
-        } else {
-            // This is an outbound-only relay, wait for connections from other clients
-            tracing::info!("Relay configured for outbound connections to {}", self.config.exit_point);
-            futures_util::future::pending::<()>().await;
+                    RelayRole::Entry if config.tunnel_addr.is_none() => {
+                        let outbound = exits.connect().await?;
+                        Self::relay_traffic(inbound, outbound, shaper).await
+                    }
+                    RelayRole::Entry => Self::handle_relay_connection(inbound, config, encryption_manager, shaper).await,
+                    RelayRole::Hop => Self::handle_hop_connection(inbound, config, encryption_manager, shaper).await,
+                    RelayRole::Exit => {
+                        Self::handle_tunnel_connection(inbound, config, encryption_manager, exits, connections, shaper)
+                            .await
+                    }
+                }
+            });
         }
 
         Ok(())
     }
 
+    /// End the accept loop, wait up to `grace` for open connections to finish, then abort the rest
+    async fn stop(self: Arc<Self>, accept_loop: JoinHandle<()>, grace: Duration) -> StopReport {
+        self.shutdown.cancel();
+        // The listener is dropped with the loop
+        let _ = accept_loop.await;
+
+        let tracker = &self.connections.tracker;
+        tracker.close();
+        let open = tracker.len();
+
+        let aborted = if tokio::time::timeout(grace, tracker.wait()).await.is_ok() {
+            0
+        } else {
+            let remaining = tracker.len();
+            self.connections.abort.cancel();
+            tracker.wait().await;
+            remaining
+        };
+
+        StopReport {
+            relay_id: relay_id(&self.config),
+            listener: self.listen_addr.to_string(),
+            drained: open - aborted,
+            aborted,
+        }
+    }
+
     async fn handle_relay_connection(
         inbound: TcpStream,
         config: RelayConfig,
         encryption_manager: EncryptionManager,
+        shaper: Shaper,
     ) -> Result<()> {
-        // Connect to exit point
-        let outbound = match config.transport_type {
-            TransportType::Direct => {
-                Self::connect_direct(&config.exit_point).await?
-            }
+        // Connect through the tunnel and start bidirectional relay
+        let outbound = Self::connect_tunnel(&config, &encryption_manager).await?;
+        Self::relay_traffic(inbound, outbound, shaper).await
+    }
+
+    /// Dial the exit end over the relay's transport, adding the inner layer to the far end
+    /// of a chain
+    async fn connect_tunnel(config: &RelayConfig, encryption_manager: &EncryptionManager) -> Result<Box<dyn TunnelIo>> {
+        let target = config
+            .tunnel_addr
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Relay has no tunnel address"))?;
+        let link = Self::connect_link(target, config.peer.as_ref(), &config.transport_type, encryption_manager).await?;
+
+        match &config.chain_peer {
+            Some(chain_peer) => Ok(Box::new(
+                encryption_manager
+                    .encrypt_relay_stream(link, chain_peer, StreamRole::Initiator)
+                    .await?,
+            )),
+            None => Ok(link),
+        }
+    }
+
+    /// Dial the next client over the relay's transport, authenticating it as `peer` on
+    /// encrypted relays
+    async fn connect_link(
+        target: &str,
+        peer: Option<&RelayPeer>,
+        transport_type: &TransportType,
+        encryption_manager: &EncryptionManager,
+    ) -> Result<Box<dyn TunnelIo>> {
+        Ok(match transport_type {
+            TransportType::Direct => Box::new(Self::connect_direct(target).await?),
             TransportType::Encrypted => {
-                Self::connect_encrypted(&config.exit_point, &encryption_manager).await?
+                let peer = peer.ok_or_else(|| anyhow::anyhow!("Encrypted relay has no peer identity"))?;
+                Box::new(Self::connect_encrypted(target, peer, encryption_manager).await?)
             }
-            TransportType::WebSocket => {
-                Self::connect_websocket(&config.exit_point).await?
+            TransportType::WebSocket => Box::new(Self::connect_websocket(target).await?),
+            TransportType::Tls => Box::new(Self::connect_tls(target, encryption_manager).await?),
+        })
+    }
+
+    /// Unwrap the relay's transport on a connection from the previous client
+    async fn accept_link(
+        stream: TcpStream,
+        peer: Option<&RelayPeer>,
+        transport_type: &TransportType,
+        encryption_manager: &EncryptionManager,
+    ) -> Result<Box<dyn TunnelIo>> {
+        Ok(match transport_type {
+            TransportType::Encrypted => {
+                let peer = peer.ok_or_else(|| anyhow::anyhow!("Encrypted relay has no peer identity"))?;
+                Box::new(encryption_manager.encrypt_relay_stream(stream, peer, StreamRole::Responder).await?)
             }
-        };
+            TransportType::WebSocket => Box::new(websocket::accept_stream(stream).await?),
+            TransportType::Tls => Box::new(encryption_manager.accept_tls(stream).await?),
+            TransportType::Direct => Box::new(stream),
+        })
+    }
 
-        // Start bidirectional relay
-        Self::relay_traffic(inbound, outbound).await?;
+    /// Exit end: unwrap a tunnel connection from the entry client and forward it to the exit point
+    async fn handle_tunnel_connection(
+        tunnel: TcpStream,
+        config: RelayConfig,
+        encryption_manager: EncryptionManager,
+        exits: Arc<ExitPool>,
+        connections: Connections,
+        shaper: Shaper,
+    ) -> Result<()> {
+        let mut tunnel = Self::accept_link(tunnel, config.peer.as_ref(), &config.transport_type, &encryption_manager).await?;
+
+        if let Some(chain_peer) = &config.chain_peer {
+            tunnel = Box::new(
+                encryption_manager
+                    .encrypt_relay_stream(tunnel, chain_peer, StreamRole::Responder)
+                    .await?,
+            );
+        }
+
+        if config.multiplex {
+            return Self::serve_multiplexed(tunnel, config.exit_point, exits, connections, shaper).await;
+        }
+
+        let outbound = exits.connect().await?;
+        Self::relay_traffic(tunnel, outbound, shaper).await
+    }
+
+    /// Middle of a chain: unwrap the link from the previous client and rewrap it for the
+    /// next one. The inner layer between the chain's ends passes through untouched.
+    async fn handle_hop_connection(
+        upstream: TcpStream,
+        config: RelayConfig,
+        encryption_manager: EncryptionManager,
+        shaper: Shaper,
+    ) -> Result<()> {
+        let next_hop = config
+            .next_hop
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Chain hop has no next hop"))?;
+
+        let upstream = Self::accept_link(upstream, config.peer.as_ref(), &config.transport_type, &encryption_manager).await?;
+        let downstream =
+            Self::connect_link(&next_hop.addr, next_hop.peer.as_ref(), &config.transport_type, &encryption_manager).await?;
 
+        Self::relay_traffic(upstream, downstream, shaper).await
+    }
+
+    /// Exit end of a multiplexed tunnel: forward each stream to the exit point until the tunnel closes
+    async fn serve_multiplexed(
+        tunnel: Box<dyn TunnelIo>,
+        exit_point: String,
+        exits: Arc<ExitPool>,
+        connections: Connections,
+        shaper: Shaper,
+    ) -> Result<()> {
+        let mut session = MuxSession::new(tunnel, StreamRole::Responder);
+        tracing::info!("Multiplexed tunnel accepted for {}", exit_point);
+
+        while let Some(stream) = session.accept().await {
+            let exits = exits.clone();
+            let shaper = shaper.clone();
+            connections.spawn(async move {
+                let outbound = exits.connect().await?;
+                Self::relay_traffic(stream, outbound, shaper).await
+            });
+        }
+
+        tracing::info!("Multiplexed tunnel for {} closed", exit_point);
         Ok(())
     }
 
-    async fn connect_direct(exit_point: &str) -> Result<TcpStream> {
-        let addr: SocketAddr = exit_point.parse()?;
-        let stream = TcpStream::connect(addr).await?;
-        tracing::debug!("Direct connection established to {}", exit_point);
+    async fn connect_direct(target: &str) -> Result<TcpStream> {
+        let stream = TcpStream::connect(target).await?;
+        tracing::debug!("Direct connection established to {}", target);
         Ok(stream)
     }
 
-    async fn connect_encrypted(exit_point: &str, _encryption_manager: &EncryptionManager) -> Result<TcpStream> {
-        let addr: SocketAddr = exit_point.parse()?;
-        let stream = TcpStream::connect(addr).await?;
+    async fn connect_encrypted(
+        target: &str,
+        peer: &RelayPeer,
+        encryption_manager: &EncryptionManager,
+    ) -> Result<SymmetricEncryptedStream<TcpStream>> {
+        let stream = TcpStream::connect(target).await?;
 
-        // For now, establish a direct connection but log that encryption is intended
-        // In production, this would use the encryption_manager to wrap the stream
-        tracing::info!("Encrypted connection established to {} (encryption layer ready for implementation)", exit_point);
+        let stream = encryption_manager
+            .encrypt_relay_stream(stream, peer, StreamRole::Initiator)
+            .await?;
 
-        // TODO: Implement actual encryption wrapping:
-        // let key = EncryptionManager::generate_key();
-        // let encryption_config = EncryptionConfig {
-        //     encryption_type: EncryptionType::Aes256Gcm,
-        //     key: Some(key),
-        //     tls_config: None,
-        // };
-        // let encrypted_stream = encryption_manager.wrap_stream(stream, encryption_config).await?;
+        tracing::debug!("Encrypted connection established to {} (client {})", target, peer.client_id);
+        Ok(stream)
+    }
+
+    async fn connect_tls(
+        target: &str,
+        encryption_manager: &EncryptionManager,
+    ) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(target).await?;
+
+        // SNI defaults to the tunnel's host, without the port or IPv6 brackets
+        let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = encryption_manager.connect_tls(stream, host).await?;
 
+        tracing::debug!("TLS connection established to {}", target);
         Ok(stream)
     }
 
-    async fn connect_websocket(exit_point: &str) -> Result<TcpStream> {
+    async fn connect_websocket(exit_point: &str) -> Result<WebSocketByteStream<MaybeTlsStream<TcpStream>>> {
         // Parse the exit point to determine if it's a WebSocket URL or address
         let ws_url = if exit_point.starts_with("ws://") || exit_point.starts_with("wss://") {
             exit_point.to_string()
@@ -190,98 +576,295 @@ impl RelayConnection {
             format!("ws://{}/relay", exit_point)
         };
 
-        tracing::info!("WebSocket transport configured for {}", ws_url);
+        WebSocketTransport::new().connect(&ws_url).await
+    }
 
-        // For now, establish a regular TCP connection but log WebSocket capability
-        // In a full implementation, this would use the WebSocket transport directly
-        // and require modifying the relay_traffic function to handle WebSocket frames
+    async fn relay_traffic<A, B>(inbound: A, outbound: B, shaper: Shaper) -> Result<()>
+    where
+        A: AsyncRead + AsyncWrite + Send + 'static,
+        B: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (ri, wi) = tokio::io::split(inbound);
+        let (ro, wo) = tokio::io::split(outbound);
 
-        // Extract the host:port from the WebSocket URL for fallback TCP connection
-        let tcp_addr = if let Some(host_port) = ws_url.strip_prefix("ws://").and_then(|s| s.split('/').next()) {
-            host_port
-        } else if let Some(host_port) = ws_url.strip_prefix("wss://").and_then(|s| s.split('/').next()) {
-            host_port
-        } else {
-            exit_point
-        };
+        // Both directions run in this task, so aborting it cuts the whole connection
+        tokio::try_join!(
+            pipe(ri, wo, Direction::Upload, &shaper),
+            pipe(ro, wi, Direction::Download, &shaper),
+        )?;
 
-        let addr: SocketAddr = tcp_addr.parse()?;
-        let stream = TcpStream::connect(addr).await?;
+        tracing::debug!("Relay connection closed");
+        Ok(())
+    }
+}
 
-        tracing::info!("WebSocket transport connection established to {} (using TCP fallback)", exit_point);
+/// Entry end of a multiplexed relay: one tunnel to the exit client, redialed when it drops.
+/// Streams open in a tunnel that drops are cut; only later connections get the new tunnel.
+#[derive(Default)]
+struct MuxTunnel {
+    session: Mutex<Option<MuxSession>>,
+    /// Wakes `keep_up` once the first tunnel is dialed
+    established: Notify,
+}
 
-        // TODO: Full WebSocket implementation would require:
-        // 1. Establishing WebSocket handshake
-        // 2. Creating a WebSocket adapter that implements AsyncRead/AsyncWrite
-        // 3. Wrapping TCP data in WebSocket frames
-        // 4. Handling WebSocket control frames (ping/pong/close)
+impl MuxTunnel {
+    const DIAL_ATTEMPTS: u32 = 3;
+    /// Pause between rounds of background redials while the exit client is unreachable
+    const REDIAL_INTERVAL: Duration = Duration::from_secs(5);
 
-        Ok(stream)
-    }
+    async fn open_stream(&self, config: &RelayConfig, encryption_manager: &EncryptionManager) -> Result<MuxStream> {
+        // Held across the redial so concurrent connections share the new tunnel
+        let mut session = self.session.lock().await;
 
-    async fn relay_traffic(inbound: TcpStream, outbound: TcpStream) -> Result<()> {
-        let (mut ri, mut wi) = inbound.into_split();
-        let (mut ro, mut wo) = outbound.into_split();
+        if let Some(stream) = session.as_ref().filter(|s| !s.is_closed()).and_then(|s| s.open_stream().ok()) {
+            return Ok(stream);
+        }
 
-        let client_to_server = tokio::spawn(async move {
-            let mut buf = vec![0u8; 8192];
-            let mut total_bytes = 0u64;
+        self.dial(&mut session, config, encryption_manager).await?.open_stream()
+    }
 
-            loop {
-                let n = match ri.read(&mut buf).await {
-                    Ok(0) => break,
-                    Ok(n) => n,
-                    Err(e) => {
-                        tracing::debug!("Read error: {}", e);
-                        break;
-                    }
-                };
+    /// Redial as soon as the keepalives find the tunnel down, instead of waiting for the
+    /// next connection to notice. Runs until the relay stops.
+    async fn keep_up(&self, config: &RelayConfig, encryption_manager: &EncryptionManager) {
+        loop {
+            let closed = self.session.lock().await.as_ref().map(MuxSession::closed);
+            match closed {
+                Some(closed) => closed.await,
+                None => {
+                    self.established.notified().await;
+                    continue;
+                }
+            }
+            tracing::warn!("Multiplexed tunnel to {:?} dropped, redialing", config.tunnel_addr);
 
-                if let Err(e) = wo.write_all(&buf[..n]).await {
-                    tracing::debug!("Write error: {}", e);
+            loop {
+                let mut session = self.session.lock().await;
+                // A connection may have redialed first
+                if session.as_ref().is_some_and(|s| !s.is_closed()) {
                     break;
                 }
+                if self.dial(&mut session, config, encryption_manager).await.is_ok() {
+                    break;
+                }
+                drop(session);
+                tokio::time::sleep(Self::REDIAL_INTERVAL).await;
+            }
+        }
+    }
 
-                total_bytes += n as u64;
+    async fn dial<'a>(
+        &self,
+        session: &'a mut Option<MuxSession>,
+        config: &RelayConfig,
+        encryption_manager: &EncryptionManager,
+    ) -> Result<&'a MuxSession> {
+        let mut attempt = 0;
+        let tunnel = loop {
+            attempt += 1;
+            match RelayConnection::connect_tunnel(config, encryption_manager).await {
+                Ok(tunnel) => break tunnel,
+                Err(e) if attempt < Self::DIAL_ATTEMPTS => {
+                    tracing::warn!("Multiplexed tunnel dial failed (attempt {}): {}", attempt, e);
+                    tokio::time::sleep(Duration::from_millis(500 << attempt)).await;
+                }
+                Err(e) => return Err(e),
             }
+        };
 
-            tracing::debug!("Client->Server relay finished, {} bytes transferred", total_bytes);
-            Ok::<_, anyhow::Error>(())
-        });
+        if session.is_some() {
+            tracing::info!("Multiplexed tunnel to {:?} re-established", config.tunnel_addr);
+        } else {
+            tracing::info!("Multiplexed tunnel to {:?} established", config.tunnel_addr);
+            self.established.notify_one();
+        }
 
-        let server_to_client = tokio::spawn(async move {
-            let mut buf = vec![0u8; 8192];
-            let mut total_bytes = 0u64;
+        Ok(session.insert(MuxSession::new(tunnel, StreamRole::Initiator)))
+    }
+}
 
-            loop {
-                let n = match ro.read(&mut buf).await {
-                    Ok(0) => break,
-                    Ok(n) => n,
-                    Err(e) => {
-                        tracing::debug!("Read error: {}", e);
-                        break;
-                    }
-                };
+/// Copy one direction of a relay until EOF or an error, then shut the writer down
+async fn pipe<R, W>(mut reader: R, mut writer: W, direction: Direction, shaper: &Shaper) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    let mut total_bytes = 0u64;
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                tracing::debug!("Read error: {}", e);
+                break;
+            }
+        };
+        shaper.wait(direction, n).await;
 
-                if let Err(e) = wi.write_all(&buf[..n]).await {
-                    tracing::debug!("Write error: {}", e);
-                    break;
-                }
+        // Flush so an encrypted transport doesn't hold back a sealed record
+        if let Err(e) = writer.write_all(&buf[..n]).await.and(writer.flush().await) {
+            tracing::debug!("Write error: {}", e);
+            break;
+        }
 
-                total_bytes += n as u64;
-            }
+        total_bytes += n as u64;
+    }
+
+    let _ = writer.shutdown().await;
+
+    tracing::debug!("{:?} relay finished, {} bytes transferred", direction, total_bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{IdentityKey, TlsConfig};
+    use sentinel_common::Bandwidth;
 
-            tracing::debug!("Server->Client relay finished, {} bytes transferred", total_bytes);
-            Ok::<_, anyhow::Error>(())
+    fn manager(stop_grace: Duration) -> RelayManager {
+        let identity = Arc::new(IdentityKey::generate().unwrap().0);
+        let encryption_manager = EncryptionManager::new(identity, None, &TlsConfig::default()).unwrap();
+        RelayManager::new(encryption_manager, stop_grace)
+    }
+
+    /// Stands in for the relay's exit point
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
         });
+        addr
+    }
 
-        // Wait for either direction to complete
-        tokio::select! {
-            r1 = client_to_server => r1??,
-            r2 = server_to_client => r2??,
+    fn direct_relay(entry_point: &str, exit_point: SocketAddr) -> RelayConfig {
+        RelayConfig {
+            entry_point: entry_point.to_string(),
+            exit_point: exit_point.to_string(),
+            transport_type: TransportType::Direct,
+            peer: None,
+            role: RelayRole::Entry,
+            tunnel_addr: None,
+            multiplex: false,
+            chain_id: None,
+            next_hop: None,
+            chain_peer: None,
+            fallback_exits: Vec::new(),
+            exit_probe: None,
+            bandwidth: None,
+            source_bandwidth: None,
         }
+    }
 
-        tracing::debug!("Relay connection closed");
-        Ok(())
+    /// Open a connection through the relay and check it reaches the exit
+    async fn connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_stop_drains_then_aborts() {
+        let exit = echo_server().await;
+        let relays = Arc::new(manager(Duration::from_millis(500)));
+        let config = direct_relay("127.0.0.1:0", exit);
+
+        // A connection that finishes within the grace period is drained
+        let addr = relays.start_relay(config.clone()).await.unwrap();
+        let client = connect(addr).await;
+        let stop = tokio::spawn({
+            let relays = relays.clone();
+            let config = config.clone();
+            async move { relays.stop_relay(&config).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addr).await.is_err(), "listener still open during the grace period");
+        drop(client);
+        let report = stop.await.unwrap().unwrap();
+        assert_eq!((report.drained, report.aborted), (1, 0));
+
+        // One still open when the grace period runs out is cut
+        let addr = relays.start_relay(config.clone()).await.unwrap();
+        let mut client = connect(addr).await;
+        let report = relays.stop_relay(&config).await.unwrap();
+        assert_eq!((report.drained, report.aborted), (0, 1));
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap_or(0), 0);
+
+        assert!(relays.stop_relay(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restart_relay() {
+        let exit = echo_server().await;
+        let relays = manager(Duration::from_millis(100));
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let config = direct_relay(&format!("127.0.0.1:{}", port), exit);
+
+        // Starting the same relay again, as a redelivered task does, keeps it running
+        let addr = relays.start_relay(config.clone()).await.unwrap();
+        let _client = connect(addr).await;
+        assert_eq!(relays.start_relay(config.clone()).await.unwrap(), addr);
+        connect(addr).await;
+
+        // A changed one takes over the same address
+        let changed = RelayConfig {
+            bandwidth: Some(Bandwidth::symmetric(100)),
+            ..config
+        };
+        assert_eq!(relays.start_relay(changed.clone()).await.unwrap(), addr);
+        connect(addr).await;
+        let report = relays.stop_relay(&changed).await.unwrap();
+        assert_eq!(report.listener, addr.to_string());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_multiplexed_tunnel_redial() {
+        let exit = echo_server().await;
+        let relays = manager(Duration::from_millis(100));
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let entry = RelayConfig {
+            tunnel_addr: Some(format!("127.0.0.1:{}", port)),
+            multiplex: true,
+            ..direct_relay("127.0.0.1:0", exit)
+        };
+        let exit_end = RelayConfig {
+            role: RelayRole::Exit,
+            ..entry.clone()
+        };
+
+        relays.start_relay(exit_end.clone()).await.unwrap();
+        let addr = relays.start_relay(entry.clone()).await.unwrap();
+        let mut client = connect(addr).await;
+
+        let tunnel = relays.active_relays.read().await[&relay_id(&entry)].connection.tunnel.clone();
+        let closed = tunnel.session.lock().await.as_ref().unwrap().closed();
+
+        // Killing the tunnel cuts the streams inside it
+        relays.stop_relay(&exit_end).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap_or(0), 0);
+        tokio::time::timeout(Duration::from_secs(1), closed).await.unwrap();
+
+        // The entry end redials on its own once the exit client is back
+        relays.start_relay(exit_end.clone()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while tunnel.session.lock().await.as_ref().is_none_or(|s| s.is_closed()) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        connect(addr).await;
+    }
+}
@@ -0,0 +1,302 @@
+use anyhow::{Context, Result};
+use sentinel_common::ConfigUpdate;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::Config;
+use crate::monitor::MetricsReporter;
+use crate::proxy::ProxyRegistry;
+use crate::register::RegistrationManager;
+
+pub type LogReloadHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Log filter for a `logging.level` value, scoped to our own crates
+pub fn log_filter(level: &str) -> EnvFilter {
+    EnvFilter::new(format!("sentinel={}", level))
+}
+
+/// Settings touched by an `UpdateConfig` task, reported back as the task result
+#[derive(Debug, Default, Serialize)]
+pub struct UpdateOutcome {
+    pub applied: Vec<&'static str>,
+    pub restart_required: Vec<&'static str>,
+    /// Stored in config.toml, but not used by this client
+    pub unsupported: Vec<&'static str>,
+}
+
+/// Applies `UpdateConfig` tasks to the running client and writes them back to config.toml
+pub struct SettingsManager {
+    config_path: PathBuf,
+    config: Mutex<Config>,
+    registration: Arc<RegistrationManager>,
+    reporter: Option<Arc<MetricsReporter>>,
+    proxy_registry: Arc<ProxyRegistry>,
+    log_reload: LogReloadHandle,
+}
+
+impl SettingsManager {
+    pub fn new(
+        config_path: &str,
+        config: Config,
+        registration: Arc<RegistrationManager>,
+        reporter: Option<Arc<MetricsReporter>>,
+        proxy_registry: Arc<ProxyRegistry>,
+        log_reload: LogReloadHandle,
+    ) -> Self {
+        Self {
+            config_path: resolve_config_path(config_path),
+            config: Mutex::new(config),
+            registration,
+            reporter,
+            proxy_registry,
+            log_reload,
+        }
+    }
+
+    /// Validate and persist an update, then apply whatever can change without a restart
+    pub async fn apply(&self, update: &ConfigUpdate) -> Result<UpdateOutcome> {
+        update.validate()?;
+
+        let mut config = self.config.lock().await;
+        let mut outcome = UpdateOutcome::default();
+
+        // Write the file first so a failed write leaves the running client untouched
+        self.persist(update)?;
+
+        if let Some(interval) = update.server.as_ref().and_then(|s| s.heartbeat_interval) {
+            config.server.heartbeat_interval = interval;
+            self.registration.set_heartbeat_interval(Duration::from_secs(interval));
+            outcome.applied.push("server.heartbeat_interval");
+        }
+
+        if let Some(monitoring) = &update.monitoring {
+            if let Some(enabled) = monitoring.enabled {
+                // The metrics reporter is only spawned at startup
+                if enabled != config.monitoring.enabled {
+                    outcome.restart_required.push("monitoring.enabled");
+                } else {
+                    outcome.applied.push("monitoring.enabled");
+                }
+                config.monitoring.enabled = enabled;
+            }
+
+            if let Some(interval) = monitoring.report_interval {
+                config.monitoring.report_interval = interval;
+                if let Some(reporter) = &self.reporter {
+                    reporter.set_interval(Duration::from_secs(interval));
+                }
+                outcome.applied.push("monitoring.report_interval");
+            }
+
+            // Metrics are sampled when they are reported; nothing reads this yet
+            if let Some(interval) = monitoring.collect_interval {
+                config.monitoring.collect_interval = interval;
+                outcome.unsupported.push("monitoring.collect_interval");
+            }
+        }
+
+        if let Some(limits) = &update.limits {
+            if let Some(max) = limits.max_connections {
+                config.limits.max_connections = max;
+                outcome.applied.push("limits.max_connections");
+            }
+            if let Some(max) = limits.max_connections_per_source {
+                config.limits.max_connections_per_source = max;
+                outcome.applied.push("limits.max_connections_per_source");
+            }
+            if let Some(rate) = limits.accept_rate_per_source {
+                config.limits.accept_rate_per_source = rate;
+                outcome.applied.push("limits.accept_rate_per_source");
+            }
+            self.proxy_registry.set_limits(config.limits.connection_limits());
+
+            // The bandwidth cap of `[limits]` governs the proxy started from `[proxy]`
+            if let Some(mbps) = limits.rate_limit_mbps {
+                config.limits.rate_limit_mbps = mbps;
+                outcome.applied.push("limits.rate_limit_mbps");
+
+                if config.proxy.is_some() && self.proxy_registry.config("default").await.is_some() {
+                    self.proxy_registry.set_rate_limit("default", mbps).await?;
+                }
+            }
+        }
+
+        if let Some(level) = update.logging.as_ref().and_then(|l| l.level.as_ref()) {
+            self.log_reload.reload(log_filter(level))?;
+            config.logging.level = level.clone();
+            outcome.applied.push("logging.level");
+        }
+
+        tracing::info!(
+            "Config updated: applied {:?}, restart required for {:?}, unsupported {:?}",
+            outcome.applied,
+            outcome.restart_required,
+            outcome.unsupported
+        );
+        Ok(outcome)
+    }
+
+    /// Write the updated values into config.toml, keeping its comments and layout
+    fn persist(&self, update: &ConfigUpdate) -> Result<()> {
+        let text = std::fs::read_to_string(&self.config_path)
+            .with_context(|| format!("Failed to read {}", self.config_path.display()))?;
+        let mut doc: toml_edit::DocumentMut = text.parse()?;
+
+        if let Some(server) = &update.server {
+            if let Some(interval) = server.heartbeat_interval {
+                doc["server"]["heartbeat_interval"] = toml_edit::value(interval as i64);
+            }
+        }
+
+        if let Some(monitoring) = &update.monitoring {
+            if let Some(enabled) = monitoring.enabled {
+                doc["monitoring"]["enabled"] = toml_edit::value(enabled);
+            }
+            if let Some(interval) = monitoring.report_interval {
+                doc["monitoring"]["report_interval"] = toml_edit::value(interval as i64);
+            }
+            if let Some(interval) = monitoring.collect_interval {
+                doc["monitoring"]["collect_interval"] = toml_edit::value(interval as i64);
+            }
+        }
+
+        if let Some(limits) = &update.limits {
+            if let Some(max) = limits.max_connections {
+                doc["limits"]["max_connections"] = toml_edit::value(max as i64);
+            }
+            if let Some(max) = limits.max_connections_per_source {
+                doc["limits"]["max_connections_per_source"] = toml_edit::value(max as i64);
+            }
+            if let Some(rate) = limits.accept_rate_per_source {
+                doc["limits"]["accept_rate_per_source"] = toml_edit::value(rate as i64);
+            }
+            if let Some(mbps) = limits.rate_limit_mbps {
+                doc["limits"]["rate_limit_mbps"] = toml_edit::value(mbps as i64);
+            }
+        }
+
+        if let Some(level) = update.logging.as_ref().and_then(|l| l.level.as_ref()) {
+            doc["logging"]["level"] = toml_edit::value(level.as_str());
+        }
+
+        // Replace the file in one step so a crash can't leave it half written
+        let tmp_path = self.config_path.with_extension("toml.tmp");
+        std::fs::write(&tmp_path, doc.to_string())
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.config_path)
+            .with_context(|| format!("Failed to replace {}", self.config_path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// `Config::from_file` accepts a path without its `.toml` extension; find the real file
+fn resolve_config_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.extension().is_none() {
+        let with_ext = path.with_extension("toml");
+        if with_ext.exists() {
+            return with_ext;
+        }
+    }
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::IdentityKey;
+    use sentinel_common::{ClientInfo, LimitsConfigUpdate, LoggingConfigUpdate, ServerConfigUpdate, SystemInfo};
+
+    fn registration(proxy_registry: Arc<ProxyRegistry>, state_file: PathBuf) -> Arc<RegistrationManager> {
+        let client_info = ClientInfo {
+            id: String::new(),
+            hostname: "test".to_string(),
+            ip: "127.0.0.1".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: vec![],
+            system_info: SystemInfo {
+                os: "linux".to_string(),
+                kernel_version: String::new(),
+                cpu_cores: 1,
+                total_memory: 0,
+                total_disk: 0,
+            },
+        };
+        let (identity, _) = IdentityKey::generate().unwrap();
+        let (task_tx, _) = tokio::sync::mpsc::unbounded_channel();
+
+        Arc::new(RegistrationManager::new(
+            client_info,
+            Arc::new(identity),
+            "http://127.0.0.1:8080".to_string(),
+            Duration::from_secs(30),
+            state_file,
+            task_tx,
+            proxy_registry,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_apply_update() {
+        let dir = std::env::temp_dir().join(format!("sentinel-settings-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml"), &path).unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let config = Config::from_file(path_str).unwrap();
+        let proxy_registry = Arc::new(ProxyRegistry::new(config.limits.connection_limits()));
+        let registration = registration(proxy_registry.clone(), dir.join("client-state.json"));
+        // The handle stops working once its layer is dropped
+        let (_layer, log_reload): (_, LogReloadHandle) = reload::Layer::new(log_filter("info"));
+        let settings = SettingsManager::new(path_str, config, registration.clone(), None, proxy_registry, log_reload);
+
+        // Only the fields present are touched; 0 turns the connection cap off
+        let update = ConfigUpdate {
+            server: Some(ServerConfigUpdate { heartbeat_interval: Some(45) }),
+            limits: Some(LimitsConfigUpdate { max_connections: Some(0), ..Default::default() }),
+            logging: Some(LoggingConfigUpdate { level: Some("debug".to_string()) }),
+            ..Default::default()
+        };
+        let outcome = settings.apply(&update).await.unwrap();
+        assert_eq!(outcome.applied, vec!["server.heartbeat_interval", "limits.max_connections", "logging.level"]);
+        assert!(outcome.restart_required.is_empty());
+        assert_eq!(registration.heartbeat_interval(), Duration::from_secs(45));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let doc: toml_edit::DocumentMut = text.parse().unwrap();
+        assert_eq!(doc["server"]["heartbeat_interval"].as_integer(), Some(45));
+        assert_eq!(doc["limits"]["max_connections"].as_integer(), Some(0));
+        assert_eq!(doc["limits"]["max_connections_per_source"].as_integer(), Some(0));
+        assert_eq!(doc["logging"]["level"].as_str(), Some("debug"));
+        assert_eq!(doc["monitoring"]["report_interval"].as_integer(), Some(30));
+        // Comments in the file survive the rewrite
+        assert!(text.contains("# Connection caps across all proxies; 0 turns a limit off"));
+        assert!(!path.with_extension("toml.tmp").exists());
+
+        let reloaded = Config::from_file(path_str).unwrap();
+        assert_eq!(reloaded.server.heartbeat_interval, 45);
+        assert_eq!(reloaded.limits.max_connections, 0);
+        assert_eq!(reloaded.logging.level, "debug");
+
+        // A rejected update leaves both the file and the running client as they were
+        let update = ConfigUpdate {
+            server: Some(ServerConfigUpdate { heartbeat_interval: Some(60) }),
+            logging: Some(LoggingConfigUpdate { level: Some("loud".to_string()) }),
+            ..Default::default()
+        };
+        assert!(settings.apply(&update).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        assert_eq!(registration.heartbeat_interval(), Duration::from_secs(45));
+        let config = settings.config.lock().await;
+        assert_eq!(config.server.heartbeat_interval, 45);
+        assert_eq!(config.logging.level, "debug");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
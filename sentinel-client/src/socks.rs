@@ -0,0 +1,228 @@
+use anyhow::Result;
+use sentinel_common::ProxyAuth;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes
+pub const SUCCEEDED: u8 = 0x00;
+pub const GENERAL_FAILURE: u8 = 0x01;
+pub const NOT_ALLOWED: u8 = 0x02;
+pub const NETWORK_UNREACHABLE: u8 = 0x03;
+pub const HOST_UNREACHABLE: u8 = 0x04;
+pub const CONNECTION_REFUSED: u8 = 0x05;
+pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Destination {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Addr(_) => None,
+            Self::Domain(name, _) => Some(name),
+        }
+    }
+
+    /// The addresses to try, looking a domain up
+    pub async fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Addr(addr) => Ok(vec![*addr]),
+            Self::Domain(name, port) => Ok(tokio::net::lookup_host((name.as_str(), *port)).await?.collect()),
+        }
+    }
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Addr(addr) => write!(f, "{}", addr),
+            Self::Domain(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Connect(Destination),
+    /// Where the client will send its datagrams from, often left unspecified
+    UdpAssociate(Destination),
+    /// Anything else, already refused
+    Unsupported,
+}
+
+/// Negotiate authentication (none, or RFC 1929 username/password) and read the client's
+/// RFC 1928 request. A client that fails authentication has been told so and gets an error.
+pub async fn handshake<S>(stream: &mut S, auth: Option<&ProxyAuth>) -> Result<Request>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        anyhow::bail!("Not a SOCKS5 client (version {})", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if auth.is_some() { METHOD_PASSWORD } else { METHOD_NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        anyhow::bail!("SOCKS5 client offered no acceptable authentication method");
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some(auth) = auth {
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).await?;
+        if version[0] != AUTH_VERSION {
+            anyhow::bail!("Unknown SOCKS5 authentication version {}", version[0]);
+        }
+        let username = read_short(stream).await?;
+        let password = read_short(stream).await?;
+
+        let valid = constant_time_eq(&username, auth.username.as_bytes()) & constant_time_eq(&password, auth.password.as_bytes());
+        stream.write_all(&[AUTH_VERSION, if valid { 0x00 } else { 0x01 }]).await?;
+        if !valid {
+            anyhow::bail!("SOCKS5 authentication failed for user {:?}", String::from_utf8_lossy(&username));
+        }
+    }
+
+    let mut request = [0u8; 3];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        anyhow::bail!("Bad SOCKS5 request version {}", request[0]);
+    }
+
+    let destination = match read_destination(stream).await? {
+        Some(destination) => destination,
+        None => {
+            reply(stream, ADDRESS_NOT_SUPPORTED, unspecified()).await?;
+            return Ok(Request::Unsupported);
+        }
+    };
+
+    Ok(match request[1] {
+        CMD_CONNECT => Request::Connect(destination),
+        CMD_UDP_ASSOCIATE => Request::UdpAssociate(destination),
+        _ => {
+            reply(stream, COMMAND_NOT_SUPPORTED, unspecified()).await?;
+            Request::Unsupported
+        }
+    })
+}
+
+/// Answer the request, with the address the proxy bound for it
+pub async fn reply<S>(stream: &mut S, code: u8, bound: SocketAddr) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![VERSION, code, 0x00];
+    encode_addr(&mut buf, bound);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reply code for a failed connection to the destination
+pub fn error_code(e: &std::io::Error) -> u8 {
+    match e.kind() {
+        std::io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        std::io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::TimedOut => HOST_UNREACHABLE,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+/// Split a client's UDP datagram into its destination and payload; None for fragments
+/// and malformed headers, which are dropped
+pub fn parse_udp_datagram(datagram: &[u8]) -> Option<(Destination, &[u8])> {
+    // RSV(2) FRAG(1)
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let mut cursor = std::io::Cursor::new(&datagram[3..]);
+    let destination = futures_util::FutureExt::now_or_never(read_destination(&mut cursor))?.ok()??;
+    let offset = 3 + cursor.position() as usize;
+    Some((destination, &datagram[offset..]))
+}
+
+/// Wrap a datagram from `source` for the client
+pub fn encode_udp_datagram(source: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 22);
+    buf.extend_from_slice(&[0, 0, 0]);
+    encode_addr(&mut buf, source);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn unspecified() -> SocketAddr {
+    (Ipv4Addr::UNSPECIFIED, 0).into()
+}
+
+async fn read_short<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let len = stream.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// ATYP, address and port; None for an unknown address type
+async fn read_destination<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Destination>> {
+    let atyp = stream.read_u8().await?;
+    let ip = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv4Addr::from(octets))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            let name = String::from_utf8(read_short(stream).await?)?;
+            let port = stream.read_u16().await?;
+            return Ok(Some(Destination::Domain(name, port)));
+        }
+        _ => return Ok(None),
+    };
+    let port = stream.read_u16().await?;
+    Ok(Some(Destination::Addr(SocketAddr::new(ip, port))))
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Compare secrets without returning early on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
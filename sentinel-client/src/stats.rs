@@ -2,11 +2,25 @@ use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+/// Hosts tracked separately; traffic for any more is counted under `OTHER_HOSTS`
+const MAX_TRACKED_HOSTS: usize = 1000;
+const OTHER_HOSTS: &str = "(other)";
+
 pub struct StatsCollector {
     bytes_sent: AtomicU64,
     bytes_received: AtomicU64,
     connections: AtomicU64,
+    refused: AtomicU64,
     connection_details: RwLock<HashMap<String, ConnectionStats>>,
+    hosts: RwLock<HashMap<String, HostStats>>,
+}
+
+/// Requests and traffic of one host behind an HTTP proxy
+#[derive(Debug, Clone, Default)]
+pub struct HostStats {
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
 }
 
 #[allow(dead_code)]
@@ -33,7 +47,9 @@ impl StatsCollector {
             bytes_sent: AtomicU64::new(0),
             bytes_received: AtomicU64::new(0),
             connections: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
             connection_details: RwLock::new(HashMap::new()),
+            hosts: RwLock::new(HashMap::new()),
         }
     }
 
@@ -52,28 +68,59 @@ impl StatsCollector {
         details.insert(peer, ConnectionStats::new());
     }
 
-    #[allow(dead_code)]
     pub fn close_connection(&self, peer: &str) {
         let mut details = self.connection_details.write();
         details.remove(peer);
     }
 
-    #[allow(dead_code)]
+    pub fn refuse_connection(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_request(&self, host: &str) {
+        self.update_host(host, |stats| stats.requests += 1);
+    }
+
+    pub fn add_host_bytes_sent(&self, host: &str, bytes: usize) {
+        self.update_host(host, |stats| stats.bytes_sent += bytes as u64);
+    }
+
+    pub fn add_host_bytes_received(&self, host: &str, bytes: usize) {
+        self.update_host(host, |stats| stats.bytes_received += bytes as u64);
+    }
+
+    fn update_host(&self, host: &str, update: impl FnOnce(&mut HostStats)) {
+        let mut hosts = self.hosts.write();
+        if let Some(stats) = hosts.get_mut(host) {
+            return update(stats);
+        }
+        // Hosts come from clients, so their number is capped
+        let key = if hosts.len() < MAX_TRACKED_HOSTS { host } else { OTHER_HOSTS };
+        update(hosts.entry(key.to_string()).or_default());
+    }
+
+    pub fn host_stats(&self) -> Vec<(String, HostStats)> {
+        let mut hosts: Vec<_> = self.hosts.read().iter().map(|(host, stats)| (host.clone(), stats.clone())).collect();
+        hosts.sort_by(|a, b| a.0.cmp(&b.0));
+        hosts
+    }
+
     pub fn get_stats(&self) -> Stats {
         Stats {
             bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
             bytes_received: self.bytes_received.load(Ordering::Relaxed),
             total_connections: self.connections.load(Ordering::Relaxed),
             active_connections: self.connection_details.read().len() as u64,
+            refused_connections: self.refused.load(Ordering::Relaxed),
         }
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Stats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub total_connections: u64,
     pub active_connections: u64,
+    pub refused_connections: u64,
 }
\ No newline at end of file
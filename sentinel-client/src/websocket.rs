@@ -1,266 +1,198 @@
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message};
-use tracing;
-
-#[allow(dead_code)]
-pub struct WebSocketTransport;
+use futures_util::{ready, Sink, Stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::{
+    accept_async, connect_async, tungstenite::Error as WsError, tungstenite::Message, MaybeTlsStream,
+    WebSocketStream,
+};
+
+/// How often an idle tunnel pings its peer, so proxies and CDNs don't drop it
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Largest payload sent in a single binary message
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Byte stream carried in binary WebSocket messages, so a relay can tunnel TCP through
+/// anything that passes WebSocket traffic.
+///
+/// WebSocket has no half-close, so shutdown sends an empty binary message as end-of-stream
+/// and keeps reading; the close frame only goes out once both directions are done. A close
+/// frame from the peer also reads as EOF. Pings from the peer are answered by tungstenite;
+/// we send our own on a timer while reading.
+pub struct WebSocketByteStream<S> {
+    inner: WebSocketStream<S>,
+    /// Payload of the last message not yet handed to the reader
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+    read_closed: bool,
+    eof_sent: bool,
+    keepalive: Interval,
+    ping_due: bool,
+}
 
-impl WebSocketTransport {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketByteStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        let mut keepalive = tokio::time::interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            inner,
+            read_buffer: Vec::new(),
+            read_pos: 0,
+            read_closed: false,
+            eof_sent: false,
+            keepalive,
+            ping_due: false,
+        }
     }
 
-    /// Create a WebSocket server that listens for connections
-    #[allow(dead_code)]
-    pub async fn listen(&self, addr: SocketAddr) -> Result<WebSocketListener> {
-        let listener = TcpListener::bind(addr).await?;
-        tracing::info!("WebSocket server listening on {}", addr);
+    /// Send a keepalive ping once one is due and the connection can take it
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) {
+        while self.keepalive.poll_tick(cx).is_ready() {
+            self.ping_due = true;
+        }
 
-        Ok(WebSocketListener { listener })
+        if self.ping_due && !self.read_closed {
+            if let Poll::Ready(Ok(())) = Pin::new(&mut self.inner).poll_ready(cx) {
+                self.ping_due = false;
+                if Pin::new(&mut self.inner).start_send(Message::Ping(Vec::new())).is_ok() {
+                    let _ = Pin::new(&mut self.inner).poll_flush(cx);
+                }
+            }
+        }
     }
+}
 
-    /// Connect to a WebSocket server
-    #[allow(dead_code)]
-    pub async fn connect(&self, url: &str) -> Result<()> {
-        let ws_url = if url.starts_with("ws://") || url.starts_with("wss://") {
-            url.to_string()
-        } else {
-            format!("ws://{}", url)
-        };
-
-        tracing::info!("Connecting to WebSocket server: {}", ws_url);
-
-        let (_ws_stream, _response) = connect_async(&ws_url)
-            .await
-            .map_err(|e| anyhow::anyhow!("WebSocket connection failed: {}", e))?;
-
-        tracing::info!("WebSocket connection established to {}", ws_url);
-
-        // For demo purposes, we log the successful connection
-        // In a real implementation, you'd return a connection object
-        Ok(())
+fn ws_io_error(e: WsError) -> std::io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => std::io::ErrorKind::BrokenPipe.into(),
+        e => std::io::Error::other(e),
     }
 }
 
-#[allow(dead_code)]
-pub struct WebSocketListener {
-    listener: TcpListener,
-}
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketByteStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.poll_keepalive(cx);
+
+        loop {
+            if this.read_pos < this.read_buffer.len() {
+                let available = &this.read_buffer[this.read_pos..];
+                let n = std::cmp::min(available.len(), buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
 
-impl WebSocketListener {
-    #[allow(dead_code)]
-    pub async fn accept(&self) -> Result<(SocketAddr,)> {
-        let (tcp_stream, addr) = self.listener.accept().await?;
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
 
-        tracing::debug!("Accepting WebSocket connection from {}", addr);
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => this.read_closed = true,
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buffer = data;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    this.read_buffer = text.into_bytes();
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => this.read_closed = true,
+                Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => this.read_closed = true,
+                // Pings are answered by tungstenite; pongs and raw frames carry no data
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(ws_io_error(e))),
+            }
+        }
+    }
+}
 
-        let _ws_stream = accept_async(tcp_stream)
-            .await
-            .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketByteStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.eof_sent {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
 
-        tracing::debug!("WebSocket handshake completed with {}", addr);
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_io_error)?;
 
-        // For demo purposes, we just return the address
-        // In a real implementation, you'd return a connection object
-        Ok((addr,))
+        let n = std::cmp::min(buf.len(), MAX_MESSAGE_LEN);
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf[..n].to_vec()))
+            .map_err(ws_io_error)?;
+        Poll::Ready(Ok(n))
     }
-}
-
-/// WebSocket relay that forwards data between connections
-#[allow(dead_code)]
-pub struct WebSocketRelay;
 
-impl WebSocketRelay {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_io_error)
     }
 
-    /// Create a simple WebSocket echo server for testing
-    #[allow(dead_code)]
-    pub async fn echo_server(&self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        tracing::info!("WebSocket echo server listening on {}", addr);
-
-        while let Ok((tcp_stream, client_addr)) = listener.accept().await {
-            tracing::info!("New WebSocket connection from {}", client_addr);
-
-            tokio::spawn(async move {
-                match accept_async(tcp_stream).await {
-                    Ok(ws_stream) => {
-                        if let Err(e) = Self::handle_echo_connection(ws_stream).await {
-                            tracing::error!("Echo connection error: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!("WebSocket handshake failed: {}", e);
-                    }
-                }
-            });
-        }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
 
-        Ok(())
-    }
+        if !this.eof_sent {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(ws_io_error)?;
+            Pin::new(&mut this.inner)
+                .start_send(Message::Binary(Vec::new()))
+                .map_err(ws_io_error)?;
+            this.eof_sent = true;
+        }
 
-    #[allow(dead_code)]
-    async fn handle_echo_connection(
-        mut ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-    ) -> Result<()> {
-        while let Some(msg) = ws_stream.next().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    tracing::debug!("Received text: {}", text);
-                    if let Err(e) = ws_stream.send(Message::Text(text)).await {
-                        tracing::error!("Failed to echo text: {}", e);
-                        break;
-                    }
-                }
-                Ok(Message::Binary(data)) => {
-                    tracing::debug!("Received binary data: {} bytes", data.len());
-                    if let Err(e) = ws_stream.send(Message::Binary(data)).await {
-                        tracing::error!("Failed to echo binary: {}", e);
-                        break;
-                    }
-                }
-                Ok(Message::Ping(data)) => {
-                    if let Err(e) = ws_stream.send(Message::Pong(data)).await {
-                        tracing::error!("Failed to send pong: {}", e);
-                        break;
-                    }
-                }
-                Ok(Message::Close(_)) => {
-                    tracing::info!("WebSocket connection closed");
-                    break;
-                }
-                Ok(_) => {
-                    // Ignore other message types
-                }
-                Err(e) => {
-                    tracing::error!("WebSocket error: {}", e);
-                    break;
-                }
-            }
+        // The peer may still be sending; leave the connection open until it is done too
+        if !this.read_closed {
+            return Pin::new(&mut this.inner).poll_flush(cx).map_err(ws_io_error);
         }
 
-        Ok(())
+        match ready!(Pin::new(&mut this.inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(ws_io_error(e))),
+        }
     }
+}
 
-    /// Relay data between a WebSocket and a TCP stream
-    #[allow(dead_code)]
-    pub async fn relay_ws_to_tcp(ws_url: &str, tcp_addr: SocketAddr) -> Result<()> {
-        tracing::info!(
-            "Setting up WebSocket-TCP relay: {} <-> {}",
-            ws_url,
-            tcp_addr
-        );
-
-        // Connect to WebSocket
-        let (ws_stream, _) = connect_async(ws_url)
-            .await
-            .map_err(|e| anyhow::anyhow!("WebSocket connection failed: {}", e))?;
+/// Complete the server side of the WebSocket handshake on an accepted connection
+pub async fn accept_stream<S>(stream: S) -> Result<WebSocketByteStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws_stream = accept_async(stream)
+        .await
+        .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
 
-        // Connect to TCP
-        let tcp_stream = TcpStream::connect(tcp_addr).await?;
-        let (mut tcp_reader, mut tcp_writer) = tcp_stream.into_split();
-
-        // Split WebSocket stream for bidirectional communication
-        let (mut ws_sink, mut ws_stream) = ws_stream.split();
-
-        // Forward data from TCP to WebSocket
-        let tcp_to_ws = tokio::spawn(async move {
-            let mut buf = vec![0u8; 8192];
-            let mut total_bytes = 0u64;
-
-            loop {
-                match tcp_reader.read(&mut buf).await {
-                    Ok(0) => break, // EOF
-                    Ok(n) => {
-                        total_bytes += n as u64;
-                        if let Err(e) = ws_sink.send(Message::Binary(buf[..n].to_vec())).await {
-                            tracing::error!("Failed to send TCP data to WebSocket: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!("TCP read error: {}", e);
-                        break;
-                    }
-                }
-            }
+    Ok(WebSocketByteStream::new(ws_stream))
+}
 
-            tracing::debug!(
-                "TCP->WS forward finished, {} bytes transferred",
-                total_bytes
-            );
-        });
+pub struct WebSocketTransport;
 
-        // Forward data from WebSocket to TCP
-        let ws_to_tcp = tokio::spawn(async move {
-            let mut total_bytes = 0u64;
-
-            while let Some(msg) = ws_stream.next().await {
-                match msg {
-                    Ok(Message::Binary(data)) => {
-                        total_bytes += data.len() as u64;
-                        if let Err(e) = tcp_writer.write_all(&data).await {
-                            tracing::error!("Failed to write WebSocket data to TCP: {}", e);
-                            break;
-                        }
-                    }
-                    Ok(Message::Text(text)) => {
-                        let data = text.into_bytes();
-                        total_bytes += data.len() as u64;
-                        if let Err(e) = tcp_writer.write_all(&data).await {
-                            tracing::error!("Failed to write WebSocket text to TCP: {}", e);
-                            break;
-                        }
-                    }
-                    Ok(Message::Close(_)) => {
-                        tracing::info!("WebSocket closed");
-                        break;
-                    }
-                    Ok(Message::Ping(_data)) => {
-                        // Handle ping - we can't access ws_sink from here
-                        // In practice, the WebSocket library handles this automatically
-                        tracing::debug!("Received ping message");
-                    }
-                    Ok(_) => {
-                        // Ignore other message types
-                    }
-                    Err(e) => {
-                        tracing::error!("WebSocket receive error: {}", e);
-                        break;
-                    }
-                }
-            }
+impl WebSocketTransport {
+    pub fn new() -> Self {
+        Self
+    }
 
-            tracing::debug!(
-                "WS->TCP forward finished, {} bytes transferred",
-                total_bytes
-            );
-        });
+    /// Connect to a WebSocket server
+    pub async fn connect(&self, url: &str) -> Result<WebSocketByteStream<MaybeTlsStream<TcpStream>>> {
+        let ws_url = if url.starts_with("ws://") || url.starts_with("wss://") {
+            url.to_string()
+        } else {
+            format!("ws://{}", url)
+        };
 
-        // Wait for either direction to complete
-        tokio::select! {
-            r1 = tcp_to_ws => {
-                if let Err(e) = r1 {
-                    tracing::error!("TCP->WS task error: {}", e);
-                }
-            }
-            r2 = ws_to_tcp => {
-                if let Err(e) = r2 {
-                    tracing::error!("WS->TCP task error: {}", e);
-                }
-            }
-        }
+        tracing::info!("Connecting to WebSocket server: {}", ws_url);
 
-        tracing::info!("WebSocket-TCP relay completed");
-        Ok(())
+        let (ws_stream, _response) = connect_async(&ws_url)
+            .await
+            .map_err(|e| anyhow::anyhow!("WebSocket connection failed: {}", e))?;
+
+        tracing::debug!("WebSocket connection established to {}", ws_url);
+        Ok(WebSocketByteStream::new(ws_stream))
     }
 }
 
@@ -270,8 +202,44 @@ impl Default for WebSocketTransport {
     }
 }
 
-impl Default for WebSocketRelay {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_byte_stream_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_stream(tcp_stream).await.unwrap();
+
+            // Echo everything back, then close once the client is done
+            let mut received = Vec::new();
+            ws.read_to_end(&mut received).await.unwrap();
+            ws.write_all(&received).await.unwrap();
+            ws.shutdown().await.unwrap();
+            received.len()
+        });
+
+        let mut ws = WebSocketTransport::new()
+            .connect(&format!("ws://{}/relay", addr))
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..2 * 1024 * 1024 + 3).map(|i| (i % 251) as u8).collect();
+        for chunk in data.chunks(10_007) {
+            ws.write_all(chunk).await.unwrap();
+        }
+        ws.shutdown().await.unwrap();
+
+        let mut echoed = Vec::new();
+        ws.read_to_end(&mut echoed).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), data.len());
+        assert_eq!(echoed, data);
     }
-}
\ No newline at end of file
+}
@@ -1,13 +1,59 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use crate::types::{ClientInfo, SystemMetrics, Task};
+use crate::types::{ClientInfo, ProxyStatus, RelayRole, SystemMetrics, Task, TaskStatus, TaskType, TransportType};
+
+/// JSON-RPC error code returned when a call carries a missing, invalid or expired token
+pub const UNAUTHORIZED_ERROR_CODE: i32 = -32001;
+
+/// JSON-RPC error code returned when an authenticated operator lacks the role a method requires
+pub const FORBIDDEN_ERROR_CODE: i32 = -32002;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegisterRequest {
+    /// `client_info.id` may be empty, in which case the server assigns one
     pub client_info: ClientInfo,
+    /// Token last issued for `client_info.id`, proving the caller is the client that held it
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Hex-encoded Ed25519 identity key, handed to peers so they can authenticate relays
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Nonce from `client.register_challenge`, and the hex Ed25519 signature of
+    /// `register_challenge_message` for it and `client_info.id` by the identity key the id
+    /// was registered with; proves the caller holds the id when its token is gone
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// Asks for a nonce to sign before registering under an existing id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterChallengeRequest {
+    pub client_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterChallengeResponse {
+    /// Hex nonce, good for one `client.register` call shortly after
+    pub nonce: String,
+}
+
+/// What a client signs to prove it holds `client_id`
+pub fn register_challenge_message(client_id: &str, nonce: &str) -> Vec<u8> {
+    format!("sentinelx-register\n{}\n{}", client_id, nonce).into_bytes()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegisterResponse {
+    /// Id the server assigned or confirmed; the client must use it from now on
+    pub client_id: String,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
     pub client_id: String,
     pub token: String,
 }
@@ -17,6 +63,9 @@ pub struct HeartbeatRequest {
     pub client_id: String,
     pub token: String,
     pub metrics: Option<SystemMetrics>,
+    /// Every proxy the client runs; `None` leaves the server's last known list untouched
+    #[serde(default)]
+    pub proxies: Option<Vec<ProxyStatus>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +74,14 @@ pub struct HeartbeatResponse {
     pub tasks: Vec<Task>,
 }
 
+/// Opens the `client.subscribe_tasks` channel over which the server pushes tasks as they are queued
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskSubscribeRequest {
+    pub client_id: String,
+    pub token: String,
+}
+
+/// Outcome of a task, reported by the client once execution finishes
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaskResult {
     pub task_id: String,
@@ -33,6 +90,62 @@ pub struct TaskResult {
     pub data: Option<serde_json::Value>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRunningRequest {
+    pub client_id: String,
+    pub token: String,
+    pub task_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskResultRequest {
+    pub client_id: String,
+    pub token: String,
+    pub result: TaskResult,
+}
+
+/// Server-side record of a task and its lifecycle, as returned by `task.list` and `task.get`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub id: String,
+    pub client_id: String,
+    pub task_type: TaskType,
+    pub status: TaskStatus,
+    pub payload: serde_json::Value,
+    pub result: Option<TaskResult>,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    pub dispatched_at: Option<DateTime<Utc>>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+/// Filters for `task.list`; every field is optional and `since`/`until` bound `created_at`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskListRequest {
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub task_type: Option<TaskType>,
+    #[serde(default)]
+    pub status: Option<TaskStatus>,
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskListResponse {
+    pub tasks: Vec<TaskRecord>,
+    /// Number of tasks matching the filters, ignoring limit and offset
+    pub total: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsSummary {
     pub total_clients: u32,
@@ -41,4 +154,45 @@ pub struct MetricsSummary {
     pub total_memory_usage: f32,
     pub total_bandwidth_rx: u64,
     pub total_bandwidth_tx: u64,
-}
\ No newline at end of file
+}
+
+/// A relay routed through several clients, tracked by the server as a single object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayChain {
+    pub id: String,
+    pub entry_point: String,
+    pub exit_point: String,
+    pub transport_type: TransportType,
+    pub multiplex: bool,
+    /// In traffic order: the entry first, the exit last
+    pub hops: Vec<ChainHop>,
+    /// The worst health of any hop
+    pub health: RelayHealth,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainHop {
+    pub client_id: String,
+    pub role: RelayRole,
+    /// Where this hop listens for the previous one; unset for the entry
+    pub tunnel_listen: Option<String>,
+    /// Address the previous hop dials to reach this one
+    pub tunnel_addr: Option<String>,
+    /// The task that started this hop's relay
+    pub task_id: String,
+    #[serde(default)]
+    pub health: RelayHealth,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayHealth {
+    /// The start task hasn't finished yet
+    #[default]
+    Starting,
+    Up,
+    /// The start task failed, or the client is offline
+    Down,
+    Stopped,
+}
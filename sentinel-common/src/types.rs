@@ -66,6 +66,65 @@ pub enum TaskType {
     StartRelay,
     StopRelay,
     UpdateConfig,
+    /// Change the caps of a running relay end, identified like for `StopRelay`
+    SetRelayBandwidth,
+}
+
+/// Lifecycle of a task: pending -> dispatched -> running -> succeeded/failed,
+/// with cancelled and timed_out as the other terminal states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Queued on the server, not yet handed to the client
+    Pending,
+    /// Delivered to the client, execution not yet confirmed
+    Dispatched,
+    /// Client reported that execution started
+    Running,
+    Succeeded,
+    Failed,
+    /// Withdrawn by an operator before the client picked it up
+    Cancelled,
+    /// No result within the dispatch timeout after all retries, or never picked up
+    TimedOut,
+}
+
+impl TaskStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Pending => "pending",
+            TaskStatus::Dispatched => "dispatched",
+            TaskStatus::Running => "running",
+            TaskStatus::Succeeded => "succeeded",
+            TaskStatus::Failed => "failed",
+            TaskStatus::Cancelled => "cancelled",
+            TaskStatus::TimedOut => "timed_out",
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Succeeded | TaskStatus::Failed | TaskStatus::Cancelled | TaskStatus::TimedOut
+        )
+    }
+}
+
+impl std::str::FromStr for TaskStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(TaskStatus::Pending),
+            "dispatched" => Ok(TaskStatus::Dispatched),
+            "running" => Ok(TaskStatus::Running),
+            "succeeded" => Ok(TaskStatus::Succeeded),
+            "failed" => Ok(TaskStatus::Failed),
+            "cancelled" => Ok(TaskStatus::Cancelled),
+            "timed_out" => Ok(TaskStatus::TimedOut),
+            _ => Err(format!("unknown task status: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,10 +148,281 @@ pub enum Action {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyConfig {
+    pub name: String,
     pub listen_addr: String,
+    /// Where traffic is forwarded; unused by SOCKS5 and HTTP proxies
+    #[serde(default)]
     pub target_addr: String,
+    /// Bandwidth cap in Mbps, each way
     pub rate_limit: Option<u32>,
+    /// Separate upload and download caps, overriding `rate_limit` where set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<Bandwidth>,
+    /// Caps for the traffic of each source IP, on top of the proxy's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_bandwidth: Option<Bandwidth>,
+    /// Cap on open connections, or on UDP sessions
     pub max_connections: Option<u32>,
+    /// The same cap for each source IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_source: Option<u32>,
+    /// New connections (or UDP sessions) per second accepted from one source IP, in
+    /// bursts of as many
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_rate_per_source: Option<u32>,
+    #[serde(default)]
+    pub mode: ProxyMode,
+    /// Seconds without a datagram either way before a UDP session is dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// Credentials SOCKS5 and HTTP `CONNECT` clients must present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<ProxyAuth>,
+    /// Destinations SOCKS5 and HTTP `CONNECT` clients may reach: IPs, CIDR ranges, host
+    /// names or `*.domain` wildcards, each optionally with a `:port`. Empty allows
+    /// everything not denied.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_destinations: Vec<String>,
+    /// Destinations refused even when allowed, in the same form
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_destinations: Vec<String>,
+    /// Where an HTTP proxy sends requests other than `CONNECT`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<HttpRoute>,
+}
+
+/// Reverse proxy route of an HTTP proxy. A request goes to the route matching its Host
+/// most specifically, then by the longest path prefix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpRoute {
+    /// Host to match, without the port, or `*.domain` for its subdomains; any host if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Path prefix to match on whole segments
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// `host:port` backends, taken in turn and skipped while they refuse connections
+    pub backends: Vec<String>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+/// What a proxy forwards. A UDP proxy keeps a session per source address, each with its
+/// own socket towards the target; a SOCKS5 proxy connects its clients wherever they ask,
+/// over TCP (`CONNECT`) or UDP (`UDP ASSOCIATE`). An HTTP proxy tunnels `CONNECT` requests
+/// and passes everything else to its routes' backends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyMode {
+    #[default]
+    Tcp,
+    Udp,
+    Socks5,
+    Http,
+}
+
+/// Bandwidth caps of a proxy or relay. Upload is traffic from the connecting client
+/// towards the target, download the way back. Rates are in Mbps like `rate_limit`, with 0
+/// for no cap; bursts are the KiB that may pass at once after a quiet spell, one
+/// second's worth when 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bandwidth {
+    #[serde(default)]
+    pub upload_mbps: u32,
+    #[serde(default)]
+    pub download_mbps: u32,
+    #[serde(default)]
+    pub upload_burst_kb: u32,
+    #[serde(default)]
+    pub download_burst_kb: u32,
+}
+
+impl Bandwidth {
+    /// The same cap both ways
+    pub fn symmetric(mbps: u32) -> Self {
+        Self {
+            upload_mbps: mbps,
+            download_mbps: mbps,
+            ..Default::default()
+        }
+    }
+}
+
+/// Username and password a proxy requires of its clients
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Payload of a `ConfigureProxy` task. Starting a proxy under a name that is
+/// already in use replaces it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ProxyCommand {
+    Start(Box<ProxyConfig>),
+    Stop { name: String },
+    /// Change a running proxy's caps, open connections included; unset caps are lifted
+    SetBandwidth {
+        name: String,
+        #[serde(default)]
+        bandwidth: Option<Bandwidth>,
+        #[serde(default)]
+        source_bandwidth: Option<Bandwidth>,
+    },
+}
+
+/// Live state of one named proxy, reported by the client with each heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyStatus {
+    pub name: String,
+    pub listen_addr: String,
+    pub target_addr: String,
+    pub running: bool,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub total_connections: u64,
+    pub active_connections: u64,
+    /// Connections turned away by connection limits
+    #[serde(default)]
+    pub refused_connections: u64,
+    /// Requests and traffic by host, for HTTP proxies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<HostTraffic>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostTraffic {
+    pub host: String,
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Partial client configuration carried by an `UpdateConfig` task.
+/// Sections and fields mirror the client's config.toml; anything left out is unchanged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerConfigUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monitoring: Option<MonitoringConfigUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<LimitsConfigUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logging: Option<LoggingConfigUpdate>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonitoringConfigUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collect_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfigUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_source: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_rate_per_source: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_mbps: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfigUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+}
+
+/// Log levels accepted for `logging.level`
+pub const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+impl ConfigUpdate {
+    /// Reject empty updates and values the client could never apply
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut fields = 0;
+
+        if let Some(server) = &self.server {
+            if let Some(interval) = server.heartbeat_interval {
+                anyhow::ensure!(interval > 0, "server.heartbeat_interval must be at least 1 second");
+                fields += 1;
+            }
+        }
+
+        if let Some(monitoring) = &self.monitoring {
+            if let Some(interval) = monitoring.report_interval {
+                anyhow::ensure!(interval > 0, "monitoring.report_interval must be at least 1 second");
+                fields += 1;
+            }
+            if let Some(interval) = monitoring.collect_interval {
+                anyhow::ensure!(interval > 0, "monitoring.collect_interval must be at least 1 second");
+                fields += 1;
+            }
+            if monitoring.enabled.is_some() {
+                fields += 1;
+            }
+        }
+
+        if let Some(limits) = &self.limits {
+            // 0 turns any of these limits off, as it does in config.toml
+            if let Some(max) = limits.max_connections {
+                anyhow::ensure!(max <= u32::MAX as usize, "limits.max_connections is too large");
+                fields += 1;
+            }
+            if limits.max_connections_per_source.is_some() {
+                fields += 1;
+            }
+            if limits.accept_rate_per_source.is_some() {
+                fields += 1;
+            }
+            if limits.rate_limit_mbps.is_some() {
+                fields += 1;
+            }
+        }
+
+        if let Some(logging) = &self.logging {
+            if let Some(level) = &logging.level {
+                anyhow::ensure!(
+                    LOG_LEVELS.contains(&level.as_str()),
+                    "logging.level must be one of {}",
+                    LOG_LEVELS.join(", ")
+                );
+                fields += 1;
+            }
+        }
+
+        anyhow::ensure!(fields > 0, "config update does not change anything");
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,6 +430,87 @@ pub struct RelayConfig {
     pub entry_point: String,
     pub exit_point: String,
     pub transport_type: TransportType,
+    /// Client at the other end, filled in by the server for encrypted relays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<RelayPeer>,
+    /// Which end of the relay this client runs
+    #[serde(default)]
+    pub role: RelayRole,
+    /// Tunnel between the two clients: the address the entry dials, or the one the exit
+    /// listens on. Without it the entry client connects straight to `exit_point`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_addr: Option<String>,
+    /// Carry every connection as a stream over one long-lived tunnel instead of a tunnel each
+    #[serde(default)]
+    pub multiplex: bool,
+    /// Set when this relay is one hop of a `relay.create_chain` chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<String>,
+    /// For a `Hop`, the next client in the chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_hop: Option<NextHop>,
+    /// The client at the far end of a chain, with which the entry and exit run an inner
+    /// encrypted layer that the hops in between can't read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_peer: Option<RelayPeer>,
+    /// Exits tried in order when `exit_point` is down, each a `host:port` like `exit_point`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_exits: Vec<String>,
+    /// Probe the exits in the background, so traffic skips those found down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_probe: Option<ExitProbe>,
+    /// Caps for all of this end's connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<Bandwidth>,
+    /// Caps for the connections from each source IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_bandwidth: Option<Bandwidth>,
+}
+
+/// TCP health probe of a relay's exits: a connection attempt every `interval_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitProbe {
+    #[serde(default = "default_probe_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_probe_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_probe_interval() -> u64 {
+    10
+}
+
+fn default_probe_timeout() -> u64 {
+    2000
+}
+
+/// Where a chain hop forwards its tunnel to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextHop {
+    pub addr: String,
+    /// Identity of the next client, filled in for encrypted relays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<RelayPeer>,
+}
+
+/// The entry accepts traffic on `entry_point` and sends it through the tunnel; the exit
+/// accepts the tunnel and forwards it to `exit_point`. In a chain, each hop accepts the
+/// tunnel from the previous client and dials the next one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayRole {
+    #[default]
+    Entry,
+    Hop,
+    Exit,
+}
+
+/// Identity of the client at the other end of a relay, as registered with the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayPeer {
+    pub client_id: String,
+    /// Hex-encoded Ed25519 public key the peer signs relay handshakes with
+    pub public_key: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +518,8 @@ pub enum TransportType {
     Direct,
     Encrypted,
     WebSocket,
+    /// TLS terminated by the exit client, with optional client certificates
+    Tls,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
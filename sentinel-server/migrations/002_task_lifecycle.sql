@@ -0,0 +1,5 @@
+-- Track when a task was handed to its client and when execution started
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS dispatched_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS started_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_tasks_client_status ON tasks(client_id, status);
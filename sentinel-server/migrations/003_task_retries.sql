@@ -0,0 +1,5 @@
+-- Count deliveries of each task and delay redelivery after a timeout
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_tasks_dispatched_at ON tasks(dispatched_at) WHERE status IN ('dispatched', 'running');
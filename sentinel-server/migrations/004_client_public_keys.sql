@@ -0,0 +1,2 @@
+-- Ed25519 identity key each client registers, handed to relay peers
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS public_key VARCHAR(64);
@@ -0,0 +1,14 @@
+-- Multi-hop relays created by relay.create_chain; hops holds each client's role and start task
+CREATE TABLE IF NOT EXISTS relay_chains (
+    id VARCHAR(255) PRIMARY KEY,
+    entry_point VARCHAR(255) NOT NULL,
+    exit_point VARCHAR(255) NOT NULL,
+    transport_type VARCHAR(20) NOT NULL,
+    multiplex BOOLEAN NOT NULL DEFAULT FALSE,
+    hops JSONB NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'active',
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    stopped_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_chains_status ON relay_chains(status);
@@ -1,40 +1,162 @@
 use anyhow::Result;
-use jsonrpsee::server::RpcModule;
+use chrono::{DateTime, Utc};
+use jsonrpsee::server::{Extensions, HttpRequest, RpcModule, SubscriptionMessage};
 use jsonrpsee::types::{ErrorCode, ErrorObjectOwned};
 use sentinel_common::{
-    HeartbeatRequest, HeartbeatResponse, MetricsSummary, RegisterRequest,
-    RegisterResponse, RelayConfig, IptablesRule,
+    HeartbeatRequest, HeartbeatResponse, MetricsSummary, RefreshTokenRequest, RegisterChallengeRequest,
+    RegisterChallengeResponse, RegisterRequest, RegisterResponse, RelayConfig, RelayPeer, RelayRole, ExitProbe, Bandwidth, TransportType, ChainHop, NextHop, RelayChain, RelayHealth, IptablesRule, ProxyCommand, ProxyStatus, ConfigUpdate, TaskListRequest, TaskResultRequest, TaskRunningRequest,
+    TaskSubscribeRequest,
+    FORBIDDEN_ERROR_CODE, UNAUTHORIZED_ERROR_CODE,
 };
+use std::collections::HashSet;
 use std::sync::Arc;
 
+use crate::auth::{AuthError, BearerToken, Claims, OperatorAuth, Role};
 use crate::manager::ClientManager;
 
-pub async fn create_rpc_module(manager: Arc<ClientManager>) -> Result<RpcModule<Arc<ClientManager>>> {
-    let mut module = RpcModule::new(manager);
+/// Shared state handed to every RPC method
+pub struct RpcContext {
+    pub manager: Arc<ClientManager>,
+    pub auth: OperatorAuth,
+}
+
+impl RpcContext {
+    /// Require an operator session with at least `role` for the current call
+    fn authorize(&self, ext: &Extensions, role: Role) -> Result<Claims, ErrorObjectOwned> {
+        self.auth.authorize(ext.get::<BearerToken>(), role).map_err(auth_error)
+    }
+}
+
+fn auth_error(e: AuthError) -> ErrorObjectOwned {
+    let code = match e {
+        AuthError::Forbidden(_) => FORBIDDEN_ERROR_CODE,
+        _ => UNAUTHORIZED_ERROR_CODE,
+    };
+    ErrorObjectOwned::owned(code, e.to_string(), None::<()>)
+}
+
+/// Copy the `Authorization: Bearer` header into the request extensions so RPC methods can see it
+pub fn extract_bearer_token(mut request: HttpRequest) -> HttpRequest {
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| BearerToken(token.trim().to_string()));
+
+    if let Some(token) = token {
+        request.extensions_mut().insert(token);
+    }
+    request
+}
+
+pub async fn create_rpc_module(context: RpcContext) -> Result<RpcModule<RpcContext>> {
+    let mut module = RpcModule::new(context);
+
+    module.register_async_method("auth.login", |params, ctx, _| async move {
+        #[derive(serde::Deserialize)]
+        struct LoginRequest {
+            username: String,
+            password: String,
+        }
+
+        #[derive(Clone, serde::Serialize)]
+        struct LoginResponse {
+            token: String,
+            username: String,
+            role: Role,
+            expires_at: DateTime<Utc>,
+        }
+
+        let req: LoginRequest = params.parse()?;
+        let (token, claims) = ctx.auth.login(&req.username, &req.password).map_err(|e| {
+            tracing::warn!("Failed login attempt for operator {}", req.username);
+            auth_error(e)
+        })?;
+
+        tracing::info!("Operator {} logged in ({:?})", claims.sub, claims.role);
+
+        Ok::<LoginResponse, ErrorObjectOwned>(LoginResponse {
+            token,
+            username: claims.sub,
+            role: claims.role,
+            expires_at: DateTime::from_timestamp(claims.exp, 0).unwrap_or_else(Utc::now),
+        })
+    })?;
 
     module.register_async_method("client.register", |params, ctx, _| async move {
         let req: RegisterRequest = params.parse()?;
-        let token = ctx.register_client(req.client_info).await
+
+        if let Some(key) = &req.public_key {
+            if key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ErrorObjectOwned::owned(
+                    ErrorCode::InvalidParams.code(),
+                    "public_key must be a hex-encoded 32-byte Ed25519 key",
+                    None::<()>,
+                ));
+            }
+        }
+
+        let (client_id, issued) = ctx
+            .manager
+            .register_client(
+                req.client_info,
+                req.token.as_deref(),
+                req.public_key,
+                req.nonce.as_deref().zip(req.signature.as_deref()),
+            )
+            .await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
         Ok::<RegisterResponse, ErrorObjectOwned>(RegisterResponse {
-            client_id: "".to_string(),
-            token,
+            client_id,
+            token: issued.token,
+            expires_at: issued.expires_at,
+        })
+    })?;
+
+    module.register_async_method("client.register_challenge", |params, ctx, _| async move {
+        let req: RegisterChallengeRequest = params.parse()?;
+
+        let nonce = ctx.manager.register_challenge(&req.client_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?
+            .ok_or_else(|| ErrorObjectOwned::owned(
+                ErrorCode::InvalidParams.code(),
+                format!("No client with an identity key is registered as {}", req.client_id),
+                None::<()>,
+            ))?;
+
+        Ok::<RegisterChallengeResponse, ErrorObjectOwned>(RegisterChallengeResponse { nonce })
+    })?;
+
+    module.register_async_method("client.refresh_token", |params, ctx, _| async move {
+        let req: RefreshTokenRequest = params.parse()?;
+        let issued = ctx.manager.refresh_token(&req.client_id, &req.token).map_err(auth_error)?;
+
+        Ok::<RegisterResponse, ErrorObjectOwned>(RegisterResponse {
+            client_id: req.client_id,
+            token: issued.token,
+            expires_at: issued.expires_at,
         })
     })?;
 
     module.register_async_method("client.heartbeat", |params, ctx, _| async move {
         let req: HeartbeatRequest = params.parse()?;
+        ctx.manager.verify_token(&req.client_id, &req.token).map_err(auth_error)?;
 
-        ctx.update_heartbeat(&req.client_id).await
+        ctx.manager.update_heartbeat(&req.client_id).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
         if let Some(metrics) = req.metrics {
-            ctx.update_metrics(&req.client_id, metrics).await
+            ctx.manager.update_metrics(&req.client_id, metrics).await
                 .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
         }
 
-        let tasks = ctx.get_pending_tasks(&req.client_id).await
+        if let Some(proxies) = req.proxies {
+            ctx.manager.update_proxies(&req.client_id, proxies);
+        }
+
+        let tasks = ctx.manager.dispatch_pending_tasks(&req.client_id).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
         Ok::<HeartbeatResponse, ErrorObjectOwned>(HeartbeatResponse {
@@ -43,12 +165,116 @@ pub async fn create_rpc_module(manager: Arc<ClientManager>) -> Result<RpcModule<
         })
     })?;
 
-    module.register_async_method("client.list", |_, ctx, _| async move {
-        Ok::<Vec<sentinel_common::ClientInfo>, ErrorObjectOwned>(ctx.list_clients().await)
+    // Persistent push channel: tasks are sent as `client.task` notifications the moment they are queued
+    module.register_subscription("client.subscribe_tasks", "client.task", "client.unsubscribe_tasks", |params, pending, ctx, _| async move {
+        let req: TaskSubscribeRequest = match params.parse() {
+            Ok(req) => req,
+            Err(e) => {
+                pending.reject(e).await;
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = ctx.manager.verify_token(&req.client_id, &req.token) {
+            pending.reject(auth_error(e)).await;
+            return Ok(());
+        }
+
+        let sink = pending.accept().await?;
+        let mut tasks = ctx.manager.open_task_stream(&req.client_id).await;
+
+        loop {
+            tokio::select! {
+                _ = sink.closed() => break,
+                task = tasks.recv() => {
+                    // `None` means a newer subscription from the same client took over
+                    let Some(task) = task else { break };
+                    if sink.send(SubscriptionMessage::from_json(&task)?).await.is_err() {
+                        tracing::warn!("Client {} disconnected before task {} was delivered", req.client_id, task.id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        drop(tasks);
+        ctx.manager.close_task_stream(&req.client_id);
+        Ok(())
+    })?;
+
+    module.register_async_method("task.report_running", |params, ctx, _| async move {
+        let req: TaskRunningRequest = params.parse()?;
+        ctx.manager.verify_token(&req.client_id, &req.token).map_err(auth_error)?;
+
+        ctx.manager.mark_task_running(&req.client_id, &req.task_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "ok"}))
+    })?;
+
+    module.register_async_method("task.report_result", |params, ctx, _| async move {
+        let req: TaskResultRequest = params.parse()?;
+        ctx.manager.verify_token(&req.client_id, &req.token).map_err(auth_error)?;
+
+        ctx.manager.complete_task(&req.client_id, req.result).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "ok"}))
+    })?;
+
+    module.register_async_method("task.list", |params, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+
+        let filter: TaskListRequest = if params.as_str().is_some() {
+            params.parse()?
+        } else {
+            TaskListRequest::default()
+        };
+
+        ctx.manager.list_tasks(&filter).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))
+    })?;
+
+    module.register_async_method("task.get", |params, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+
+        #[derive(serde::Deserialize)]
+        struct GetTaskRequest {
+            task_id: String,
+        }
+
+        let req: GetTaskRequest = params.parse()?;
+
+        ctx.manager.get_task(&req.task_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?
+            .ok_or_else(|| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), format!("Task not found: {}", req.task_id), None::<()>))
     })?;
 
-    module.register_async_method("metrics.get_summary", |_, ctx, _| async move {
-        let clients = ctx.list_clients().await;
+    module.register_async_method("task.cancel", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
+        #[derive(serde::Deserialize)]
+        struct CancelTaskRequest {
+            task_id: String,
+        }
+
+        let req: CancelTaskRequest = params.parse()?;
+        tracing::info!("Operator {} cancelling task {}", operator.sub, req.task_id);
+
+        ctx.manager.cancel_task(&req.task_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "cancelled"}))
+    })?;
+
+    module.register_async_method("client.list", |_, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+        Ok::<Vec<sentinel_common::ClientInfo>, ErrorObjectOwned>(ctx.manager.list_clients().await)
+    })?;
+
+    module.register_async_method("metrics.get_summary", |_, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+        let clients = ctx.manager.list_clients().await;
 
         Ok::<MetricsSummary, ErrorObjectOwned>(MetricsSummary {
             total_clients: clients.len() as u32,
@@ -60,54 +286,495 @@ pub async fn create_rpc_module(manager: Arc<ClientManager>) -> Result<RpcModule<
         })
     })?;
 
-    module.register_async_method("relay.start", |params, ctx, _| async move {
+    module.register_async_method("relay.start", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
         #[derive(serde::Deserialize)]
         struct StartRelayRequest {
             entry_client_id: String,
-            #[allow(dead_code)]
             exit_client_id: String,
             entry_point: String,
             exit_point: String,
-            transport_type: sentinel_common::TransportType,
+            transport_type: TransportType,
+            /// Address the exit client listens on for the tunnel; without it only the entry runs
+            #[serde(default)]
+            tunnel_listen: Option<String>,
+            /// Address the entry client dials, when the exit client's registered IP won't do
+            #[serde(default)]
+            tunnel_addr: Option<String>,
+            /// Share one tunnel between all of the relay's connections
+            #[serde(default)]
+            multiplex: bool,
+            /// Exits tried in order when `exit_point` is down
+            #[serde(default)]
+            fallback_exits: Vec<String>,
+            #[serde(default)]
+            exit_probe: Option<ExitProbe>,
+            /// Caps applied by the entry client
+            #[serde(default)]
+            bandwidth: Option<Bandwidth>,
+            #[serde(default)]
+            source_bandwidth: Option<Bandwidth>,
         }
 
         let req: StartRelayRequest = params.parse()?;
+        tracing::info!("Operator {} starting relay on client {}", operator.sub, req.entry_client_id);
 
-        let relay_config = RelayConfig {
+        let invalid_params = |message: String| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), message, None::<()>);
+
+        let Some(tunnel_listen) = req.tunnel_listen else {
+            // Entry-only relay: the entry client connects straight to exit_point
+            if !matches!(req.transport_type, TransportType::Direct) {
+                return Err(invalid_params(format!(
+                    "{:?} relays need tunnel_listen so the exit client can unwrap them",
+                    req.transport_type
+                )));
+            }
+            if req.multiplex {
+                return Err(invalid_params("Multiplexed relays need tunnel_listen".to_string()));
+            }
+
+            let relay_config = RelayConfig {
+                entry_point: req.entry_point,
+                exit_point: req.exit_point,
+                transport_type: req.transport_type,
+                peer: None,
+                role: RelayRole::Entry,
+                tunnel_addr: None,
+                multiplex: false,
+                chain_id: None,
+                next_hop: None,
+                chain_peer: None,
+                fallback_exits: req.fallback_exits,
+                exit_probe: req.exit_probe,
+                bandwidth: req.bandwidth,
+                source_bandwidth: req.source_bandwidth,
+            };
+
+            let task_id = ctx.manager.create_relay_task(&req.entry_client_id, relay_config).await
+                .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+
+            return Ok(serde_json::json!({"status": "relay_started", "task_id": task_id}));
+        };
+
+        let exit_ip = ctx.manager.client_ip(&req.exit_client_id)
+            .ok_or_else(|| invalid_params(format!("Exit client {} is not connected", req.exit_client_id)))?;
+
+        let tunnel_addr = match req.tunnel_addr {
+            Some(addr) => addr,
+            None => tunnel_addr_for(&exit_ip, &tunnel_listen)
+                .ok_or_else(|| invalid_params(format!("Invalid tunnel_listen address (needs a fixed port): {}", tunnel_listen)))?,
+        };
+
+        // Each end authenticates the other by the identity key it registered with
+        let (entry_peer, exit_peer) = if matches!(req.transport_type, TransportType::Encrypted) {
+            let peer = |client_id: &str| {
+                ctx.manager
+                    .public_key(client_id)
+                    .map(|public_key| RelayPeer { client_id: client_id.to_string(), public_key })
+                    .ok_or_else(|| invalid_params(format!("Client {} is not connected with an identity key", client_id)))
+            };
+            (Some(peer(&req.exit_client_id)?), Some(peer(&req.entry_client_id)?))
+        } else {
+            (None, None)
+        };
+
+        tracing::info!(
+            "Relay {} -> {} tunnels through client {} at {}",
+            req.entry_point, req.exit_point, req.exit_client_id, tunnel_addr
+        );
+
+        let exit_config = RelayConfig {
+            entry_point: req.entry_point.clone(),
+            exit_point: req.exit_point.clone(),
+            transport_type: req.transport_type.clone(),
+            peer: exit_peer,
+            role: RelayRole::Exit,
+            tunnel_addr: Some(tunnel_listen),
+            multiplex: req.multiplex,
+            chain_id: None,
+            next_hop: None,
+            chain_peer: None,
+            fallback_exits: req.fallback_exits,
+            exit_probe: req.exit_probe,
+            bandwidth: None,
+            source_bandwidth: None,
+        };
+        let entry_config = RelayConfig {
             entry_point: req.entry_point,
             exit_point: req.exit_point,
             transport_type: req.transport_type,
+            peer: entry_peer,
+            role: RelayRole::Entry,
+            tunnel_addr: Some(tunnel_addr),
+            multiplex: req.multiplex,
+            chain_id: None,
+            next_hop: None,
+            chain_peer: None,
+            fallback_exits: Vec::new(),
+            exit_probe: None,
+            bandwidth: req.bandwidth,
+            source_bandwidth: req.source_bandwidth,
         };
 
-        ctx.create_relay_task(&req.entry_client_id, relay_config).await
+        // Task the exit first so its listener is normally up before the entry takes traffic
+        let exit_task_id = ctx.manager.create_relay_task(&req.exit_client_id, exit_config).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+        let task_id = ctx.manager.create_relay_task(&req.entry_client_id, entry_config).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
-        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "relay_started"}))
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({
+            "status": "relay_started",
+            "task_id": task_id,
+            "exit_task_id": exit_task_id,
+        }))
     })?;
 
-    module.register_async_method("relay.stop", |params, ctx, _| async move {
+    module.register_async_method("relay.stop", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
         #[derive(serde::Deserialize)]
         struct StopRelayRequest {
             client_id: String,
             entry_point: String,
             exit_point: String,
+            /// Exit end of a tunneled relay, stopped along with the entry
+            #[serde(default)]
+            exit_client_id: Option<String>,
         }
 
         let req: StopRelayRequest = params.parse()?;
+        tracing::info!("Operator {} stopping relay on client {}", operator.sub, req.client_id);
 
         let relay_config = RelayConfig {
             entry_point: req.entry_point,
             exit_point: req.exit_point,
-            transport_type: sentinel_common::TransportType::Direct, // Doesn't matter for stop
+            transport_type: TransportType::Direct, // Doesn't matter for stop
+            peer: None,
+            role: RelayRole::Entry,
+            tunnel_addr: None,
+            multiplex: false,
+            chain_id: None,
+            next_hop: None,
+            chain_peer: None,
+            fallback_exits: Vec::new(),
+            exit_probe: None,
+            bandwidth: None,
+            source_bandwidth: None,
         };
 
-        ctx.create_stop_relay_task(&req.client_id, relay_config).await
+        let task_id = ctx.manager.create_stop_relay_task(&req.client_id, relay_config.clone()).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
-        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "relay_stopped"}))
+        let Some(exit_client_id) = req.exit_client_id else {
+            return Ok(serde_json::json!({"status": "relay_stopped", "task_id": task_id}));
+        };
+
+        let exit_config = RelayConfig { role: RelayRole::Exit, ..relay_config };
+        let exit_task_id = ctx.manager.create_stop_relay_task(&exit_client_id, exit_config).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({
+            "status": "relay_stopped",
+            "task_id": task_id,
+            "exit_task_id": exit_task_id,
+        }))
     })?;
 
-    module.register_async_method("iptables.update", |params, ctx, _| async move {
+    module.register_async_method("relay.set_bandwidth", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
+        #[derive(serde::Deserialize)]
+        struct SetRelayBandwidthRequest {
+            /// The entry client, which applies a relay's caps
+            client_id: String,
+            entry_point: String,
+            exit_point: String,
+            /// For the entry of a chain
+            #[serde(default)]
+            chain_id: Option<String>,
+            /// Caps to apply; unset ones are lifted
+            #[serde(default)]
+            bandwidth: Option<Bandwidth>,
+            #[serde(default)]
+            source_bandwidth: Option<Bandwidth>,
+        }
+
+        let req: SetRelayBandwidthRequest = params.parse()?;
+        tracing::info!(
+            "Operator {} setting bandwidth of relay {} -> {} on client {} to {:?} (per source {:?})",
+            operator.sub, req.entry_point, req.exit_point, req.client_id, req.bandwidth, req.source_bandwidth
+        );
+
+        let relay_config = RelayConfig {
+            entry_point: req.entry_point,
+            exit_point: req.exit_point,
+            transport_type: TransportType::Direct, // Only identifies the relay
+            peer: None,
+            role: RelayRole::Entry,
+            tunnel_addr: None,
+            multiplex: false,
+            chain_id: req.chain_id,
+            next_hop: None,
+            chain_peer: None,
+            fallback_exits: Vec::new(),
+            exit_probe: None,
+            bandwidth: req.bandwidth,
+            source_bandwidth: req.source_bandwidth,
+        };
+
+        let task_id = ctx.manager.create_relay_bandwidth_task(&req.client_id, relay_config).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "relay_bandwidth_queued", "task_id": task_id}))
+    })?;
+
+    module.register_async_method("relay.create_chain", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
+        #[derive(serde::Deserialize)]
+        struct ChainHopRequest {
+            client_id: String,
+            /// Where this client listens for the previous hop; unused for the entry
+            #[serde(default)]
+            tunnel_listen: Option<String>,
+            /// Address the previous hop dials, when this client's registered IP won't do
+            #[serde(default)]
+            tunnel_addr: Option<String>,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct CreateChainRequest {
+            /// Clients in traffic order: the entry first, the exit last
+            hops: Vec<ChainHopRequest>,
+            entry_point: String,
+            exit_point: String,
+            transport_type: TransportType,
+            #[serde(default)]
+            multiplex: bool,
+            #[serde(default)]
+            fallback_exits: Vec<String>,
+            #[serde(default)]
+            exit_probe: Option<ExitProbe>,
+            /// Caps applied by the entry client
+            #[serde(default)]
+            bandwidth: Option<Bandwidth>,
+            #[serde(default)]
+            source_bandwidth: Option<Bandwidth>,
+        }
+
+        let req: CreateChainRequest = params.parse()?;
+        tracing::info!("Operator {} creating relay chain through {} clients", operator.sub, req.hops.len());
+
+        let invalid_params = |message: String| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), message, None::<()>);
+
+        if req.hops.len() < 2 {
+            return Err(invalid_params("A chain needs at least an entry and an exit client".to_string()));
+        }
+        let mut seen = HashSet::new();
+        if let Some(hop) = req.hops.iter().find(|hop| !seen.insert(hop.client_id.as_str())) {
+            return Err(invalid_params(format!("Client {} appears twice in the chain", hop.client_id)));
+        }
+
+        // The entry and exit always run an inner encrypted layer, and encrypted links
+        // authenticate every hop, so each client needs its identity key on record
+        let peers = req.hops.iter()
+            .map(|hop| {
+                ctx.manager
+                    .public_key(&hop.client_id)
+                    .map(|public_key| RelayPeer { client_id: hop.client_id.clone(), public_key })
+                    .ok_or_else(|| invalid_params(format!("Client {} is not connected with an identity key", hop.client_id)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Address each hop after the entry is dialed at; `tunnel_addrs[i - 1]` reaches hop `i`
+        let mut tunnel_addrs = Vec::new();
+        for hop in &req.hops[1..] {
+            let tunnel_listen = hop.tunnel_listen.as_deref()
+                .ok_or_else(|| invalid_params(format!("Client {} needs tunnel_listen to accept the previous hop", hop.client_id)))?;
+            let addr = match &hop.tunnel_addr {
+                Some(addr) => addr.clone(),
+                None => ctx.manager.client_ip(&hop.client_id)
+                    .and_then(|ip| tunnel_addr_for(&ip, tunnel_listen))
+                    .ok_or_else(|| invalid_params(format!("Invalid tunnel_listen address (needs a fixed port): {}", tunnel_listen)))?,
+            };
+            tunnel_addrs.push(addr);
+        }
+
+        let chain_id = uuid::Uuid::new_v4().to_string();
+        let last = req.hops.len() - 1;
+        let link_peer = |i: usize| matches!(req.transport_type, TransportType::Encrypted).then(|| peers[i].clone());
+
+        // Task from the exit backwards so each listener is normally up before its upstream hop dials it.
+        // Tasks already queued are withdrawn if a later one, or saving the chain, fails.
+        let mut queued = Vec::with_capacity(req.hops.len());
+        let created = async {
+            let mut hops = Vec::with_capacity(req.hops.len());
+            for i in (0..=last).rev() {
+                let role = match i {
+                    0 => RelayRole::Entry,
+                    i if i == last => RelayRole::Exit,
+                    _ => RelayRole::Hop,
+                };
+
+                let relay_config = RelayConfig {
+                    entry_point: req.entry_point.clone(),
+                    exit_point: req.exit_point.clone(),
+                    transport_type: req.transport_type.clone(),
+                    // The neighbor whose link this end authenticates: the next hop for the entry,
+                    // the previous one for everything after it
+                    peer: if i == 0 { link_peer(1) } else { link_peer(i - 1) },
+                    role,
+                    tunnel_addr: if i == 0 { Some(tunnel_addrs[0].clone()) } else { req.hops[i].tunnel_listen.clone() },
+                    multiplex: req.multiplex,
+                    chain_id: Some(chain_id.clone()),
+                    next_hop: (role == RelayRole::Hop).then(|| NextHop {
+                        addr: tunnel_addrs[i].clone(),
+                        peer: link_peer(i + 1),
+                    }),
+                    chain_peer: match role {
+                        RelayRole::Entry => Some(peers[last].clone()),
+                        RelayRole::Exit => Some(peers[0].clone()),
+                        RelayRole::Hop => None,
+                    },
+                    fallback_exits: if role == RelayRole::Exit { req.fallback_exits.clone() } else { Vec::new() },
+                    exit_probe: if role == RelayRole::Exit { req.exit_probe.clone() } else { None },
+                    bandwidth: if role == RelayRole::Entry { req.bandwidth } else { None },
+                    source_bandwidth: if role == RelayRole::Entry { req.source_bandwidth } else { None },
+                };
+
+                let task_id = ctx.manager.create_relay_task(&req.hops[i].client_id, relay_config.clone()).await?;
+                queued.push((req.hops[i].client_id.clone(), task_id.clone(), relay_config));
+
+                hops.push(ChainHop {
+                    client_id: req.hops[i].client_id.clone(),
+                    role,
+                    tunnel_listen: if i == 0 { None } else { req.hops[i].tunnel_listen.clone() },
+                    tunnel_addr: if i == 0 { None } else { Some(tunnel_addrs[i - 1].clone()) },
+                    task_id,
+                    health: RelayHealth::Starting,
+                });
+            }
+            hops.reverse();
+
+            let chain = RelayChain {
+                id: chain_id.clone(),
+                entry_point: req.entry_point.clone(),
+                exit_point: req.exit_point.clone(),
+                transport_type: req.transport_type.clone(),
+                multiplex: req.multiplex,
+                hops,
+                health: RelayHealth::Starting,
+                created_at: Utc::now(),
+            };
+
+            ctx.manager.save_chain(&chain).await?;
+            anyhow::Ok(chain)
+        }
+        .await;
+
+        match created {
+            Ok(chain) => Ok::<RelayChain, ErrorObjectOwned>(chain),
+            Err(e) => {
+                tracing::warn!("Creating relay chain {} failed, withdrawing its tasks: {}", chain_id, e);
+                for (client_id, task_id, relay_config) in queued {
+                    if let Err(e) = ctx.manager.withdraw_relay_task(&client_id, &task_id, relay_config).await {
+                        tracing::warn!("Failed to withdraw task {} from client {}: {}", task_id, client_id, e);
+                    }
+                }
+                Err(ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))
+            }
+        }
+    })?;
+
+    module.register_async_method("relay.list_chains", |params, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+
+        #[derive(Default, serde::Deserialize)]
+        struct ListChainsRequest {
+            #[serde(default)]
+            active_only: bool,
+        }
+
+        let req: ListChainsRequest = if params.as_str().is_some() {
+            params.parse()?
+        } else {
+            ListChainsRequest::default()
+        };
+
+        ctx.manager.list_chains(req.active_only).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))
+    })?;
+
+    module.register_async_method("relay.get_chain", |params, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+
+        #[derive(serde::Deserialize)]
+        struct GetChainRequest {
+            chain_id: String,
+        }
+
+        let req: GetChainRequest = params.parse()?;
+
+        ctx.manager.get_chain(&req.chain_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?
+            .ok_or_else(|| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), format!("Chain not found: {}", req.chain_id), None::<()>))
+    })?;
+
+    module.register_async_method("relay.stop_chain", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
+        #[derive(serde::Deserialize)]
+        struct StopChainRequest {
+            chain_id: String,
+        }
+
+        let req: StopChainRequest = params.parse()?;
+        tracing::info!("Operator {} stopping relay chain {}", operator.sub, req.chain_id);
+
+        let chain = ctx.manager.get_chain(&req.chain_id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?
+            .ok_or_else(|| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), format!("Chain not found: {}", req.chain_id), None::<()>))?;
+
+        let stopped = ctx.manager.stop_chain(&chain.id).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+        if !stopped {
+            return Err(ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), format!("Chain {} is already stopped", chain.id), None::<()>));
+        }
+
+        let mut task_ids = Vec::with_capacity(chain.hops.len());
+        for hop in &chain.hops {
+            let relay_config = RelayConfig {
+                entry_point: chain.entry_point.clone(),
+                exit_point: chain.exit_point.clone(),
+                transport_type: TransportType::Direct, // Doesn't matter for stop
+                peer: None,
+                role: hop.role,
+                tunnel_addr: None,
+                multiplex: false,
+                chain_id: Some(chain.id.clone()),
+                next_hop: None,
+                chain_peer: None,
+                fallback_exits: Vec::new(),
+                exit_probe: None,
+                bandwidth: None,
+                source_bandwidth: None,
+            };
+
+            let task_id = ctx.manager.create_stop_relay_task(&hop.client_id, relay_config).await
+                .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+            task_ids.push(task_id);
+        }
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({
+            "status": "chain_stopped",
+            "task_ids": task_ids,
+        }))
+    })?;
+
+    module.register_async_method("iptables.update", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
         #[derive(serde::Deserialize)]
         struct UpdateIptablesRequest {
             client_id: String,
@@ -115,14 +782,17 @@ pub async fn create_rpc_module(manager: Arc<ClientManager>) -> Result<RpcModule<
         }
 
         let req: UpdateIptablesRequest = params.parse()?;
+        tracing::info!("Operator {} pushing {} iptables rules to client {}", operator.sub, req.rules.len(), req.client_id);
 
-        ctx.create_iptables_task(&req.client_id, req.rules).await
+        let task_id = ctx.manager.create_iptables_task(&req.client_id, req.rules).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
-        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "iptables_task_created"}))
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "iptables_task_created", "task_id": task_id}))
     })?;
 
-    module.register_async_method("iptables.apply_rule", |params, ctx, _| async move {
+    module.register_async_method("iptables.apply_rule", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
         #[derive(serde::Deserialize)]
         struct ApplyRuleRequest {
             client_id: String,
@@ -130,12 +800,93 @@ pub async fn create_rpc_module(manager: Arc<ClientManager>) -> Result<RpcModule<
         }
 
         let req: ApplyRuleRequest = params.parse()?;
+        tracing::info!("Operator {} applying iptables rule on client {}", operator.sub, req.client_id);
 
-        ctx.create_iptables_task(&req.client_id, vec![req.rule]).await
+        let task_id = ctx.manager.create_iptables_task(&req.client_id, vec![req.rule]).await
             .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
 
-        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "iptables_rule_queued"}))
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "iptables_rule_queued", "task_id": task_id}))
+    })?;
+
+    module.register_async_method("proxy.configure", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Operator)?;
+
+        #[derive(serde::Deserialize)]
+        struct ConfigureProxyRequest {
+            client_id: String,
+            #[serde(flatten)]
+            command: ProxyCommand,
+        }
+
+        let req: ConfigureProxyRequest = params.parse()?;
+        match &req.command {
+            ProxyCommand::Start(config) => tracing::info!(
+                "Operator {} starting proxy {} ({} -> {}) on client {}",
+                operator.sub, config.name, config.listen_addr, config.target_addr, req.client_id
+            ),
+            ProxyCommand::Stop { name } => tracing::info!(
+                "Operator {} stopping proxy {} on client {}",
+                operator.sub, name, req.client_id
+            ),
+            ProxyCommand::SetBandwidth { name, bandwidth, source_bandwidth } => tracing::info!(
+                "Operator {} setting bandwidth of proxy {} on client {} to {:?} (per source {:?})",
+                operator.sub, name, req.client_id, bandwidth, source_bandwidth
+            ),
+        }
+
+        let task_id = ctx.manager.create_proxy_task(&req.client_id, req.command).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "proxy_task_created", "task_id": task_id}))
+    })?;
+
+    module.register_async_method("proxy.list", |params, ctx, ext| async move {
+        ctx.authorize(&ext, Role::Viewer)?;
+
+        #[derive(serde::Deserialize)]
+        struct ListProxiesRequest {
+            client_id: String,
+        }
+
+        let req: ListProxiesRequest = params.parse()?;
+        let proxies = ctx.manager.list_proxies(&req.client_id)
+            .ok_or_else(|| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), format!("Client not found: {}", req.client_id), None::<()>))?;
+
+        Ok::<Vec<ProxyStatus>, ErrorObjectOwned>(proxies)
+    })?;
+
+    module.register_async_method("client.update_config", |params, ctx, ext| async move {
+        let operator = ctx.authorize(&ext, Role::Admin)?;
+
+        #[derive(serde::Deserialize)]
+        struct UpdateConfigRequest {
+            client_id: String,
+            config: ConfigUpdate,
+        }
+
+        let req: UpdateConfigRequest = params.parse()?;
+        req.config.validate()
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InvalidParams.code(), e.to_string(), None::<()>))?;
+
+        tracing::info!("Operator {} updating config of client {}", operator.sub, req.client_id);
+
+        let task_id = ctx.manager.create_config_task(&req.client_id, req.config).await
+            .map_err(|e| ErrorObjectOwned::owned(ErrorCode::InternalError.code(), e.to_string(), None::<()>))?;
+
+        Ok::<serde_json::Value, ErrorObjectOwned>(serde_json::json!({"status": "config_task_created", "task_id": task_id}))
     })?;
 
     Ok(module)
-}
\ No newline at end of file
+}
+
+/// Tunnel address on `ip` at the port of `tunnel_listen`, bracketing IPv6 addresses. None
+/// for port 0, which the previous client couldn't know to dial
+fn tunnel_addr_for(ip: &str, tunnel_listen: &str) -> Option<String> {
+    let port = tunnel_listen.rsplit_once(':')?.1.parse::<u16>().ok().filter(|&port| port != 0)?;
+
+    if ip.contains(':') {
+        Some(format!("[{}]:{}", ip, port))
+    } else {
+        Some(format!("{}:{}", ip, port))
+    }
+}
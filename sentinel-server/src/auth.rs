@@ -0,0 +1,245 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::{OperatorConfig, SecurityConfig};
+
+/// Reasons a client token or operator credential can be rejected
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("unauthorized: unknown client {0}")]
+    UnknownClient(String),
+    #[error("unauthorized: invalid token")]
+    InvalidToken,
+    #[error("unauthorized: token expired")]
+    TokenExpired,
+    #[error("unauthorized: missing bearer token")]
+    MissingToken,
+    #[error("unauthorized: invalid username or password")]
+    InvalidCredentials,
+    #[error("forbidden: {0:?} role required")]
+    Forbidden(Role),
+}
+
+/// Operator roles, ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+/// JWT claims carried by operator session tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// Bearer token lifted from the HTTP `Authorization` header into request extensions
+#[derive(Debug, Clone)]
+pub struct BearerToken(pub String);
+
+/// Issues and verifies operator JWTs signed with `security.jwt_secret`
+pub struct OperatorAuth {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    token_expiry: chrono::Duration,
+    operators: HashMap<String, OperatorConfig>,
+}
+
+impl OperatorAuth {
+    pub fn new(config: &SecurityConfig) -> Self {
+        let secret = config.jwt_secret.as_bytes();
+
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            token_expiry: chrono::Duration::seconds(config.token_expiry as i64),
+            operators: config
+                .operators
+                .iter()
+                .map(|op| (op.username.clone(), op.clone()))
+                .collect(),
+        }
+    }
+
+    /// Check operator credentials and issue a signed session token
+    pub fn login(&self, username: &str, password: &str) -> Result<(String, Claims), AuthError> {
+        let operator = self
+            .operators
+            .get(username)
+            .ok_or(AuthError::InvalidCredentials)?;
+
+        let hash = PasswordHash::new(&operator.password_hash).map_err(|e| {
+            tracing::error!("Invalid password hash configured for operator {}: {}", username, e);
+            AuthError::InvalidCredentials
+        })?;
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .map_err(|_| AuthError::InvalidCredentials)?;
+
+        let now = Utc::now();
+        let claims = Claims {
+            sub: operator.username.clone(),
+            role: operator.role,
+            iat: now.timestamp(),
+            exp: (now + self.token_expiry).timestamp(),
+        };
+
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        Ok((token, claims))
+    }
+
+    /// Verify a session token and check that its role is at least `required`
+    pub fn authorize(&self, token: Option<&BearerToken>, required: Role) -> Result<Claims, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+
+        let claims = jsonwebtoken::decode::<Claims>(&token.0, &self.decoding_key, &Validation::default())
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => AuthError::TokenExpired,
+                _ => AuthError::InvalidToken,
+            })?
+            .claims;
+
+        if claims.role < required {
+            return Err(AuthError::Forbidden(required));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Token handed to a client on registration or refresh
+#[derive(Debug, Clone)]
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl IssuedToken {
+    pub fn generate(expiry: chrono::Duration) -> Self {
+        Self {
+            token: uuid::Uuid::new_v4().to_string(),
+            expires_at: Utc::now() + expiry,
+        }
+    }
+
+    /// Whether `presented` is this token, regardless of expiry
+    pub fn matches(&self, presented: &str) -> bool {
+        constant_time_eq(self.token.as_bytes(), presented.as_bytes())
+    }
+
+    pub fn verify(&self, presented: &str) -> Result<(), AuthError> {
+        if !self.matches(presented) {
+            return Err(AuthError::InvalidToken);
+        }
+
+        if Utc::now() >= self.expires_at {
+            return Err(AuthError::TokenExpired);
+        }
+
+        Ok(())
+    }
+}
+
+/// Random hex nonce for a client to sign
+pub fn generate_nonce() -> String {
+    let mut nonce = [0u8; 32];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut nonce).expect("system randomness");
+    hex::encode(nonce)
+}
+
+/// Whether `signature` is a valid Ed25519 signature of `message` by `public_key`, both hex
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let (Ok(public_key), Ok(signature)) = (hex::decode(public_key), hex::decode(signature)) else {
+        return false;
+    };
+
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(message, &signature)
+        .is_ok()
+}
+
+/// Compare two secrets without leaking the position of the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_verification() {
+        let issued = IssuedToken::generate(chrono::Duration::minutes(5));
+        assert!(issued.verify(&issued.token).is_ok());
+        assert!(matches!(issued.verify("bogus"), Err(AuthError::InvalidToken)));
+
+        let expired = IssuedToken::generate(chrono::Duration::seconds(-1));
+        assert!(matches!(expired.verify(&expired.token), Err(AuthError::TokenExpired)));
+    }
+
+    #[test]
+    fn test_register_signature() {
+        use ring::signature::KeyPair;
+
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let keypair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = hex::encode(keypair.public_key());
+
+        let nonce = generate_nonce();
+        let message = sentinel_common::register_challenge_message("client-1", &nonce);
+        let signature = hex::encode(keypair.sign(&message));
+
+        assert!(verify_signature(&public_key, &message, &signature));
+        let other = sentinel_common::register_challenge_message("client-2", &nonce);
+        assert!(!verify_signature(&public_key, &other, &signature));
+        assert!(!verify_signature(&public_key, &message, "not hex"));
+    }
+
+    #[test]
+    fn test_operator_roles() {
+        use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+
+        let auth = OperatorAuth::new(&SecurityConfig {
+            jwt_secret: "test-secret".to_string(),
+            token_expiry: 60,
+            operators: vec![OperatorConfig {
+                username: "alice".to_string(),
+                password_hash,
+                role: Role::Operator,
+            }],
+        });
+
+        assert!(matches!(auth.login("alice", "wrong"), Err(AuthError::InvalidCredentials)));
+
+        let (token, claims) = auth.login("alice", "secret").unwrap();
+        assert_eq!(claims.role, Role::Operator);
+
+        let bearer = BearerToken(token);
+        assert!(auth.authorize(Some(&bearer), Role::Viewer).is_ok());
+        assert!(auth.authorize(Some(&bearer), Role::Operator).is_ok());
+        assert!(matches!(auth.authorize(Some(&bearer), Role::Admin), Err(AuthError::Forbidden(Role::Admin))));
+        assert!(matches!(auth.authorize(None, Role::Viewer), Err(AuthError::MissingToken)));
+    }
+}
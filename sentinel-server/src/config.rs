@@ -2,12 +2,15 @@ use anyhow::Result;
 use config::{Config as ConfigBuilder, ConfigError, File};
 use serde::{Deserialize, Serialize};
 
+use crate::auth::Role;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub security: SecurityConfig,
     pub client_management: ClientManagementConfig,
+    pub tasks: TaskConfig,
     pub api: ApiConfig,
     pub logging: LoggingConfig,
 }
@@ -29,6 +32,16 @@ pub struct DatabaseConfig {
 pub struct SecurityConfig {
     pub jwt_secret: String,
     pub token_expiry: u64,
+    #[serde(default)]
+    pub operators: Vec<OperatorConfig>,
+}
+
+/// Web UI / API operator account; `password_hash` is an Argon2 PHC string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperatorConfig {
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +50,23 @@ pub struct ClientManagementConfig {
     pub cleanup_interval: u64,
 }
 
+/// Delivery policy for tasks queued on clients; all durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskConfig {
+    /// How long a dispatched task may go without its client confirming it started
+    pub dispatch_timeout: u64,
+    /// Redeliveries after a dispatch timeout before the task is marked timed out
+    pub max_retries: u32,
+    /// Delay before the first redelivery, doubled on every further attempt
+    pub retry_backoff: u64,
+    /// How long a task may wait for its client to pick it up (0 = forever)
+    pub queue_timeout: u64,
+    /// How long a running task may go without a result before it is timed out (0 = forever)
+    pub execution_timeout: u64,
+    /// How often timeouts are checked
+    pub check_interval: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
     pub rate_limit: u32,
@@ -60,6 +90,12 @@ impl Config {
             .set_default("security.token_expiry", 3600)?
             .set_default("client_management.heartbeat_timeout", 120)?
             .set_default("client_management.cleanup_interval", 60)?
+            .set_default("tasks.dispatch_timeout", 300)?
+            .set_default("tasks.max_retries", 3)?
+            .set_default("tasks.retry_backoff", 30)?
+            .set_default("tasks.queue_timeout", 86400)?
+            .set_default("tasks.execution_timeout", 1800)?
+            .set_default("tasks.check_interval", 30)?
             .set_default("api.rate_limit", 100)?
             .set_default("api.max_request_size", "10MB")?
             .set_default("logging.level", "info")?
@@ -1,7 +1,78 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use sentinel_common::{ClientInfo, SystemMetrics, Task};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sentinel_common::{
+    ClientInfo, RelayChain, RelayHealth, SystemMetrics, Task, TaskListRequest, TaskRecord, TaskResult,
+    TaskStatus, TaskType,
+};
+use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, QueryBuilder};
+#[cfg(test)]
+use sqlx::postgres::PgConnectOptions;
+
+const TASK_COLUMNS: &str = "id, client_id, task_type, status, payload, result, attempts, \
+    created_at, dispatched_at, started_at, completed_at";
+
+#[derive(sqlx::FromRow)]
+struct TaskRecordRow {
+    id: String,
+    client_id: Option<String>,
+    task_type: String,
+    status: Option<String>,
+    payload: Option<serde_json::Value>,
+    result: Option<serde_json::Value>,
+    attempts: i32,
+    created_at: Option<DateTime<Utc>>,
+    dispatched_at: Option<DateTime<Utc>>,
+    started_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+impl TaskRecordRow {
+    fn into_record(self) -> Option<TaskRecord> {
+        Some(TaskRecord {
+            id: self.id,
+            client_id: self.client_id.unwrap_or_default(),
+            task_type: task_type_from_str(&self.task_type)?,
+            status: self.status.as_deref().unwrap_or("pending").parse().ok()?,
+            payload: self.payload.unwrap_or(serde_json::Value::Null),
+            result: self.result.and_then(|r| serde_json::from_value(r).ok()),
+            attempts: self.attempts.max(0) as u32,
+            created_at: self.created_at.unwrap_or_else(Utc::now),
+            dispatched_at: self.dispatched_at,
+            started_at: self.started_at,
+            completed_at: self.completed_at,
+        })
+    }
+}
+
+const CHAIN_COLUMNS: &str = "id, entry_point, exit_point, transport_type, multiplex, hops, status, created_at";
+
+#[derive(sqlx::FromRow)]
+struct RelayChainRow {
+    id: String,
+    entry_point: String,
+    exit_point: String,
+    transport_type: String,
+    multiplex: bool,
+    hops: serde_json::Value,
+    status: String,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl RelayChainRow {
+    fn into_chain(self) -> Option<(RelayChain, bool)> {
+        let chain = RelayChain {
+            id: self.id,
+            entry_point: self.entry_point,
+            exit_point: self.exit_point,
+            transport_type: serde_json::from_value(serde_json::Value::String(self.transport_type)).ok()?,
+            multiplex: self.multiplex,
+            hops: serde_json::from_value(self.hops).ok()?,
+            health: RelayHealth::default(),
+            created_at: self.created_at.unwrap_or_else(Utc::now),
+        };
+        Some((chain, self.status == "active"))
+    }
+}
 
 #[derive(Clone)]
 pub struct Database {
@@ -26,17 +97,19 @@ impl Database {
         Ok(())
     }
 
-    pub async fn save_client(&self, info: &ClientInfo) -> Result<()> {
+    pub async fn save_client(&self, info: &ClientInfo, public_key: Option<&str>) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO clients (id, hostname, ip_address, version, capabilities, last_heartbeat, status)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            INSERT INTO clients (id, hostname, ip_address, version, capabilities, last_heartbeat, status, public_key)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
             ON CONFLICT (id) DO UPDATE SET
                 hostname = EXCLUDED.hostname,
                 ip_address = EXCLUDED.ip_address,
                 version = EXCLUDED.version,
                 capabilities = EXCLUDED.capabilities,
                 last_heartbeat = EXCLUDED.last_heartbeat,
+                status = EXCLUDED.status,
+                public_key = EXCLUDED.public_key,
                 updated_at = NOW()
             "#,
         )
@@ -47,12 +120,23 @@ impl Database {
         .bind(serde_json::to_value(&info.capabilities)?)
         .bind(Utc::now())
         .bind("online")
+        .bind(public_key)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
+    /// Hostname and identity key a client id was last registered with, if it ever was
+    pub async fn client_identity(&self, client_id: &str) -> Result<Option<(String, Option<String>)>> {
+        let row = sqlx::query_as::<_, (String, Option<String>)>("SELECT hostname, public_key FROM clients WHERE id = $1")
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row)
+    }
+
     pub async fn update_heartbeat(&self, client_id: &str) -> Result<()> {
         sqlx::query(
             r#"
@@ -129,7 +213,8 @@ impl Database {
         Ok(())
     }
 
-    pub async fn get_pending_tasks(&self, client_id: &str) -> Result<Vec<Task>> {
+    /// Hand every pending task of a client over to it, moving them to `dispatched`
+    pub async fn dispatch_pending_tasks(&self, client_id: &str) -> Result<Vec<Task>> {
         #[derive(sqlx::FromRow)]
         struct TaskRow {
             id: String,
@@ -140,58 +225,43 @@ impl Database {
 
         let rows = sqlx::query_as::<_, TaskRow>(
             r#"
-            SELECT id, task_type, payload, created_at
-            FROM client_tasks
+            UPDATE tasks
+            SET status = 'dispatched', dispatched_at = NOW(), attempts = attempts + 1
             WHERE client_id = $1 AND status = 'pending'
-            ORDER BY created_at ASC
+              AND (next_attempt_at IS NULL OR next_attempt_at <= NOW())
+            RETURNING id, task_type, payload, created_at
             "#,
         )
         .bind(client_id)
         .fetch_all(&self.pool)
         .await?;
 
-        let tasks = rows
+        let mut tasks: Vec<Task> = rows
             .into_iter()
             .filter_map(|row| {
-                let task_type = match row.task_type.as_str() {
-                    "start_relay" => sentinel_common::TaskType::StartRelay,
-                    "stop_relay" => sentinel_common::TaskType::StopRelay,
-                    "update_iptables" => sentinel_common::TaskType::UpdateIptables,
-                    "configure_proxy" => sentinel_common::TaskType::ConfigureProxy,
-                    "update_config" => sentinel_common::TaskType::UpdateConfig,
-                    _ => return None,
-                };
-
                 Some(Task {
                     id: row.id,
-                    task_type,
+                    task_type: task_type_from_str(&row.task_type)?,
                     payload: row.payload,
                     created_at: row.created_at,
                 })
             })
             .collect();
 
+        tasks.sort_by_key(|task| task.created_at);
         Ok(tasks)
     }
 
     pub async fn create_task(&self, client_id: &str, task: &Task) -> Result<()> {
-        let task_type_str = match task.task_type {
-            sentinel_common::TaskType::StartRelay => "start_relay",
-            sentinel_common::TaskType::StopRelay => "stop_relay",
-            sentinel_common::TaskType::UpdateIptables => "update_iptables",
-            sentinel_common::TaskType::ConfigureProxy => "configure_proxy",
-            sentinel_common::TaskType::UpdateConfig => "update_config",
-        };
-
         sqlx::query(
             r#"
-            INSERT INTO client_tasks (id, client_id, task_type, payload, status, created_at)
+            INSERT INTO tasks (id, client_id, task_type, payload, status, created_at)
             VALUES ($1, $2, $3, $4, 'pending', $5)
             "#,
         )
         .bind(&task.id)
         .bind(client_id)
-        .bind(task_type_str)
+        .bind(task_type_to_str(&task.task_type))
         .bind(&task.payload)
         .bind(task.created_at)
         .execute(&self.pool)
@@ -200,6 +270,188 @@ impl Database {
         Ok(())
     }
 
+    /// Move a dispatched task to `running`; returns false if the task is not in that state
+    pub async fn mark_task_running(&self, client_id: &str, task_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE tasks
+            SET status = 'running', started_at = NOW()
+            WHERE id = $1 AND client_id = $2 AND status = 'dispatched'
+            "#,
+        )
+        .bind(task_id)
+        .bind(client_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store the outcome of a dispatched or running task; returns false if it is in neither state
+    pub async fn complete_task(&self, client_id: &str, result: &TaskResult) -> Result<bool> {
+        let status = if result.success {
+            TaskStatus::Succeeded
+        } else {
+            TaskStatus::Failed
+        };
+
+        let outcome = sqlx::query(
+            r#"
+            UPDATE tasks
+            SET status = $1, result = $2, completed_at = NOW()
+            WHERE id = $3 AND client_id = $4 AND status IN ('dispatched', 'running')
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(serde_json::to_value(result)?)
+        .bind(&result.task_id)
+        .bind(client_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(outcome.rows_affected() > 0)
+    }
+
+    pub async fn get_task(&self, task_id: &str) -> Result<Option<TaskRecord>> {
+        let row = sqlx::query_as::<_, TaskRecordRow>(&format!(
+            "SELECT {} FROM tasks WHERE id = $1",
+            TASK_COLUMNS
+        ))
+        .bind(task_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.and_then(TaskRecordRow::into_record))
+    }
+
+    /// List tasks matching `filter`, newest first, along with the total number of matches
+    pub async fn list_tasks(&self, filter: &TaskListRequest, limit: u32, offset: u32) -> Result<(Vec<TaskRecord>, u64)> {
+        let mut count_query = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM tasks");
+        push_task_filters(&mut count_query, filter);
+        let total: i64 = count_query.build_query_scalar().fetch_one(&self.pool).await?;
+
+        let mut query = QueryBuilder::<Postgres>::new(format!("SELECT {} FROM tasks", TASK_COLUMNS));
+        push_task_filters(&mut query, filter);
+        query
+            .push(" ORDER BY created_at DESC, id LIMIT ")
+            .push_bind(limit as i64)
+            .push(" OFFSET ")
+            .push_bind(offset as i64);
+
+        let rows = query
+            .build_query_as::<TaskRecordRow>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        let tasks = rows.into_iter().filter_map(TaskRecordRow::into_record).collect();
+        Ok((tasks, total.max(0) as u64))
+    }
+
+    /// Withdraw a task that has not been picked up yet; returns false if it is no longer pending
+    pub async fn cancel_task(&self, task_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE tasks
+            SET status = 'cancelled', completed_at = NOW()
+            WHERE id = $1 AND status = 'pending'
+            "#,
+        )
+        .bind(task_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Put dispatched tasks the client never confirmed within `timeout_secs` back in the
+    /// queue, delaying redelivery by `backoff_secs` doubled for every earlier attempt.
+    /// Running tasks are left alone: the client has them and redelivering would run them twice.
+    pub async fn requeue_stalled_tasks(
+        &self,
+        timeout_secs: u64,
+        max_attempts: u32,
+        backoff_secs: u64,
+    ) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            UPDATE tasks
+            SET status = 'pending', dispatched_at = NULL,
+                next_attempt_at = NOW() + make_interval(secs => $3 * power(2, LEAST(attempts - 1, 10)))
+            WHERE status = 'dispatched'
+              AND dispatched_at < NOW() - make_interval(secs => $1)
+              AND attempts < $2
+            RETURNING id, client_id
+            "#,
+        )
+        .bind(timeout_secs as f64)
+        .bind(max_attempts as i32)
+        .bind(backoff_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Give up on unconfirmed dispatched tasks that have used all their attempts
+    pub async fn expire_stalled_tasks(&self, timeout_secs: u64, max_attempts: u32) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            UPDATE tasks
+            SET status = 'timed_out', completed_at = NOW(),
+                result = jsonb_build_object('task_id', id, 'success', false,
+                    'message', 'no result after ' || attempts || ' attempts', 'data', NULL)
+            WHERE status = 'dispatched'
+              AND dispatched_at < NOW() - make_interval(secs => $1)
+              AND attempts >= $2
+            RETURNING id, client_id
+            "#,
+        )
+        .bind(timeout_secs as f64)
+        .bind(max_attempts as i32)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Time out running tasks that went `timeout_secs` since they started without a result
+    pub async fn expire_running_tasks(&self, timeout_secs: u64) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            UPDATE tasks
+            SET status = 'timed_out', completed_at = NOW(),
+                result = jsonb_build_object('task_id', id, 'success', false,
+                    'message', 'no result within ' || $1 || 's of starting', 'data', NULL)
+            WHERE status = 'running' AND started_at < NOW() - make_interval(secs => $1)
+            RETURNING id, client_id
+            "#,
+        )
+        .bind(timeout_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Time out tasks whose client never picked them up within `timeout_secs`
+    pub async fn expire_queued_tasks(&self, timeout_secs: u64) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            UPDATE tasks
+            SET status = 'timed_out', completed_at = NOW(),
+                result = jsonb_build_object('task_id', id, 'success', false,
+                    'message', 'client did not pick up the task in time', 'data', NULL)
+            WHERE status = 'pending' AND created_at < NOW() - make_interval(secs => $1)
+            RETURNING id, client_id
+            "#,
+        )
+        .bind(timeout_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
     #[allow(dead_code)]
     pub async fn get_latest_metrics(&self, client_id: &str) -> Result<Option<SystemMetrics>> {
         #[derive(sqlx::FromRow)]
@@ -245,4 +497,293 @@ impl Database {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+
+    pub async fn save_chain(&self, chain: &RelayChain) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO relay_chains (id, entry_point, exit_point, transport_type, multiplex, hops, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&chain.id)
+        .bind(&chain.entry_point)
+        .bind(&chain.exit_point)
+        .bind(serde_json::to_value(&chain.transport_type)?.as_str().unwrap_or_default())
+        .bind(chain.multiplex)
+        .bind(serde_json::to_value(&chain.hops)?)
+        .bind(chain.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Chains with hop health left at its default; `active_only` skips stopped chains
+    pub async fn list_chains(&self, active_only: bool) -> Result<Vec<(RelayChain, bool)>> {
+        let rows = sqlx::query_as::<_, RelayChainRow>(&format!(
+            "SELECT {} FROM relay_chains WHERE status = 'active' OR NOT $1 ORDER BY created_at DESC",
+            CHAIN_COLUMNS
+        ))
+        .bind(active_only)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().filter_map(RelayChainRow::into_chain).collect())
+    }
+
+    /// A chain and whether it is still active
+    pub async fn get_chain(&self, chain_id: &str) -> Result<Option<(RelayChain, bool)>> {
+        let row = sqlx::query_as::<_, RelayChainRow>(&format!(
+            "SELECT {} FROM relay_chains WHERE id = $1",
+            CHAIN_COLUMNS
+        ))
+        .bind(chain_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.and_then(RelayChainRow::into_chain))
+    }
+
+    /// Mark a chain stopped; returns false if it was already stopped or doesn't exist
+    pub async fn stop_chain(&self, chain_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE relay_chains
+            SET status = 'stopped', stopped_at = NOW()
+            WHERE id = $1 AND status = 'active'
+            "#,
+        )
+        .bind(chain_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn push_task_filters(query: &mut QueryBuilder<'_, Postgres>, filter: &TaskListRequest) {
+    query.push(" WHERE TRUE");
+
+    if let Some(client_id) = &filter.client_id {
+        query.push(" AND client_id = ").push_bind(client_id.clone());
+    }
+    if let Some(task_type) = &filter.task_type {
+        query.push(" AND task_type = ").push_bind(task_type_to_str(task_type));
+    }
+    if let Some(status) = &filter.status {
+        query.push(" AND status = ").push_bind(status.as_str());
+    }
+    if let Some(since) = filter.since {
+        query.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = filter.until {
+        query.push(" AND created_at < ").push_bind(until);
+    }
+}
+
+fn task_type_to_str(task_type: &TaskType) -> &'static str {
+    match task_type {
+        TaskType::StartRelay => "start_relay",
+        TaskType::StopRelay => "stop_relay",
+        TaskType::UpdateIptables => "update_iptables",
+        TaskType::ConfigureProxy => "configure_proxy",
+        TaskType::UpdateConfig => "update_config",
+        TaskType::SetRelayBandwidth => "set_relay_bandwidth",
+    }
+}
+
+fn task_type_from_str(task_type: &str) -> Option<TaskType> {
+    match task_type {
+        "start_relay" => Some(TaskType::StartRelay),
+        "stop_relay" => Some(TaskType::StopRelay),
+        "update_iptables" => Some(TaskType::UpdateIptables),
+        "configure_proxy" => Some(TaskType::ConfigureProxy),
+        "update_config" => Some(TaskType::UpdateConfig),
+        "set_relay_bandwidth" => Some(TaskType::SetRelayBandwidth),
+        _ => None,
+    }
+}
+#[cfg(test)]
+impl Database {
+    /// A freshly migrated database of its own on the Postgres server `DATABASE_URL` points
+    /// at, or `None` to skip the test when it isn't set. Remove it with `drop_test_database`.
+    pub async fn test_database() -> Option<Self> {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL is not set, skipping database test");
+            return None;
+        };
+
+        let name = format!("sentinel_test_{}", uuid::Uuid::new_v4().simple());
+        let admin = PgPool::connect(&url).await.expect("DATABASE_URL is not reachable");
+        sqlx::query(&format!("CREATE DATABASE {}", name)).execute(&admin).await.unwrap();
+        admin.close().await;
+
+        let options = url.parse::<PgConnectOptions>().unwrap().database(&name);
+        let pool = PgPoolOptions::new().max_connections(4).connect_with(options).await.unwrap();
+        let db = Self { pool };
+        db.run_migrations().await.unwrap();
+        Some(db)
+    }
+
+    pub async fn drop_test_database(self) {
+        let name: String = sqlx::query_scalar("SELECT current_database()")
+            .fetch_one(&self.pool)
+            .await
+            .unwrap();
+        self.pool.close().await;
+
+        let admin = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", name)).execute(&admin).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_client(id: &str) -> ClientInfo {
+    ClientInfo {
+        id: id.to_string(),
+        hostname: format!("{}.test", id),
+        ip: "127.0.0.1".to_string(),
+        version: "test".to_string(),
+        capabilities: Vec::new(),
+        system_info: sentinel_common::SystemInfo {
+            os: "test".to_string(),
+            kernel_version: "test".to_string(),
+            cpu_cores: 1,
+            total_memory: 0,
+            total_disk: 0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn queue(db: &Database, client_id: &str) -> String {
+        let task = Task {
+            id: uuid::Uuid::new_v4().to_string(),
+            task_type: TaskType::UpdateIptables,
+            payload: serde_json::json!([]),
+            created_at: Utc::now(),
+        };
+        db.create_task(client_id, &task).await.unwrap();
+        task.id
+    }
+
+    async fn status(db: &Database, task_id: &str) -> TaskStatus {
+        db.get_task(task_id).await.unwrap().unwrap().status
+    }
+
+    fn result(task_id: &str, success: bool) -> TaskResult {
+        TaskResult {
+            task_id: task_id.to_string(),
+            success,
+            message: String::new(),
+            data: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_task_lifecycle() {
+        let Some(db) = Database::test_database().await else { return };
+        db.save_client(&test_client("alpha"), None).await.unwrap();
+        db.save_client(&test_client("beta"), None).await.unwrap();
+
+        let task_id = queue(&db, "alpha").await;
+        assert_eq!(status(&db, &task_id).await, TaskStatus::Pending);
+
+        // Only the task's own client gets it, and only once
+        assert!(db.dispatch_pending_tasks("beta").await.unwrap().is_empty());
+        let dispatched = db.dispatch_pending_tasks("alpha").await.unwrap();
+        assert_eq!(dispatched.len(), 1);
+        assert_eq!(dispatched[0].id, task_id);
+        assert!(db.dispatch_pending_tasks("alpha").await.unwrap().is_empty());
+        let record = db.get_task(&task_id).await.unwrap().unwrap();
+        assert_eq!((record.status, record.attempts), (TaskStatus::Dispatched, 1));
+        assert!(record.dispatched_at.is_some());
+
+        assert!(!db.mark_task_running("beta", &task_id).await.unwrap());
+        assert!(db.mark_task_running("alpha", &task_id).await.unwrap());
+        assert!(!db.mark_task_running("alpha", &task_id).await.unwrap());
+        assert_eq!(status(&db, &task_id).await, TaskStatus::Running);
+
+        assert!(!db.complete_task("beta", &result(&task_id, true)).await.unwrap());
+        assert!(db.complete_task("alpha", &result(&task_id, true)).await.unwrap());
+        let record = db.get_task(&task_id).await.unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert!(record.result.is_some_and(|r| r.success));
+        assert!(record.completed_at.is_some());
+
+        // A finished task takes no further results or transitions
+        assert!(!db.complete_task("alpha", &result(&task_id, false)).await.unwrap());
+        assert!(!db.mark_task_running("alpha", &task_id).await.unwrap());
+        assert!(!db.cancel_task(&task_id).await.unwrap());
+        assert_eq!(status(&db, &task_id).await, TaskStatus::Succeeded);
+
+        // A client may report straight from dispatched
+        let failing = queue(&db, "alpha").await;
+        db.dispatch_pending_tasks("alpha").await.unwrap();
+        assert!(db.complete_task("alpha", &result(&failing, false)).await.unwrap());
+        assert_eq!(status(&db, &failing).await, TaskStatus::Failed);
+
+        // Cancelled tasks are never handed out
+        let cancelled = queue(&db, "alpha").await;
+        assert!(db.cancel_task(&cancelled).await.unwrap());
+        assert!(db.dispatch_pending_tasks("alpha").await.unwrap().is_empty());
+        assert!(!db.complete_task("alpha", &result(&cancelled, true)).await.unwrap());
+        assert_eq!(status(&db, &cancelled).await, TaskStatus::Cancelled);
+
+        db.drop_test_database().await;
+    }
+
+    #[tokio::test]
+    async fn test_task_redelivery() {
+        let Some(db) = Database::test_database().await else { return };
+        db.save_client(&test_client("alpha"), None).await.unwrap();
+        // Zero timeouts and backoff: everything dispatched is already overdue
+        let (timeout, max_attempts, backoff) = (0, 2, 0);
+
+        // Dispatched but never confirmed: requeued, then expired after the last attempt
+        let task_id = queue(&db, "alpha").await;
+        db.dispatch_pending_tasks("alpha").await.unwrap();
+        let requeued = db.requeue_stalled_tasks(timeout, max_attempts, backoff).await.unwrap();
+        assert_eq!(requeued, vec![(task_id.clone(), "alpha".to_string())]);
+        assert_eq!(status(&db, &task_id).await, TaskStatus::Pending);
+        // The first delivery's result comes too late to count
+        assert!(!db.complete_task("alpha", &result(&task_id, true)).await.unwrap());
+
+        let redelivered = db.dispatch_pending_tasks("alpha").await.unwrap();
+        assert_eq!(redelivered.len(), 1);
+        assert_eq!(db.get_task(&task_id).await.unwrap().unwrap().attempts, 2);
+        assert!(db.requeue_stalled_tasks(timeout, max_attempts, backoff).await.unwrap().is_empty());
+        let expired = db.expire_stalled_tasks(timeout, max_attempts).await.unwrap();
+        assert_eq!(expired, vec![(task_id.clone(), "alpha".to_string())]);
+        let record = db.get_task(&task_id).await.unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::TimedOut);
+        assert!(record.result.is_some_and(|r| !r.success));
+        assert!(!db.complete_task("alpha", &result(&task_id, true)).await.unwrap());
+
+        // A running task is never redelivered, so its slow result still lands
+        let slow = queue(&db, "alpha").await;
+        db.dispatch_pending_tasks("alpha").await.unwrap();
+        db.mark_task_running("alpha", &slow).await.unwrap();
+        assert!(db.requeue_stalled_tasks(timeout, max_attempts, backoff).await.unwrap().is_empty());
+        assert!(db.expire_stalled_tasks(timeout, max_attempts).await.unwrap().is_empty());
+        assert!(db.expire_running_tasks(3600).await.unwrap().is_empty());
+        assert!(db.dispatch_pending_tasks("alpha").await.unwrap().is_empty());
+        assert!(db.complete_task("alpha", &result(&slow, true)).await.unwrap());
+        assert_eq!(status(&db, &slow).await, TaskStatus::Succeeded);
+
+        // ...unless it runs past the execution timeout, which ends it for good
+        let stuck = queue(&db, "alpha").await;
+        db.dispatch_pending_tasks("alpha").await.unwrap();
+        db.mark_task_running("alpha", &stuck).await.unwrap();
+        let expired = db.expire_running_tasks(0).await.unwrap();
+        assert_eq!(expired, vec![(stuck.clone(), "alpha".to_string())]);
+        assert_eq!(status(&db, &stuck).await, TaskStatus::TimedOut);
+        assert!(db.dispatch_pending_tasks("alpha").await.unwrap().is_empty());
+        assert!(!db.complete_task("alpha", &result(&stuck, true)).await.unwrap());
+
+        db.drop_test_database().await;
+    }
+}
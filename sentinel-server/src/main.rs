@@ -1,4 +1,5 @@
 mod api;
+mod auth;
 mod config;
 mod db;
 mod manager;
@@ -6,9 +7,10 @@ mod manager;
 use anyhow::Result;
 use clap::Parser;
 use std::sync::Arc;
-use tracing_subscriber;
+use std::time::Duration;
 
-use crate::api::create_rpc_module;
+use crate::api::{create_rpc_module, extract_bearer_token, RpcContext};
+use crate::auth::OperatorAuth;
 use crate::config::Config;
 use crate::db::Database;
 use crate::manager::ClientManager;
@@ -37,16 +39,36 @@ async fn main() -> Result<()> {
     let db = Database::connect(&config.database.url).await?;
     db.run_migrations().await?;
 
-    let manager = Arc::new(ClientManager::new(db.clone()));
+    let manager = Arc::new(ClientManager::new(
+        db.clone(),
+        Duration::from_secs(config.security.token_expiry),
+        config.tasks.clone(),
+    ));
 
     let manager_clone = manager.clone();
     tokio::spawn(async move {
         manager_clone.start_cleanup_task().await;
     });
 
-    let rpc_module = create_rpc_module(manager.clone()).await?;
+    let manager_clone = manager.clone();
+    tokio::spawn(async move {
+        manager_clone.start_task_supervisor().await;
+    });
+
+    if config.security.operators.is_empty() {
+        tracing::warn!("No operators configured in [security]; admin RPC methods will reject every call");
+    }
+
+    let rpc_module = create_rpc_module(RpcContext {
+        manager: manager.clone(),
+        auth: OperatorAuth::new(&config.security),
+    })
+    .await?;
+
+    let http_middleware = tower::ServiceBuilder::new().map_request(extract_bearer_token);
 
     let server = jsonrpsee::server::ServerBuilder::default()
+        .set_http_middleware(http_middleware)
         .build(&config.server.bind_addr)
         .await?;
 
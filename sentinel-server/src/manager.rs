@@ -1,15 +1,33 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use sentinel_common::{ClientInfo, ClientStatus, SystemMetrics, Task, RelayConfig, TaskType, IptablesRule};
+use sentinel_common::{
+    ClientInfo, ClientStatus, SystemMetrics, Task, TaskListRequest, TaskListResponse, TaskRecord,
+    TaskResult, RelayConfig, TaskType, IptablesRule, ProxyCommand, ProxyStatus, ConfigUpdate,
+    ChainHop, RelayChain, RelayHealth, TaskStatus,
+};
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio::time::{interval, Duration};
 
+use crate::auth::{self, AuthError, IssuedToken};
+use crate::config::TaskConfig;
 use crate::db::Database;
 
+const DEFAULT_TASK_PAGE_SIZE: u32 = 50;
+const MAX_TASK_PAGE_SIZE: u32 = 500;
+
+/// How long a registration challenge nonce may be signed and used
+const CHALLENGE_TTL_SECS: i64 = 60;
+
 pub struct ClientManager {
     clients: Arc<DashMap<String, ClientState>>,
+    task_streams: DashMap<String, mpsc::UnboundedSender<Task>>,
+    /// Outstanding `client.register_challenge` nonce of each client id, and when it was issued
+    challenges: DashMap<String, (String, DateTime<Utc>)>,
     db: Database,
+    token_expiry: chrono::Duration,
+    task_config: TaskConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -18,62 +36,299 @@ pub struct ClientState {
     pub status: ClientStatus,
     pub last_heartbeat: DateTime<Utc>,
     pub metrics: Option<SystemMetrics>,
-    #[allow(dead_code)]
-    pub token: String,
+    pub proxies: Vec<ProxyStatus>,
+    pub token: IssuedToken,
+    /// Hex Ed25519 identity key the client signs relay handshakes with
+    pub public_key: Option<String>,
 }
 
 impl ClientManager {
-    pub fn new(db: Database) -> Self {
+    pub fn new(db: Database, token_expiry: Duration, task_config: TaskConfig) -> Self {
         Self {
             clients: Arc::new(DashMap::new()),
+            task_streams: DashMap::new(),
+            challenges: DashMap::new(),
             db,
+            token_expiry: chrono::Duration::from_std(token_expiry)
+                .unwrap_or_else(|_| chrono::Duration::hours(1)),
+            task_config,
         }
     }
 
-    pub async fn register_client(&self, info: ClientInfo) -> Result<String> {
+    /// Register a client under the id it asks for, or a fresh one when it has none or can't
+    /// prove it holds the id. Returns the id the client must use from now on.
+    pub async fn register_client(
+        &self,
+        mut info: ClientInfo,
+        previous_token: Option<&str>,
+        public_key: Option<String>,
+        challenge: Option<(&str, &str)>,
+    ) -> Result<(String, IssuedToken)> {
+        if info.id.is_empty() {
+            info.id = uuid::Uuid::new_v4().to_string();
+            tracing::info!("Assigned client id {} to host {}", info.id, info.hostname);
+        } else if let Some(holder) = self.id_holder(&info.id, previous_token, challenge).await? {
+            let assigned = uuid::Uuid::new_v4().to_string();
+            tracing::warn!(
+                "Client id {} is already in use by host {}, assigning {} to host {}",
+                info.id, holder, assigned, info.hostname
+            );
+            info.id = assigned;
+        }
+
         let client_id = info.id.clone();
-        let token = self.generate_token();
+        let token = IssuedToken::generate(self.token_expiry);
 
         let state = ClientState {
             info: info.clone(),
             status: ClientStatus::Online,
             last_heartbeat: Utc::now(),
             metrics: None,
+            proxies: vec![],
             token: token.clone(),
+            public_key: public_key.clone(),
         };
 
         self.clients.insert(client_id.clone(), state);
-        self.db.save_client(&info).await?;
+        self.db.save_client(&info, public_key.as_deref()).await?;
 
         tracing::info!("Client registered: {}", client_id);
-        Ok(token)
+        Ok((client_id, token))
     }
 
-    pub async fn update_heartbeat(&self, client_id: &str) -> Result<()> {
-        if let Some(mut client) = self.clients.get_mut(client_id) {
-            client.last_heartbeat = Utc::now();
-            client.status = ClientStatus::Online;
+    /// Nonce the holder of `client_id` signs to register under it again without its token,
+    /// or `None` if no client with an identity key was ever registered under the id. Each id
+    /// has one nonce at a time, handed out again until it expires or is used.
+    pub async fn register_challenge(&self, client_id: &str) -> Result<Option<String>> {
+        if !matches!(self.db.client_identity(client_id).await?, Some((_, Some(_)))) {
+            return Ok(None);
+        }
 
-            self.db.update_heartbeat(client_id).await?;
-        } else {
-            anyhow::bail!("Client not found: {}", client_id);
+        let now = Utc::now();
+        let mut challenge = self
+            .challenges
+            .entry(client_id.to_string())
+            .or_insert_with(|| (auth::generate_nonce(), now));
+        if !challenge_live(&challenge.1) {
+            *challenge = (auth::generate_nonce(), now);
         }
+        Ok(Some(challenge.0.clone()))
+    }
+
+    /// Forget challenge nonces that expired unused
+    fn prune_challenges(&self) {
+        self.challenges.retain(|_, (_, issued)| challenge_live(issued));
+    }
+
+    /// Hostname of the client holding `client_id`, connected or not, unless the caller
+    /// proves it is that client: with the token last issued for the id while it is
+    /// connected, or by signing the id's challenge nonce with the identity key it was
+    /// registered with, given as `(nonce, signature)`. Hostnames and identity keys are
+    /// public, so they prove nothing.
+    async fn id_holder(
+        &self,
+        client_id: &str,
+        previous_token: Option<&str>,
+        challenge: Option<(&str, &str)>,
+    ) -> Result<Option<String>> {
+        // Only the id's current nonce counts; it is used up once a signature over it checks out
+        let challenge = challenge.filter(|(nonce, _)| {
+            self.challenges
+                .get(client_id)
+                .is_some_and(|current| current.0 == *nonce && challenge_live(&current.1))
+        });
+
+        let connected = self.clients.get(client_id).map(|holder| {
+            let proven = previous_token.is_some_and(|token| holder.token.matches(token));
+            (proven, holder.info.hostname.clone(), holder.public_key.clone())
+        });
+        let (hostname, public_key) = match connected {
+            Some((true, _, _)) => return Ok(None),
+            Some((false, hostname, public_key)) => (hostname, public_key),
+            // Clients that went offline keep their id too
+            None => match self.db.client_identity(client_id).await? {
+                Some(identity) => identity,
+                None => return Ok(None),
+            },
+        };
+
+        let signed = match (challenge, public_key) {
+            (Some((nonce, signature)), Some(public_key)) => auth::verify_signature(
+                &public_key,
+                &sentinel_common::register_challenge_message(client_id, nonce),
+                signature,
+            ),
+            _ => false,
+        };
+        if signed {
+            self.challenges.remove(client_id);
+        }
+        Ok((!signed).then_some(hostname))
+    }
+
+    /// Address a connected client registered from, where its relay tunnels can be reached
+    pub fn client_ip(&self, client_id: &str) -> Option<String> {
+        self.clients.get(client_id).map(|client| client.info.ip.clone())
+    }
+
+    /// Identity key a connected client registered, for authenticating it to relay peers
+    pub fn public_key(&self, client_id: &str) -> Option<String> {
+        self.clients.get(client_id)?.public_key.clone()
+    }
 
+    /// Check the token presented by a client against the one issued to it
+    pub fn verify_token(&self, client_id: &str, token: &str) -> Result<(), AuthError> {
+        let client = self
+            .clients
+            .get(client_id)
+            .ok_or_else(|| AuthError::UnknownClient(client_id.to_string()))?;
+
+        client.token.verify(token)
+    }
+
+    /// Swap a still-valid token for a fresh one, invalidating the old token
+    pub fn refresh_token(&self, client_id: &str, token: &str) -> Result<IssuedToken, AuthError> {
+        let mut client = self
+            .clients
+            .get_mut(client_id)
+            .ok_or_else(|| AuthError::UnknownClient(client_id.to_string()))?;
+
+        client.token.verify(token)?;
+        client.token = IssuedToken::generate(self.token_expiry);
+
+        tracing::debug!("Token refreshed for client: {}", client_id);
+        Ok(client.token.clone())
+    }
+
+    pub async fn update_heartbeat(&self, client_id: &str) -> Result<()> {
+        // Release the shard lock before awaiting the database, or other clients block on it
+        match self.clients.get_mut(client_id) {
+            Some(mut client) => {
+                client.last_heartbeat = Utc::now();
+                client.status = ClientStatus::Online;
+            }
+            None => anyhow::bail!("Client not found: {}", client_id),
+        }
+
+        self.db.update_heartbeat(client_id).await?;
         Ok(())
     }
 
     pub async fn update_metrics(&self, client_id: &str, metrics: SystemMetrics) -> Result<()> {
+        match self.clients.get_mut(client_id) {
+            Some(mut client) => client.metrics = Some(metrics.clone()),
+            None => return Ok(()),
+        }
+
+        self.db.save_metrics(client_id, &metrics).await?;
+        Ok(())
+    }
+
+    pub fn update_proxies(&self, client_id: &str, proxies: Vec<ProxyStatus>) {
         if let Some(mut client) = self.clients.get_mut(client_id) {
-            client.metrics = Some(metrics.clone());
+            client.proxies = proxies;
+        }
+    }
+
+    /// Proxies a client reported in its last heartbeat, or `None` for an unknown client
+    pub fn list_proxies(&self, client_id: &str) -> Option<Vec<ProxyStatus>> {
+        self.clients.get(client_id).map(|client| client.proxies.clone())
+    }
+
+    pub async fn dispatch_pending_tasks(&self, client_id: &str) -> Result<Vec<Task>> {
+        let tasks = self.db.dispatch_pending_tasks(client_id).await?;
+        if !tasks.is_empty() {
+            tracing::info!("Dispatched {} tasks to client {}", tasks.len(), client_id);
+        }
+        Ok(tasks)
+    }
+
+    /// Open the push channel for a client, replacing any earlier one, and flush its queued tasks into it
+    pub async fn open_task_stream(&self, client_id: &str) -> mpsc::UnboundedReceiver<Task> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.task_streams.insert(client_id.to_string(), tx);
+        tracing::info!("Task channel opened for client {}", client_id);
+
+        self.push_pending_tasks(client_id).await;
+        rx
+    }
+
+    /// Forget a client's push channel once its subscriber has gone away
+    pub fn close_task_stream(&self, client_id: &str) {
+        if self
+            .task_streams
+            .remove_if(client_id, |_, tx| tx.is_closed())
+            .is_some()
+        {
+            tracing::info!("Task channel closed for client {}", client_id);
+        }
+    }
+
+    /// Dispatch a client's queued tasks straight into its push channel, if it has one open.
+    /// Tasks lost to a dropped channel are redelivered by the task supervisor.
+    async fn push_pending_tasks(&self, client_id: &str) {
+        let Some(tx) = self.task_streams.get(client_id).map(|tx| tx.clone()) else {
+            return;
+        };
+        if tx.is_closed() {
+            return;
+        }
 
-            self.db.save_metrics(client_id, &metrics).await?;
+        match self.dispatch_pending_tasks(client_id).await {
+            Ok(tasks) => {
+                for task in tasks {
+                    if tx.send(task).is_err() {
+                        tracing::warn!("Task channel for client {} dropped during push", client_id);
+                        break;
+                    }
+                }
+            }
+            Err(e) => tracing::error!("Failed to push tasks to client {}: {}", client_id, e),
         }
+    }
 
+    pub async fn mark_task_running(&self, client_id: &str, task_id: &str) -> Result<()> {
+        if !self.db.mark_task_running(client_id, task_id).await? {
+            anyhow::bail!("Task {} is not dispatched to client {}", task_id, client_id);
+        }
+
+        tracing::debug!("Task {} running on client {}", task_id, client_id);
         Ok(())
     }
 
-    pub async fn get_pending_tasks(&self, client_id: &str) -> Result<Vec<Task>> {
-        self.db.get_pending_tasks(client_id).await
+    pub async fn complete_task(&self, client_id: &str, result: TaskResult) -> Result<()> {
+        if !self.db.complete_task(client_id, &result).await? {
+            anyhow::bail!("Task {} is not in progress on client {}", result.task_id, client_id);
+        }
+
+        if result.success {
+            tracing::info!("Task {} succeeded on client {}", result.task_id, client_id);
+        } else {
+            tracing::warn!("Task {} failed on client {}: {}", result.task_id, client_id, result.message);
+        }
+        Ok(())
+    }
+
+    pub async fn get_task(&self, task_id: &str) -> Result<Option<TaskRecord>> {
+        self.db.get_task(task_id).await
+    }
+
+    pub async fn list_tasks(&self, filter: &TaskListRequest) -> Result<TaskListResponse> {
+        let limit = filter.limit.unwrap_or(DEFAULT_TASK_PAGE_SIZE).clamp(1, MAX_TASK_PAGE_SIZE);
+        let offset = filter.offset.unwrap_or(0);
+
+        let (tasks, total) = self.db.list_tasks(filter, limit, offset).await?;
+        Ok(TaskListResponse { tasks, total })
+    }
+
+    /// Withdraw a task before its client picks it up
+    pub async fn cancel_task(&self, task_id: &str) -> Result<()> {
+        if !self.db.cancel_task(task_id).await? {
+            anyhow::bail!("Task {} is not pending and can no longer be cancelled", task_id);
+        }
+
+        tracing::info!("Task {} cancelled", task_id);
+        Ok(())
     }
 
     pub async fn list_clients(&self) -> Vec<ClientInfo> {
@@ -89,9 +344,64 @@ impl ClientManager {
         loop {
             ticker.tick().await;
             self.cleanup_inactive_clients().await;
+            self.prune_challenges();
+        }
+    }
+
+    /// Periodically redeliver tasks their client never confirmed and expire ones that stalled
+    pub async fn start_task_supervisor(&self) {
+        let mut ticker = interval(Duration::from_secs(self.task_config.check_interval.max(1)));
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.check_task_timeouts().await {
+                tracing::error!("Task timeout check failed: {}", e);
+            }
+
+            // Retries whose backoff has elapsed are not tied to any create call, so push them here
+            let connected: Vec<String> = self.task_streams.iter().map(|e| e.key().clone()).collect();
+            for client_id in connected {
+                self.push_pending_tasks(&client_id).await;
+            }
         }
     }
 
+    async fn check_task_timeouts(&self) -> Result<()> {
+        let policy = &self.task_config;
+        let max_attempts = policy.max_retries + 1;
+
+        for (task_id, client_id) in self
+            .db
+            .requeue_stalled_tasks(policy.dispatch_timeout, max_attempts, policy.retry_backoff)
+            .await?
+        {
+            tracing::warn!("Task {} got no result from client {}, queued for retry", task_id, client_id);
+        }
+
+        for (task_id, client_id) in self
+            .db
+            .expire_stalled_tasks(policy.dispatch_timeout, max_attempts)
+            .await?
+        {
+            tracing::warn!("Task {} timed out on client {} after {} attempts", task_id, client_id, max_attempts);
+        }
+
+        // The client has a running task, so it is never redelivered, only given up on
+        if policy.execution_timeout > 0 {
+            for (task_id, client_id) in self.db.expire_running_tasks(policy.execution_timeout).await? {
+                tracing::warn!("Task {} got no result from client {} after it started, timed out", task_id, client_id);
+            }
+        }
+
+        if policy.queue_timeout > 0 {
+            for (task_id, client_id) in self.db.expire_queued_tasks(policy.queue_timeout).await? {
+                tracing::warn!("Task {} was never picked up by client {}, timed out", task_id, client_id);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn cleanup_inactive_clients(&self) {
         let now = Utc::now();
         let timeout = chrono::Duration::seconds(120);
@@ -109,6 +419,7 @@ impl ClientManager {
 
         for client_id in to_remove {
             if let Some((_, mut client)) = self.clients.remove(&client_id) {
+                self.task_streams.remove(&client_id);
                 client.status = ClientStatus::Offline;
                 let _ = self.db.update_status(&client_id, "offline").await;
                 tracing::info!("Client {} marked as offline", client_id);
@@ -116,47 +427,292 @@ impl ClientManager {
         }
     }
 
-    pub async fn create_relay_task(&self, client_id: &str, relay_config: RelayConfig) -> Result<()> {
+    /// Persist a new task for a client and push it right away if the client is connected
+    async fn queue_task(&self, client_id: &str, task_type: TaskType, payload: serde_json::Value) -> Result<String> {
         let task = Task {
             id: uuid::Uuid::new_v4().to_string(),
-            task_type: TaskType::StartRelay,
-            payload: serde_json::to_value(relay_config)?,
+            task_type,
+            payload,
             created_at: Utc::now(),
         };
 
         self.db.create_task(client_id, &task).await?;
+        self.push_pending_tasks(client_id).await;
+        Ok(task.id)
+    }
+
+    pub async fn create_relay_task(&self, client_id: &str, relay_config: RelayConfig) -> Result<String> {
+        let task_id = self
+            .queue_task(client_id, TaskType::StartRelay, serde_json::to_value(relay_config)?)
+            .await?;
+
         tracing::info!("Created relay task for client: {}", client_id);
-        Ok(())
+        Ok(task_id)
     }
 
-    pub async fn create_stop_relay_task(&self, client_id: &str, relay_config: RelayConfig) -> Result<()> {
-        let task = Task {
-            id: uuid::Uuid::new_v4().to_string(),
-            task_type: TaskType::StopRelay,
-            payload: serde_json::to_value(relay_config)?,
-            created_at: Utc::now(),
-        };
+    pub async fn create_stop_relay_task(&self, client_id: &str, relay_config: RelayConfig) -> Result<String> {
+        let task_id = self
+            .queue_task(client_id, TaskType::StopRelay, serde_json::to_value(relay_config)?)
+            .await?;
 
-        self.db.create_task(client_id, &task).await?;
         tracing::info!("Created stop relay task for client: {}", client_id);
+        Ok(task_id)
+    }
+
+    /// Undo a queued relay start: cancel it if the client hasn't picked it up yet, or else
+    /// queue a stop for the same relay
+    pub async fn withdraw_relay_task(&self, client_id: &str, task_id: &str, relay_config: RelayConfig) -> Result<()> {
+        if self.db.cancel_task(task_id).await? {
+            tracing::info!("Task {} cancelled", task_id);
+            return Ok(());
+        }
+
+        self.create_stop_relay_task(client_id, relay_config).await?;
         Ok(())
     }
 
-    pub async fn create_iptables_task(&self, client_id: &str, rules: Vec<IptablesRule>) -> Result<()> {
-        let rules_count = rules.len();
-        let task = Task {
-            id: uuid::Uuid::new_v4().to_string(),
-            task_type: TaskType::UpdateIptables,
-            payload: serde_json::to_value(rules)?,
-            created_at: Utc::now(),
+    pub async fn create_relay_bandwidth_task(&self, client_id: &str, relay_config: RelayConfig) -> Result<String> {
+        let task_id = self
+            .queue_task(client_id, TaskType::SetRelayBandwidth, serde_json::to_value(relay_config)?)
+            .await?;
+
+        tracing::info!("Created relay bandwidth task for client: {}", client_id);
+        Ok(task_id)
+    }
+
+    pub async fn save_chain(&self, chain: &RelayChain) -> Result<()> {
+        self.db.save_chain(chain).await?;
+        tracing::info!("Created relay chain {} through {} clients", chain.id, chain.hops.len());
+        Ok(())
+    }
+
+    pub async fn get_chain(&self, chain_id: &str) -> Result<Option<RelayChain>> {
+        match self.db.get_chain(chain_id).await? {
+            Some((chain, active)) => Ok(Some(self.with_health(chain, active).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_chains(&self, active_only: bool) -> Result<Vec<RelayChain>> {
+        let mut chains = Vec::new();
+        for (chain, active) in self.db.list_chains(active_only).await? {
+            chains.push(self.with_health(chain, active).await?);
+        }
+        Ok(chains)
+    }
+
+    /// Mark a chain stopped; returns false if it was already stopped or doesn't exist
+    pub async fn stop_chain(&self, chain_id: &str) -> Result<bool> {
+        self.db.stop_chain(chain_id).await
+    }
+
+    /// Fill in the health of every hop, and the chain's as the worst of them
+    async fn with_health(&self, mut chain: RelayChain, active: bool) -> Result<RelayChain> {
+        if !active {
+            for hop in &mut chain.hops {
+                hop.health = RelayHealth::Stopped;
+            }
+            chain.health = RelayHealth::Stopped;
+            return Ok(chain);
+        }
+
+        for hop in &mut chain.hops {
+            hop.health = self.hop_health(hop).await?;
+        }
+
+        let health = |h: RelayHealth| chain.hops.iter().any(|hop| hop.health == h);
+        chain.health = if health(RelayHealth::Down) {
+            RelayHealth::Down
+        } else if health(RelayHealth::Starting) {
+            RelayHealth::Starting
+        } else {
+            RelayHealth::Up
         };
+        Ok(chain)
+    }
+
+    async fn hop_health(&self, hop: &ChainHop) -> Result<RelayHealth> {
+        let online = self
+            .clients
+            .get(&hop.client_id)
+            .is_some_and(|client| matches!(client.status, ClientStatus::Online));
+        if !online {
+            return Ok(RelayHealth::Down);
+        }
+
+        Ok(match self.db.get_task(&hop.task_id).await?.map(|task| task.status) {
+            Some(TaskStatus::Pending | TaskStatus::Dispatched | TaskStatus::Running) => RelayHealth::Starting,
+            Some(TaskStatus::Succeeded) => RelayHealth::Up,
+            _ => RelayHealth::Down,
+        })
+    }
+
+    pub async fn create_iptables_task(&self, client_id: &str, rules: Vec<IptablesRule>) -> Result<String> {
+        let rules_count = rules.len();
+        let task_id = self
+            .queue_task(client_id, TaskType::UpdateIptables, serde_json::to_value(rules)?)
+            .await?;
 
-        self.db.create_task(client_id, &task).await?;
         tracing::info!("Created iptables task for client: {} with {} rules", client_id, rules_count);
-        Ok(())
+        Ok(task_id)
+    }
+
+    pub async fn create_proxy_task(&self, client_id: &str, command: ProxyCommand) -> Result<String> {
+        let task_id = self
+            .queue_task(client_id, TaskType::ConfigureProxy, serde_json::to_value(&command)?)
+            .await?;
+
+        tracing::info!("Created proxy task for client: {}", client_id);
+        Ok(task_id)
+    }
+
+    pub async fn create_config_task(&self, client_id: &str, update: ConfigUpdate) -> Result<String> {
+        let task_id = self
+            .queue_task(client_id, TaskType::UpdateConfig, serde_json::to_value(&update)?)
+            .await?;
+
+        tracing::info!("Created config update task for client: {}", client_id);
+        Ok(task_id)
+    }
+}
+fn challenge_live(issued: &DateTime<Utc>) -> bool {
+    Utc::now().signed_duration_since(*issued).num_seconds() < CHALLENGE_TTL_SECS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_client;
+
+    fn test_manager(db: &Database) -> ClientManager {
+        let task_config = TaskConfig {
+            dispatch_timeout: 300,
+            max_retries: 3,
+            retry_backoff: 30,
+            queue_timeout: 0,
+            execution_timeout: 0,
+            check_interval: 30,
+        };
+        ClientManager::new(db.clone(), Duration::from_secs(3600), task_config)
+    }
+
+    async fn status(manager: &ClientManager, task_id: &str) -> TaskStatus {
+        manager.get_task(task_id).await.unwrap().unwrap().status
     }
 
-    fn generate_token(&self) -> String {
-        uuid::Uuid::new_v4().to_string()
+    fn identity_key() -> (ring::signature::Ed25519KeyPair, String) {
+        use ring::signature::KeyPair;
+
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let keypair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = hex::encode(keypair.public_key());
+        (keypair, public_key)
+    }
+
+    async fn signed_challenge(manager: &ClientManager, client_id: &str, key: &ring::signature::Ed25519KeyPair) -> (String, String) {
+        let nonce = manager.register_challenge(client_id).await.unwrap().unwrap();
+        let signature = hex::encode(key.sign(&sentinel_common::register_challenge_message(client_id, &nonce)));
+        (nonce, signature)
+    }
+
+    #[tokio::test]
+    async fn test_register_keeps_id() {
+        let Some(db) = Database::test_database().await else { return };
+        let manager = test_manager(&db);
+        let (key, public_key) = identity_key();
+        let (other_key, other_public_key) = identity_key();
+
+        let (client_id, issued) = manager
+            .register_client(test_client("alpha"), None, Some(public_key.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(client_id, "alpha");
+
+        // Reconnecting with the persisted id and token lands on the same record
+        let (client_id, issued) = manager
+            .register_client(test_client("alpha"), Some(&issued.token), Some(public_key.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(client_id, "alpha");
+        assert!(manager.verify_token("alpha", &issued.token).is_ok());
+
+        // Another host naming the id, without the token or with a stale one, gets a fresh id
+        let impostor = ClientInfo { hostname: "impostor.test".to_string(), ..test_client("alpha") };
+        for token in [None, Some("stale-token")] {
+            let (assigned, _) = manager
+                .register_client(impostor.clone(), token, Some(other_public_key.clone()), None)
+                .await
+                .unwrap();
+            assert_ne!(assigned, "alpha");
+        }
+        // ...and so does one signing the id's challenge with a key of its own
+        let (nonce, signature) = signed_challenge(&manager, "alpha", &other_key).await;
+        let (assigned, _) = manager
+            .register_client(impostor.clone(), None, Some(other_public_key.clone()), Some((&nonce, &signature)))
+            .await
+            .unwrap();
+        assert_ne!(assigned, "alpha");
+        assert!(manager.verify_token("alpha", &issued.token).is_ok());
+
+        // Offline, as after a server restart, the token is gone but the identity key proves the id
+        manager.clients.remove("alpha");
+        let (assigned, _) = manager
+            .register_client(test_client("alpha"), Some(&issued.token), Some(public_key.clone()), None)
+            .await
+            .unwrap();
+        assert_ne!(assigned, "alpha");
+
+        let (nonce, signature) = signed_challenge(&manager, "alpha", &key).await;
+        // The impostor's failed attempt above did not use up the nonce the real client holds
+        assert_eq!(manager.register_challenge("alpha").await.unwrap(), Some(nonce.clone()));
+        manager.clients.remove("alpha");
+        let (client_id, _) = manager
+            .register_client(test_client("alpha"), None, Some(public_key.clone()), Some((&nonce, &signature)))
+            .await
+            .unwrap();
+        assert_eq!(client_id, "alpha");
+
+        // A nonce works once
+        manager.clients.remove("alpha");
+        let (assigned, _) = manager
+            .register_client(test_client("alpha"), None, Some(public_key.clone()), Some((&nonce, &signature)))
+            .await
+            .unwrap();
+        assert_ne!(assigned, "alpha");
+
+        // Only ids registered with an identity key get challenges
+        assert_eq!(manager.register_challenge("unknown").await.unwrap(), None);
+
+        db.drop_test_database().await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_task_push() {
+        let Some(db) = Database::test_database().await else { return };
+        let manager = test_manager(&db);
+        let (client_id, _) = manager.register_client(test_client("alpha"), None, None, None).await.unwrap();
+
+        // Queued before the client subscribes: flushed as soon as the channel opens
+        let early = manager.create_iptables_task(&client_id, Vec::new()).await.unwrap();
+        assert_eq!(status(&manager, &early).await, TaskStatus::Pending);
+        let mut tasks = manager.open_task_stream(&client_id).await;
+        assert_eq!(tasks.try_recv().unwrap().id, early);
+
+        // Queued while subscribed: pushed at once, without waiting for a heartbeat
+        let pushed = manager.create_iptables_task(&client_id, Vec::new()).await.unwrap();
+        assert_eq!(tasks.try_recv().unwrap().id, pushed);
+        assert_eq!(status(&manager, &pushed).await, TaskStatus::Dispatched);
+
+        // Once the subscriber is gone tasks stay queued for the client's next poll
+        drop(tasks);
+        let polled = manager.create_iptables_task(&client_id, Vec::new()).await.unwrap();
+        assert_eq!(status(&manager, &polled).await, TaskStatus::Pending);
+        manager.close_task_stream(&client_id);
+        assert!(manager.task_streams.get(&client_id).is_none());
+
+        let dispatched = manager.dispatch_pending_tasks(&client_id).await.unwrap();
+        assert_eq!(dispatched.iter().map(|task| task.id.as_str()).collect::<Vec<_>>(), vec![polled.as_str()]);
+
+        db.drop_test_database().await;
+    }
+}
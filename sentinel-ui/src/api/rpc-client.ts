@@ -23,6 +23,13 @@ export interface ClientMetrics {
   timestamp: number;
 }
 
+export interface LoginResponse {
+  token: string;
+  username: string;
+  role: 'admin' | 'operator' | 'viewer';
+  expires_at: string;
+}
+
 export interface MetricsSummary {
   total_clients: number;
   online_clients: number;
@@ -35,7 +42,128 @@ export interface MetricsSummary {
 export interface RelayConfig {
   entry_point: string;
   exit_point: string;
-  transport_type: 'Direct' | 'Snowflake' | 'WebRTC';
+  transport_type: 'Direct' | 'Encrypted' | 'WebSocket' | 'Tls';
+  // Where the exit client listens for the tunnel; required unless Direct
+  tunnel_listen?: string;
+  // Address the entry client dials, defaults to the exit client's IP
+  tunnel_addr?: string;
+  // Carry every connection over one long-lived tunnel; needs tunnel_listen
+  multiplex?: boolean;
+  // Exits tried in order when exit_point is down
+  fallback_exits?: string[];
+  // TCP health probe of the exits
+  exit_probe?: ExitProbe;
+  // Caps applied by the entry client, in total and per source IP
+  bandwidth?: Bandwidth;
+  source_bandwidth?: Bandwidth;
+}
+
+export interface ExitProbe {
+  interval_secs?: number;
+  timeout_ms?: number;
+}
+
+// Mbps each way, 0 for no cap; bursts in KiB, a second's worth when 0
+export interface Bandwidth {
+  upload_mbps?: number;
+  download_mbps?: number;
+  upload_burst_kb?: number;
+  download_burst_kb?: number;
+}
+
+export type RelayHealth = 'starting' | 'up' | 'down' | 'stopped';
+
+export interface ChainHopRequest {
+  client_id: string;
+  // Where this client listens for the previous hop; not used for the entry
+  tunnel_listen?: string;
+  tunnel_addr?: string;
+}
+
+export interface ChainHop {
+  client_id: string;
+  role: 'entry' | 'hop' | 'exit';
+  tunnel_listen: string | null;
+  tunnel_addr: string | null;
+  task_id: string;
+  health: RelayHealth;
+}
+
+export interface RelayChain {
+  id: string;
+  entry_point: string;
+  exit_point: string;
+  transport_type: RelayConfig['transport_type'];
+  multiplex: boolean;
+  hops: ChainHop[];
+  health: RelayHealth;
+  created_at: string;
+}
+
+export interface ProxyConfig {
+  name: string;
+  listen_addr: string;
+  // Not used by socks5 and http proxies
+  target_addr?: string;
+  // Mbps each way; bandwidth takes over for the directions it caps
+  rate_limit?: number;
+  bandwidth?: Bandwidth;
+  source_bandwidth?: Bandwidth;
+  // Caps UDP sessions in udp mode
+  max_connections?: number;
+  max_connections_per_source?: number;
+  // New connections per second from one source IP
+  accept_rate_per_source?: number;
+  mode?: 'tcp' | 'udp' | 'socks5' | 'http';
+  // Seconds before an idle UDP session is dropped, default 60
+  idle_timeout?: number;
+  // socks5 and http CONNECT: required credentials and destination rules (IP,
+  // CIDR, host or *.domain, each with an optional :port)
+  auth?: { username: string; password: string };
+  allow_destinations?: string[];
+  deny_destinations?: string[];
+  // http only: where requests other than CONNECT go
+  routes?: HttpRoute[];
+}
+
+export interface HttpRoute {
+  // Exact host or *.domain; any host if unset
+  host?: string;
+  path_prefix?: string;
+  backends: string[];
+}
+
+export interface HostTraffic {
+  host: string;
+  requests: number;
+  bytes_sent: number;
+  bytes_received: number;
+}
+
+export interface ProxyStatus {
+  name: string;
+  listen_addr: string;
+  target_addr: string;
+  running: boolean;
+  bytes_sent: number;
+  bytes_received: number;
+  total_connections: number;
+  active_connections: number;
+  refused_connections: number;
+  // http proxies only
+  hosts?: HostTraffic[];
+}
+
+export interface ConfigUpdate {
+  server?: { heartbeat_interval?: number };
+  monitoring?: { enabled?: boolean; report_interval?: number; collect_interval?: number };
+  limits?: {
+    max_connections?: number;
+    max_connections_per_source?: number;
+    accept_rate_per_source?: number;
+    rate_limit_mbps?: number;
+  };
+  logging?: { level?: 'trace' | 'debug' | 'info' | 'warn' | 'error' };
 }
 
 export interface IptablesRule {
@@ -48,13 +176,58 @@ export interface IptablesRule {
   comment?: string;
 }
 
+export type TaskStatus =
+  | 'pending'
+  | 'dispatched'
+  | 'running'
+  | 'succeeded'
+  | 'failed'
+  | 'cancelled'
+  | 'timed_out';
+
+export type TaskType = 'UpdateIptables' | 'ConfigureProxy' | 'StartRelay' | 'StopRelay' | 'SetRelayBandwidth' | 'UpdateConfig';
+
+export interface TaskRecord {
+  id: string;
+  client_id: string;
+  task_type: TaskType;
+  status: TaskStatus;
+  payload: any;
+  result: { task_id: string; success: boolean; message: string; data: any } | null;
+  attempts: number;
+  created_at: string;
+  dispatched_at: string | null;
+  started_at: string | null;
+  completed_at: string | null;
+}
+
+export interface TaskFilter {
+  client_id?: string;
+  task_type?: TaskType;
+  status?: TaskStatus;
+  since?: string;
+  until?: string;
+  limit?: number;
+  offset?: number;
+}
+
 class RpcClient {
   private getAuthHeaders() {
     const token = localStorage.getItem('sentinel_token');
     return token ? { Authorization: `Bearer ${token}` } : {};
   }
 
+  // Drop an expired or revoked session and send the user back to the login page
+  private endSession() {
+    localStorage.removeItem('sentinel_token');
+    localStorage.removeItem('sentinel_user');
+    window.location.reload();
+  }
+
   private async call<T = any>(method: string, params?: any): Promise<T> {
+    // Only a rejected session token ends the session; a failed login is for LoginPage to show
+    const hasSession = method !== 'auth.login' && localStorage.getItem('sentinel_token') !== null;
+
     try {
       const response = await axios.post(API_URL, {
         jsonrpc: '2.0',
@@ -70,10 +243,8 @@ class RpcClient {
 
       if (response.data.error) {
         // Handle authentication errors
-        if (response.data.error.code === -32001 || response.data.error.message?.includes('unauthorized')) {
-          localStorage.removeItem('sentinel_token');
-          localStorage.removeItem('sentinel_user');
-          window.location.reload();
+        if (hasSession && (response.data.error.code === -32001 || response.data.error.message?.includes('unauthorized'))) {
+          this.endSession();
         }
         throw new Error(response.data.error.message);
       }
@@ -81,10 +252,8 @@ class RpcClient {
       return response.data.result;
     } catch (error) {
       if (axios.isAxiosError(error)) {
-        if (error.response?.status === 401) {
-          localStorage.removeItem('sentinel_token');
-          localStorage.removeItem('sentinel_user');
-          window.location.reload();
+        if (hasSession && error.response?.status === 401) {
+          this.endSession();
         }
         throw new Error(`API Error: ${error.response?.data?.message || error.message}`);
       }
@@ -92,6 +261,10 @@ class RpcClient {
     }
   }
 
+  async login(username: string, password: string): Promise<LoginResponse> {
+    return this.call('auth.login', { username, password });
+  }
+
   async registerClient(clientInfo: ClientInfo) {
     return this.call('client.register', { client_info: clientInfo });
   }
@@ -110,18 +283,66 @@ class RpcClient {
       exit_client_id: exitClientId,
       entry_point: config.entry_point,
       exit_point: config.exit_point,
-      transport_type: config.transport_type
+      transport_type: config.transport_type,
+      tunnel_listen: config.tunnel_listen,
+      tunnel_addr: config.tunnel_addr,
+      multiplex: config.multiplex,
+      fallback_exits: config.fallback_exits,
+      exit_probe: config.exit_probe,
+      bandwidth: config.bandwidth,
+      source_bandwidth: config.source_bandwidth
     });
   }
 
-  async stopRelay(clientId: string, entryPoint: string, exitPoint: string) {
+  async stopRelay(clientId: string, entryPoint: string, exitPoint: string, exitClientId?: string) {
     return this.call('relay.stop', {
       client_id: clientId,
       entry_point: entryPoint,
-      exit_point: exitPoint
+      exit_point: exitPoint,
+      exit_client_id: exitClientId
+    });
+  }
+
+  async setRelayBandwidth(
+    clientId: string,
+    entryPoint: string,
+    exitPoint: string,
+    bandwidth?: Bandwidth,
+    sourceBandwidth?: Bandwidth,
+    chainId?: string
+  ) {
+    return this.call('relay.set_bandwidth', {
+      client_id: clientId,
+      entry_point: entryPoint,
+      exit_point: exitPoint,
+      chain_id: chainId,
+      bandwidth,
+      source_bandwidth: sourceBandwidth
     });
   }
 
+  async createChain(
+    hops: ChainHopRequest[],
+    config: Pick<
+      RelayConfig,
+      'entry_point' | 'exit_point' | 'transport_type' | 'multiplex' | 'fallback_exits' | 'exit_probe' | 'bandwidth' | 'source_bandwidth'
+    >
+  ): Promise<RelayChain> {
+    return this.call('relay.create_chain', { hops, ...config });
+  }
+
+  async listChains(activeOnly = false): Promise<RelayChain[]> {
+    return this.call('relay.list_chains', { active_only: activeOnly });
+  }
+
+  async getChain(chainId: string): Promise<RelayChain> {
+    return this.call('relay.get_chain', { chain_id: chainId });
+  }
+
+  async stopChain(chainId: string) {
+    return this.call('relay.stop_chain', { chain_id: chainId });
+  }
+
   async updateIptables(clientId: string, rules: IptablesRule[]) {
     return this.call('iptables.update', {
       client_id: clientId,
@@ -129,6 +350,55 @@ class RpcClient {
     });
   }
 
+  async startProxy(clientId: string, config: ProxyConfig) {
+    return this.call('proxy.configure', {
+      client_id: clientId,
+      action: 'start',
+      ...config
+    });
+  }
+
+  async stopProxy(clientId: string, name: string) {
+    return this.call('proxy.configure', {
+      client_id: clientId,
+      action: 'stop',
+      name
+    });
+  }
+
+  async setProxyBandwidth(clientId: string, name: string, bandwidth?: Bandwidth, sourceBandwidth?: Bandwidth) {
+    return this.call('proxy.configure', {
+      client_id: clientId,
+      action: 'set_bandwidth',
+      name,
+      bandwidth,
+      source_bandwidth: sourceBandwidth
+    });
+  }
+
+  async listProxies(clientId: string): Promise<ProxyStatus[]> {
+    return this.call('proxy.list', { client_id: clientId });
+  }
+
+  async updateClientConfig(clientId: string, config: ConfigUpdate) {
+    return this.call('client.update_config', {
+      client_id: clientId,
+      config
+    });
+  }
+
+  async listTasks(filter: TaskFilter = {}): Promise<{ tasks: TaskRecord[]; total: number }> {
+    return this.call('task.list', filter);
+  }
+
+  async getTask(taskId: string): Promise<TaskRecord> {
+    return this.call('task.get', { task_id: taskId });
+  }
+
+  async cancelTask(taskId: string) {
+    return this.call('task.cancel', { task_id: taskId });
+  }
+
   async applyIptablesRule(clientId: string, rule: IptablesRule) {
     return this.call('iptables.apply_rule', {
       client_id: clientId,
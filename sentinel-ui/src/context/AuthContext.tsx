@@ -1,4 +1,5 @@
 import React, { createContext, useContext, useState, useEffect, type ReactNode } from 'react';
+import { rpcClient } from '../api/rpc-client';
 
 interface User {
   id: string;
@@ -53,38 +54,18 @@ export function AuthProvider({ children }: AuthProviderProps) {
 
   const login = async (username: string, password: string): Promise<boolean> => {
     try {
-      // Mock authentication - replace with actual API call
-      if (username === 'admin' && password === 'admin') {
-        const userData: User = {
-          id: '1',
-          username: 'admin',
-          role: 'admin'
-        };
-
-        const mockToken = 'mock_jwt_token_' + Date.now();
-
-        setUser(userData);
-        localStorage.setItem('sentinel_token', mockToken);
-        localStorage.setItem('sentinel_user', JSON.stringify(userData));
-
-        return true;
-      } else if (username === 'operator' && password === 'operator') {
-        const userData: User = {
-          id: '2',
-          username: 'operator',
-          role: 'operator'
-        };
-
-        const mockToken = 'mock_jwt_token_' + Date.now();
-
-        setUser(userData);
-        localStorage.setItem('sentinel_token', mockToken);
-        localStorage.setItem('sentinel_user', JSON.stringify(userData));
-
-        return true;
-      }
-
-      return false;
+      const session = await rpcClient.login(username, password);
+      const userData: User = {
+        id: session.username,
+        username: session.username,
+        role: session.role
+      };
+
+      setUser(userData);
+      localStorage.setItem('sentinel_token', session.token);
+      localStorage.setItem('sentinel_user', JSON.stringify(userData));
+
+      return true;
     } catch (error) {
       console.error('Login failed:', error);
       return false;
@@ -13,12 +13,16 @@ function RelaysPage() {
     exitClientId: string;
     entryPoint: string;
     exitPoint: string;
-    transportType: 'Direct' | 'Snowflake' | 'WebRTC';
+    tunnelListen: string;
+    multiplex: boolean;
+    transportType: 'Direct' | 'Encrypted' | 'WebSocket' | 'Tls';
   }>({
     entryClientId: '',
     exitClientId: '',
     entryPoint: '',
     exitPoint: '',
+    tunnelListen: '',
+    multiplex: false,
     transportType: 'Direct'
   });
 
@@ -37,7 +41,9 @@ function RelaysPage() {
         {
           entry_point: newRelay.entryPoint,
           exit_point: newRelay.exitPoint,
-          transport_type: newRelay.transportType
+          transport_type: newRelay.transportType,
+          tunnel_listen: newRelay.tunnelListen || undefined,
+          multiplex: newRelay.tunnelListen ? newRelay.multiplex : undefined
         }
       );
 
@@ -54,6 +60,8 @@ function RelaysPage() {
         exitClientId: '',
         entryPoint: '',
         exitPoint: '',
+        tunnelListen: '',
+        multiplex: false,
         transportType: 'Direct'
       });
     } catch (error) {
@@ -66,7 +74,8 @@ function RelaysPage() {
       await rpcClient.stopRelay(
         relay.entryClientId,
         relay.entryPoint,
-        relay.exitPoint
+        relay.exitPoint,
+        relay.tunnelListen ? relay.exitClientId : undefined
       );
       setActiveRelays(activeRelays.filter(r => r.id !== relay.id));
     } catch (error) {
@@ -223,10 +232,32 @@ function RelaysPage() {
                   className="w-full px-3 py-2 bg-gray-700 rounded-lg focus:outline-none focus:ring-2 focus:ring-purple-500"
                 >
                   <option value="Direct">Direct</option>
-                  <option value="Snowflake">Snowflake</option>
-                  <option value="WebRTC">WebRTC</option>
+                  <option value="Encrypted">Encrypted</option>
+                  <option value="WebSocket">WebSocket</option>
+                  <option value="Tls">TLS</option>
                 </select>
               </div>
+
+              <div>
+                <label className="block text-sm text-gray-400 mb-2">Tunnel Listen (exit client)</label>
+                <input
+                  type="text"
+                  placeholder="e.g., 0.0.0.0:9000 (required unless Direct)"
+                  value={newRelay.tunnelListen}
+                  onChange={(e) => setNewRelay({ ...newRelay, tunnelListen: e.target.value })}
+                  className="w-full px-3 py-2 bg-gray-700 rounded-lg focus:outline-none focus:ring-2 focus:ring-purple-500"
+                />
+              </div>
+
+              <label className="flex items-center gap-2 text-sm text-gray-400">
+                <input
+                  type="checkbox"
+                  checked={newRelay.multiplex}
+                  disabled={!newRelay.tunnelListen}
+                  onChange={(e) => setNewRelay({ ...newRelay, multiplex: e.target.checked })}
+                />
+                Multiplex connections over one tunnel
+              </label>
             </div>
 
             <div className="flex gap-3 mt-6">